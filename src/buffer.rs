@@ -0,0 +1,221 @@
+//! Pooled buffers that requests are received into
+//!
+//! The session loop takes a buffer from its pool for every request. Once the request was
+//! dispatched, the buffer is returned to the pool, unless the filesystem kept a `RequestData` or
+//! an `OwnedRequest` referring to it, e.g. to process a write on another thread. In that case the
+//! buffer is returned once the last of them is dropped, and the session loop receives the next
+//! requests into other buffers meanwhile.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// Number of free buffers a pool keeps by default; buffers returned beyond that are freed
+const MAX_FREE_BUFFERS: usize = 4;
+
+/// Buffers for receiving requests, all of the same size
+#[derive(Clone, Debug)]
+pub(crate) struct BufferPool {
+    pool: Arc<Mutex<Pool>>,
+    /// Alignment of the start of requests
+    alignment: usize,
+}
+
+#[derive(Debug)]
+struct Pool {
+    free: Vec<Vec<u8>>,
+    size: usize,
+    max_free: usize,
+}
+
+impl BufferPool {
+    pub(crate) fn new(size: usize, alignment: usize) -> BufferPool {
+        BufferPool {
+            pool: Arc::new(Mutex::new(Pool {
+                free: vec![],
+                size,
+                max_free: MAX_FREE_BUFFERS,
+            })),
+            alignment,
+        }
+    }
+
+    /// Size of the buffers
+    pub(crate) fn size(&self) -> usize {
+        self.pool.lock().unwrap().size
+    }
+
+    /// Change the size of the buffers. Buffers of the previous size are freed once they are
+    /// returned.
+    pub(crate) fn set_size(&self, size: usize) {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.size != size {
+            pool.size = size;
+            pool.free.clear();
+        }
+    }
+
+    /// Change the number of free buffers the pool keeps
+    pub(crate) fn set_max_free(&self, count: usize) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.max_free = count;
+        pool.free.truncate(count);
+    }
+
+    /// Takes a free buffer from the pool, or allocates a new one if there is none
+    pub(crate) fn take(&self) -> PooledBuffer {
+        let mut pool = self.pool.lock().unwrap();
+        let data = pool.free.pop();
+        let size = pool.size;
+        drop(pool);
+        let data = data.unwrap_or_else(|| vec![0; size]);
+        let misalignment = data.as_ptr() as usize % self.alignment;
+        PooledBuffer {
+            offset: (self.alignment - misalignment) % self.alignment,
+            data,
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+/// A buffer taken from a `BufferPool`, returned to it on drop
+pub(crate) struct PooledBuffer {
+    data: Vec<u8>,
+    /// Where the aligned part of `data` starts
+    offset: usize,
+    pool: Arc<Mutex<Pool>>,
+}
+
+impl PooledBuffer {
+    /// The aligned part of the buffer, which requests are received into
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..]
+    }
+
+    /// Turns the buffer into the request of `len` bytes that was received into it
+    pub(crate) fn into_request(self, len: usize) -> RequestData {
+        RequestData {
+            start: self.offset,
+            end: self.offset + len,
+            buffer: Arc::new(self),
+        }
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        if pool.free.len() < pool.max_free && self.data.len() == pool.size {
+            pool.free.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// Part of a received request, such as the data of a write or a name, or the whole request.
+///
+/// It refers to the buffer the request was received into instead of copying it, and can be
+/// cloned cheaply and moved to other threads. The buffer is reused for other requests once all
+/// references to it were dropped, so data that is kept for long should be copied instead.
+#[derive(Clone)]
+pub struct RequestData {
+    buffer: Arc<PooledBuffer>,
+    start: usize,
+    end: usize,
+}
+
+impl RequestData {
+    /// Returns a reference to `part`, which has to be a slice of this data, such as the `data`
+    /// argument of `Filesystem::write`. Returns `None` if `part` lies outside of it.
+    pub fn share(&self, part: &[u8]) -> Option<RequestData> {
+        let base = self.buffer.data.as_ptr() as usize;
+        let start = (part.as_ptr() as usize).checked_sub(base)?;
+        let end = start + part.len();
+        if start < self.start || end > self.end {
+            return None;
+        }
+        Some(RequestData {
+            buffer: self.buffer.clone(),
+            start,
+            end,
+        })
+    }
+}
+
+impl Deref for RequestData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer.data[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for RequestData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for RequestData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestData")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(64, 8);
+        let mut buffer = pool.take();
+        assert_eq!(buffer.as_mut_slice().as_ptr() as usize % 8, 0);
+        buffer.as_mut_slice()[..4].copy_from_slice(b"data");
+        let request = buffer.into_request(4);
+        assert_eq!(&*request, b"data");
+        // The buffer is still in use, so another one is allocated
+        let other = pool.take();
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 0);
+        drop(other);
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 1);
+        let part = request.share(&request[1..3]).unwrap();
+        drop(request);
+        assert_eq!(&*part, b"at");
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 1);
+        drop(part);
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 2);
+    }
+
+    #[test]
+    fn share_checks_bounds() {
+        let pool = BufferPool::new(64, 8);
+        let mut buffer = pool.take();
+        buffer.as_mut_slice()[..8].copy_from_slice(b"abcdefgh");
+        let request = buffer.into_request(8);
+        let part = request.share(&request[2..6]).unwrap();
+        assert_eq!(&*part, b"cdef");
+        assert!(part.share(&request[..4]).is_none());
+        assert!(part.share(&request[4..8]).is_none());
+        assert_eq!(&*part.share(&part[1..]).unwrap(), b"def");
+        assert!(request.share(b"abcd").is_none());
+    }
+
+    #[test]
+    fn resize() {
+        let pool = BufferPool::new(64, 8);
+        let request = pool.take().into_request(0);
+        drop(pool.take());
+        pool.set_size(128);
+        assert_eq!(pool.size(), 128);
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 0);
+        // Buffers of the previous size are not reused
+        drop(request);
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 0);
+        assert!(pool.take().as_mut_slice().len() >= 120);
+        pool.set_max_free(0);
+        drop(pool.take());
+        assert_eq!(pool.pool.lock().unwrap().free.len(), 0);
+    }
+}
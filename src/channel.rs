@@ -1,9 +1,46 @@
-use std::{fs::File, io, os::unix::prelude::AsRawFd, sync::Arc};
+use std::{
+    fmt,
+    fs::File,
+    io,
+    os::unix::prelude::{AsFd, AsRawFd, BorrowedFd},
+    sync::Arc,
+};
 
 use libc::{c_int, c_void, size_t};
 
 use crate::reply::ReplySender;
 
+/// Connection over which a session receives requests and sends replies, such as the FUSE
+/// device (`Channel`), a socket, or an in-memory queue for testing
+///
+/// Requests are always copied into the buffers of the session by `receive`. Moving their data
+/// through a pipe with `splice(2)` is not supported, so `fd` is only used for polling.
+pub trait Transport {
+    /// Sends replies. Replies may be sent from other threads than the session loop.
+    type Sender: ReplySender;
+
+    /// Receives a single request into the given buffer and returns its size (can block).
+    ///
+    /// Errors are handled like those of reading the FUSE device: `ENOENT`, `EINTR` and `EAGAIN`
+    /// are retried, and `ENODEV` means that the filesystem was unmounted and ends the session
+    /// loop.
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    /// Returns a sender for replies
+    fn sender(&self) -> Self::Sender;
+
+    /// File descriptor that becomes readable when a request is available, if any. It is polled
+    /// to stop the session loop without waiting for the next request.
+    ///
+    /// Without it, a stop request (see `Session::stop_callable`) or a signal (see
+    /// `Session::run_with_signal_handlers`) is only noticed between requests: a `receive` that
+    /// blocks is not interrupted, so the loop stops once the next request arrived and was
+    /// processed.
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+}
+
 /// A raw communication channel to the FUSE kernel driver
 #[derive(Debug)]
 pub struct Channel(Arc<File>);
@@ -16,6 +53,11 @@ impl Channel {
         Self(device)
     }
 
+    /// The FUSE device
+    pub(crate) fn file(&self) -> &Arc<File> {
+        &self.0
+    }
+
     /// Receives data up to the capacity of the given buffer (can block).
     pub fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
         let rc = unsafe {
@@ -42,6 +84,29 @@ impl Channel {
     }
 }
 
+impl Transport for Channel {
+    type Sender = ChannelSender;
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        Channel::receive(self, buffer)
+    }
+
+    fn sender(&self) -> ChannelSender {
+        Channel::sender(self)
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.0.as_fd())
+    }
+}
+
+impl AsFd for Channel {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/// Sends replies to the FUSE kernel driver
 #[derive(Clone, Debug)]
 pub struct ChannelSender(Arc<File>);
 
@@ -62,3 +127,25 @@ impl ReplySender for ChannelSender {
         }
     }
 }
+
+/// The sender of a session's transport, shared by all requests
+#[derive(Clone)]
+pub(crate) struct SharedSender(Arc<dyn ReplySender>);
+
+impl SharedSender {
+    pub(crate) fn new<S: ReplySender>(sender: S) -> Self {
+        Self(Arc::new(sender))
+    }
+}
+
+impl ReplySender for SharedSender {
+    fn send(&self, bufs: &[io::IoSlice<'_>]) -> io::Result<()> {
+        self.0.send(bufs)
+    }
+}
+
+impl fmt::Debug for SharedSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedSender")
+    }
+}
@@ -0,0 +1,177 @@
+//! Running a filesystem in the background, like libfuse's `fuse_daemonize`
+//!
+//! The process forks before mounting. The child mounts, processes the kernel's INIT request and
+//! only then detaches from the terminal, while the original process waits for it. The child
+//! reports the outcome over a pipe, so the original process can exit successfully once the
+//! filesystem is usable, or fail with the mount error.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+
+use log::error;
+
+use crate::session::{Session, SessionError};
+use crate::Filesystem;
+
+/// Message sent once the filesystem is ready
+const READY: u8 = 0;
+/// Message sent when setting up the filesystem failed. Followed by the OS error code (or 0) as
+/// a little endian i32 and the error message.
+const FAILED: u8 = 1;
+
+/// Create and mount a session in a background process.
+///
+/// The process forks, and the child calls `setup` to create the session. Once the kernel's INIT
+/// request was processed, the child starts a new session with `setsid`, changes its working
+/// directory to `/` and redirects stdin, stdout and stderr to `/dev/null`. It then returns the
+/// session, whose loop is usually started with `Session::run` right away.
+///
+/// The original process waits for the child. It exits with status 0 once the filesystem is
+/// ready, or returns the error with which `setup` or processing INIT failed. Any threads
+/// started before calling this only exist in the original process.
+pub fn daemonize<FS, F>(setup: F) -> Result<Session<FS>, SessionError>
+where
+    FS: Filesystem,
+    F: FnOnce() -> Result<Session<FS>, SessionError>,
+{
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+    let mut writer = unsafe { File::from_raw_fd(fds[1]) };
+    for fd in fds.iter() {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if pid > 0 {
+        drop(writer);
+        return match wait_for_daemon(&mut reader) {
+            Ok(()) => std::process::exit(0),
+            Err(err) => Err(err.into()),
+        };
+    }
+
+    drop(reader);
+    let result = setup().and_then(|mut session| {
+        session.run_until_initialized()?;
+        detach()?;
+        Ok(session)
+    });
+    match result {
+        Ok(session) => {
+            // The original process may have exited already if it was killed. There is nobody
+            // left to tell then, but the filesystem keeps working.
+            let _ = writer.write_all(&[READY]);
+            Ok(session)
+        }
+        Err(err) => {
+            error!("{}", err);
+            let _ = writer.write_all(&encode_error(err));
+            // The session, if any, was dropped already, which unmounted the filesystem
+            std::process::exit(1);
+        }
+    }
+}
+
+fn encode_error(err: SessionError) -> Vec<u8> {
+    let text = err.to_string();
+    let err = io::Error::from(err);
+    // Only the error code is transferred, so pick one that maps back to the same kind
+    let code = err.raw_os_error().unwrap_or(match err.kind() {
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::PermissionDenied => libc::EACCES,
+        io::ErrorKind::AlreadyExists => libc::EEXIST,
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        io::ErrorKind::NotConnected => libc::ENOTCONN,
+        _ => 0,
+    });
+    let mut message = vec![FAILED];
+    message.extend_from_slice(&code.to_le_bytes());
+    message.extend_from_slice(text.as_bytes());
+    message
+}
+
+/// Wait until the daemon reports that the filesystem is ready, or why it failed
+fn wait_for_daemon(reader: &mut File) -> io::Result<()> {
+    let mut message = vec![];
+    reader.read_to_end(&mut message)?;
+    match message.split_first() {
+        Some((&READY, _)) => Ok(()),
+        Some((&FAILED, rest)) if rest.len() >= 4 => {
+            let (code, text) = rest.split_at(4);
+            let code = i32::from_le_bytes(code.try_into().unwrap());
+            let kind = if code != 0 {
+                io::Error::from_raw_os_error(code).kind()
+            } else {
+                io::ErrorKind::Other
+            };
+            Err(io::Error::new(kind, String::from_utf8_lossy(text)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Background process exited before the filesystem was ready",
+        )),
+    }
+}
+
+/// Detach from the terminal and the working directory of the original process
+fn detach() -> io::Result<()> {
+    unsafe {
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::chdir(b"/\0".as_ptr() as *const libc::c_char) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for fd in 0..3 {
+            libc::dup2(null, fd);
+        }
+        if null > 2 {
+            libc::close(null);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mnt::MountError;
+    use std::path::PathBuf;
+
+    #[test]
+    fn report_error() {
+        let (mut reader, mut writer) = {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+        };
+        let err = SessionError::Mount(MountError::MountpointNotFound(PathBuf::from("/nowhere")));
+        let text = err.to_string();
+        writer.write_all(&encode_error(err)).unwrap();
+        drop(writer);
+        let received = wait_for_daemon(&mut reader).unwrap_err();
+        assert_eq!(received.to_string(), text);
+        assert_eq!(received.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn report_crash() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        drop(unsafe { File::from_raw_fd(fds[1]) });
+        assert!(wait_for_daemon(&mut reader).is_err());
+    }
+}
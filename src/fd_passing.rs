@@ -0,0 +1,180 @@
+//! Passing file descriptors over unix sockets
+//!
+//! File descriptors are sent as `SCM_RIGHTS` control messages alongside a regular payload. This
+//! is how fusermount hands the FUSE device to us and how a session is handed over to another
+//! process.
+
+use libc::c_int;
+use std::fs::File;
+use std::io::{Error, ErrorKind, IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::{io, mem, ptr};
+
+/// Send `payload` together with the file descriptors `fds` over `socket`
+pub(crate) fn send_fds(socket: &UnixStream, fds: &[RawFd], payload: &[u8]) -> io::Result<()> {
+    let mut io_vec = [IoSlice::new(payload)];
+    let fds_len = mem::size_of_val(fds) as libc::c_uint;
+    let cmsg_buffer_len = unsafe { libc::CMSG_SPACE(fds_len) };
+    let mut cmsg_buffer = vec![0u8; cmsg_buffer_len as usize];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_name = ptr::null_mut();
+    message.msg_namelen = 0;
+    message.msg_iov = io_vec.as_mut_ptr() as *mut libc::iovec;
+    message.msg_iovlen = 1;
+    message.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = cmsg_buffer.len() as _;
+
+    unsafe {
+        let control_msg = libc::CMSG_FIRSTHDR(&message);
+        (*control_msg).cmsg_level = libc::SOL_SOCKET;
+        (*control_msg).cmsg_type = libc::SCM_RIGHTS;
+        (*control_msg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        let data = libc::CMSG_DATA(control_msg) as *mut c_int;
+        for (i, fd) in fds.iter().enumerate() {
+            ptr::write_unaligned(data.add(i), *fd);
+        }
+    }
+
+    loop {
+        let result = unsafe { libc::sendmsg(socket.as_raw_fd(), &message, 0) };
+        if result == -1 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if result as usize != payload.len() {
+            return Err(Error::new(
+                ErrorKind::WriteZero,
+                "Short write while sending file descriptor",
+            ));
+        }
+        return Ok(());
+    }
+}
+
+/// Receive a payload into `buf` together with a file descriptor from `socket`. Returns the
+/// received file descriptor and the length of the payload.
+pub(crate) fn recv_fd(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(File, usize)> {
+    let (mut fds, len) = recv_fds(socket, buf, 1)?;
+    if len == 0 {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Unexpected EOF while receiving file descriptor",
+        ));
+    }
+    match fds.pop() {
+        Some(fd) => Ok((fd, len)),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "No file descriptor was received",
+        )),
+    }
+}
+
+/// Receive a payload into `buf` together with up to `max_fds` file descriptors from `socket`.
+/// Returns the received file descriptors and the length of the payload, which is 0 at the end of
+/// the stream.
+pub(crate) fn recv_fds(
+    socket: &UnixStream,
+    buf: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(Vec<File>, usize)> {
+    let mut io_vec = [IoSliceMut::new(buf)];
+    let cmsg_buffer_len =
+        unsafe { libc::CMSG_SPACE((max_fds * mem::size_of::<c_int>()) as libc::c_uint) };
+    let mut cmsg_buffer = vec![0u8; cmsg_buffer_len as usize];
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_name = ptr::null_mut();
+    message.msg_namelen = 0;
+    message.msg_iov = io_vec.as_mut_ptr() as *mut libc::iovec;
+    message.msg_iovlen = 1;
+    message.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = cmsg_buffer.len() as _;
+
+    let mut result;
+    loop {
+        unsafe {
+            result = libc::recvmsg(socket.as_raw_fd(), &mut message, 0);
+        }
+        if result != -1 {
+            break;
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    let mut fds = vec![];
+    unsafe {
+        let mut control_msg = libc::CMSG_FIRSTHDR(&message);
+        while !control_msg.is_null() {
+            if (*control_msg).cmsg_type != libc::SCM_RIGHTS {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown control message: {}", (*control_msg).cmsg_type),
+                ));
+            }
+            let data = libc::CMSG_DATA(control_msg) as *const c_int;
+            let count = ((*control_msg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                / mem::size_of::<c_int>();
+            for i in 0..count {
+                let fd = ptr::read_unaligned(data.add(i));
+                if fd < 0 {
+                    return Err(ErrorKind::InvalidData.into());
+                }
+                fds.push(File::from_raw_fd(fd));
+            }
+            control_msg = libc::CMSG_NXTHDR(&message, control_msg);
+        }
+    }
+    if message.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("More than {} file descriptors were received", max_fds),
+        ));
+    }
+    Ok((fds, result as usize))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn send_and_receive() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"hello").unwrap();
+        send_fds(&a, &[file.as_raw_fd()], b"payload").unwrap();
+
+        let mut buf = [0u8; 16];
+        let (mut received, len) = recv_fd(&b, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"payload");
+        let mut contents = String::new();
+        received.seek(SeekFrom::Start(0)).unwrap();
+        received.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+    }
+
+    #[test]
+    fn send_several() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (c, mut d) = UnixStream::pair().unwrap();
+        let file = tempfile::tempfile().unwrap();
+        send_fds(&a, &[file.as_raw_fd(), c.as_raw_fd()], b"two").unwrap();
+
+        let mut buf = [0u8; 16];
+        let (fds, len) = recv_fds(&b, &mut buf, 2).unwrap();
+        assert_eq!(&buf[..len], b"two");
+        assert_eq!(fds.len(), 2);
+        (&fds[1]).write_all(b"x").unwrap();
+        let mut received = [0u8; 1];
+        d.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"x");
+    }
+}
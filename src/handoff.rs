@@ -0,0 +1,253 @@
+//! Handing a mounted session over to another process
+//!
+//! The FUSE device is passed over a unix socket, together with the state that was negotiated
+//! with the kernel during INIT. The kernel only sends INIT once per connection, so the receiving
+//! process must be able to continue where the sending process left off.
+
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use crate::fd_passing::{recv_fds, send_fds};
+use crate::mnt::AutoUnmountKind;
+use crate::session::{ConnectionInfo, SessionACL};
+
+/// Identifies a handoff message ("FUSR")
+const MAGIC: u32 = 0x4655_5352;
+/// Version of the message format. Must be bumped whenever the format changes.
+const FORMAT_VERSION: u32 = 2;
+/// Number of u32 fields preceding the mountpoints
+const HEADER_FIELDS: usize = 15;
+/// Maximum size of a handoff message. Leaves room for two PATH_MAX long mountpoints.
+const MAX_MESSAGE_SIZE: usize = HEADER_FIELDS * 4 + 2 * 4096;
+
+/// Session state that is transferred along with the FUSE device
+#[derive(Debug, PartialEq)]
+pub(crate) struct HandoffState {
+    pub(crate) mountpoint: PathBuf,
+    pub(crate) allowed: SessionACL,
+    pub(crate) session_owner: u32,
+    pub(crate) connection: Option<ConnectionInfo>,
+    /// The mount, if the session owned it
+    pub(crate) mount: Option<MountState>,
+}
+
+/// A mount that is handed over along with the session. The socket of its auto-unmount, if any,
+/// is transferred as a second file descriptor.
+#[derive(Debug, PartialEq)]
+pub(crate) struct MountState {
+    pub(crate) mountpoint: PathBuf,
+    pub(crate) auto_unmount: Option<AutoUnmountKind>,
+}
+
+impl HandoffState {
+    fn encode(&self) -> Vec<u8> {
+        let allowed = match self.allowed {
+            SessionACL::All => 0,
+            SessionACL::RootAndOwner => 1,
+            SessionACL::Owner => 2,
+        };
+        let connection = self.connection.unwrap_or(ConnectionInfo {
+            proto_major: 0,
+            proto_minor: 0,
+            flags: 0,
+            max_readahead: 0,
+            max_write: 0,
+            max_background: 0,
+            congestion_threshold: 0,
+        });
+        let mount_path = match &self.mount {
+            Some(mount) => mount.mountpoint.as_os_str().as_bytes(),
+            None => &[],
+        };
+        let fields: [u32; HEADER_FIELDS] = [
+            MAGIC,
+            FORMAT_VERSION,
+            allowed,
+            self.session_owner,
+            self.connection.is_some() as u32,
+            connection.proto_major,
+            connection.proto_minor,
+            connection.flags,
+            connection.max_readahead,
+            connection.max_write,
+            connection.max_background as u32,
+            connection.congestion_threshold as u32,
+            self.mount.is_some() as u32,
+            match self.mount.as_ref().and_then(|x| x.auto_unmount) {
+                None => 0,
+                Some(AutoUnmountKind::Fusermount) => 1,
+                Some(AutoUnmountKind::Watchdog) => 2,
+            },
+            mount_path.len() as u32,
+        ];
+        let mut message: Vec<u8> = fields.iter().flat_map(|x| x.to_le_bytes()).collect();
+        message.extend_from_slice(mount_path);
+        message.extend_from_slice(self.mountpoint.as_os_str().as_bytes());
+        message
+    }
+
+    fn decode(message: &[u8]) -> io::Result<HandoffState> {
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_owned());
+        if message.len() < HEADER_FIELDS * 4 {
+            return Err(invalid("Handoff message is too short"));
+        }
+        let (header, paths) = message.split_at(HEADER_FIELDS * 4);
+        let fields: Vec<u32> = header
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect();
+        if fields[0] != MAGIC {
+            return Err(invalid("Not a handoff message"));
+        }
+        if fields[1] != FORMAT_VERSION {
+            return Err(invalid("Unsupported handoff message version"));
+        }
+        let allowed = match fields[2] {
+            0 => SessionACL::All,
+            1 => SessionACL::RootAndOwner,
+            2 => SessionACL::Owner,
+            _ => return Err(invalid("Invalid access control mode in handoff message")),
+        };
+        let connection = if fields[4] != 0 {
+            Some(ConnectionInfo {
+                proto_major: fields[5],
+                proto_minor: fields[6],
+                flags: fields[7],
+                max_readahead: fields[8],
+                max_write: fields[9],
+                max_background: fields[10] as u16,
+                congestion_threshold: fields[11] as u16,
+            })
+        } else {
+            None
+        };
+        let mount_path_len = fields[14] as usize;
+        if mount_path_len > paths.len() {
+            return Err(invalid("Handoff message is too short"));
+        }
+        let (mount_path, mountpoint) = paths.split_at(mount_path_len);
+        let auto_unmount = match fields[13] {
+            0 => None,
+            1 => Some(AutoUnmountKind::Fusermount),
+            2 => Some(AutoUnmountKind::Watchdog),
+            _ => return Err(invalid("Invalid auto-unmount mode in handoff message")),
+        };
+        let mount = if fields[12] != 0 {
+            Some(MountState {
+                mountpoint: OsStr::from_bytes(mount_path).into(),
+                auto_unmount,
+            })
+        } else {
+            None
+        };
+        Ok(HandoffState {
+            mountpoint: OsStr::from_bytes(mountpoint).into(),
+            allowed,
+            session_owner: fields[3],
+            connection,
+            mount,
+        })
+    }
+}
+
+/// Send the FUSE device `fd` and the session state over `socket`, along with the socket of the
+/// mount's auto-unmount, if any
+pub(crate) fn send(
+    socket: &UnixStream,
+    fd: RawFd,
+    auto_unmount: Option<RawFd>,
+    state: &HandoffState,
+) -> io::Result<()> {
+    let message = state.encode();
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Mountpoint path is too long to be handed off",
+        ));
+    }
+    let fds: Vec<RawFd> = std::iter::once(fd).chain(auto_unmount).collect();
+    send_fds(socket, &fds, &message)
+}
+
+/// Receive a FUSE device, the socket of the mount's auto-unmount if there is one, and session
+/// state that were sent with `send`
+pub(crate) fn receive(socket: &UnixStream) -> io::Result<(File, Option<OwnedFd>, HandoffState)> {
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    let (fds, len) = recv_fds(socket, &mut buf, 2)?;
+    if len == 0 {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Unexpected EOF while receiving handoff message",
+        ));
+    }
+    let state = HandoffState::decode(&buf[..len])?;
+    let mut fds = fds.into_iter();
+    let file = fds
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "No FUSE device was received"))?;
+    let auto_unmount = fds.next().map(OwnedFd::from);
+    let expected = state.mount.as_ref().and_then(|x| x.auto_unmount).is_some();
+    if auto_unmount.is_some() != expected {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Auto-unmount socket doesn't match the handoff message",
+        ));
+    }
+    Ok((file, auto_unmount, state))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let state = HandoffState {
+            mountpoint: "/mnt/fuse".into(),
+            allowed: SessionACL::RootAndOwner,
+            session_owner: 1000,
+            connection: Some(ConnectionInfo {
+                proto_major: 7,
+                proto_minor: 31,
+                flags: 0x1234,
+                max_readahead: 131072,
+                max_write: 1048576,
+                max_background: 16,
+                congestion_threshold: 12,
+            }),
+            mount: None,
+        };
+        assert_eq!(HandoffState::decode(&state.encode()).unwrap(), state);
+
+        let mounted = HandoffState {
+            mount: Some(MountState {
+                mountpoint: "/mnt/real".into(),
+                auto_unmount: Some(AutoUnmountKind::Watchdog),
+            }),
+            ..state
+        };
+        assert_eq!(HandoffState::decode(&mounted.encode()).unwrap(), mounted);
+
+        let uninitialized = HandoffState {
+            connection: None,
+            mount: None,
+            ..mounted
+        };
+        assert_eq!(
+            HandoffState::decode(&uninitialized.encode()).unwrap(),
+            uninitialized
+        );
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(HandoffState::decode(b"short").is_err());
+        assert!(HandoffState::decode(&[0u8; HEADER_FIELDS * 4]).is_err());
+    }
+}
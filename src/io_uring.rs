@@ -0,0 +1,755 @@
+//! Serving requests over io_uring (FUSE-over-io_uring)
+//!
+//! Since Linux 6.14 the kernel can pass requests to the filesystem through io_uring instead of
+//! the FUSE device, if the `enable_uring` parameter of the fuse module is set. The filesystem
+//! registers ring entries, each made of a header and a payload buffer, with one queue per
+//! possible CPU. The kernel copies a request of a process running on the queue's CPU into an
+//! entry and completes the command that registered it. The reply is written into the same entry
+//! and committed by a command that also fetches the next request into it.
+//!
+//! The ring entries are registered once INIT was answered, so INIT and the requests before it,
+//! as well as FORGET and INTERRUPT requests, are still read from the FUSE device.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, IoSlice};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{cmp, mem, ptr};
+
+use libc::{c_int, EIO};
+use log::{debug, info, warn};
+
+use crate::channel::{Channel, ChannelSender, Transport};
+use crate::ll;
+use crate::ll::fuse_abi::{self as abi, fuse_opcode};
+use crate::ll::reply::peek_reply_header;
+use crate::reply::ReplySender;
+use crate::sys::{self, invalid, u32_at, u64_at};
+
+/// `FUSE_INIT_EXT`: the flags of INIT continue in `flags2`
+const FUSE_INIT_EXT: u32 = 1 << 30;
+/// `FUSE_MAX_PAGES`: `max_pages` of the INIT reply is valid
+const FUSE_MAX_PAGES: u32 = 1 << 22;
+/// `FUSE_OVER_IO_URING` in `flags2` of INIT
+const FUSE_OVER_IO_URING: u32 = 1 << 9;
+/// Size of `fuse_init_out` with `flags2`
+const INIT_OUT_SIZE: usize = 64;
+/// Number of pages of a request if `FUSE_MAX_PAGES` isn't negotiated
+const DEFAULT_MAX_PAGES: usize = 32;
+/// `FUSE_MIN_READ_BUFFER`, the smallest payload buffer the kernel accepts
+const MIN_PAYLOAD_SIZE: usize = 8192;
+
+// Commands of FUSE-over-io_uring
+const FUSE_IO_URING_CMD_REGISTER: u32 = 1;
+const FUSE_IO_URING_CMD_COMMIT_AND_FETCH: u32 = 2;
+
+// Layout of `fuse_uring_req_header`: the FUSE header of the request or reply, the argument
+// specific to the operation, and `fuse_uring_ent_in_out`
+const OP_IN: usize = 128;
+const ENT_IN_OUT: usize = 256;
+const PAYLOAD_SZ: usize = ENT_IN_OUT + 16;
+const HEADER_SIZE: usize = ENT_IN_OUT + 32;
+
+const IORING_SETUP_SQE128: u32 = 1 << 10;
+const IORING_OP_URING_CMD: u8 = 46;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+/// Size of a submission queue entry with `IORING_SETUP_SQE128`
+const SQE_SIZE: usize = 128;
+const CQE_SIZE: usize = 16;
+
+/// Number of entries registered per queue
+const QUEUE_DEPTH: usize = 4;
+
+// Epoll tokens
+const DEVICE: u64 = 0;
+const RING: u64 = 1;
+
+/// Receives requests over io_uring if the kernel supports FUSE-over-io_uring, and from the FUSE
+/// device otherwise. Sessions using it are created with `Session::new_io_uring`.
+///
+/// Whether io_uring is used is decided during INIT: the kernel has to offer it, and setting up
+/// the ring has to succeed. Otherwise requests keep being read from the FUSE device, like with
+/// `Channel`.
+#[derive(Debug)]
+pub struct IoUringTransport {
+    ch: Channel,
+    sender: IoUringSender,
+}
+
+impl IoUringTransport {
+    /// Create a transport for an already open and mounted FUSE device, e.g. one that was mounted
+    /// by a privileged process, to serve it with `Session::with_transport`. The ring is set up
+    /// when the kernel's INIT request is answered.
+    pub fn from_fd(device: OwnedFd) -> io::Result<IoUringTransport> {
+        IoUringTransport::new(Arc::new(File::from(device)))
+    }
+
+    pub(crate) fn new(device: Arc<File>) -> io::Result<IoUringTransport> {
+        let epoll = sys::epoll_create()?;
+        sys::epoll_ctl(
+            epoll.as_fd(),
+            libc::EPOLL_CTL_ADD,
+            device.as_raw_fd(),
+            0,
+            DEVICE,
+        )?;
+        let ch = Channel::new(device.clone());
+        let shared = Shared {
+            device: ch.sender(),
+            device_fd: device.as_raw_fd(),
+            epoll,
+            state: Mutex::new(State::default()),
+        };
+        Ok(IoUringTransport {
+            ch,
+            sender: IoUringSender(Arc::new(shared)),
+        })
+    }
+
+    /// Waits until a request is available, and returns whether the FUSE device is readable
+    fn wait(&self) -> io::Result<bool> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 2];
+        let count = unsafe {
+            libc::epoll_wait(
+                self.sender.0.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as c_int,
+                -1,
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(events[..count as usize].iter().any(|x| x.u64 == DEVICE))
+    }
+
+    /// Remembers the INIT request if the kernel offers FUSE-over-io_uring, so the ring is set up
+    /// when it is answered
+    fn check_init(&self, request: &[u8]) {
+        match ll::peek_header(request) {
+            Some((unique, opcode)) if opcode == fuse_opcode::FUSE_INIT as u32 => {
+                let init = &request[mem::size_of::<abi::fuse_in_header>()..];
+                let flags = u32_at(init, 12).unwrap_or(0);
+                let flags2 = match flags & FUSE_INIT_EXT {
+                    0 => 0,
+                    _ => u32_at(init, 16).unwrap_or(0),
+                };
+                if flags2 & FUSE_OVER_IO_URING != 0 {
+                    self.sender.0.state.lock().unwrap().init = Some(unique.0);
+                } else {
+                    info!("Kernel does not offer FUSE-over-io_uring, using the FUSE device");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Transport for IoUringTransport {
+    type Sender = IoUringSender;
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(size) = self.sender.0.next_request(buffer) {
+                return Ok(size);
+            }
+            if self.wait()? {
+                let size = self.ch.receive(buffer)?;
+                self.check_init(&buffer[..size]);
+                return Ok(size);
+            }
+        }
+    }
+
+    fn sender(&self) -> IoUringSender {
+        self.sender.clone()
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.sender.0.epoll.as_fd())
+    }
+}
+
+/// Sends replies to the requests of an `IoUringTransport` through the ring entries they were
+/// received in, or to the FUSE device
+#[derive(Clone, Debug)]
+pub struct IoUringSender(Arc<Shared>);
+
+impl IoUringSender {
+    /// Whether requests are received over io_uring, which is decided when INIT is answered
+    pub fn is_io_uring(&self) -> bool {
+        self.0.state.lock().unwrap().ring.is_some()
+    }
+}
+
+impl ReplySender for IoUringSender {
+    fn send(&self, data: &[IoSlice<'_>]) -> io::Result<()> {
+        let mut reply = Vec::with_capacity(data.iter().map(|x| x.len()).sum());
+        for x in data {
+            reply.extend_from_slice(x);
+        }
+        let (unique, error) = peek_reply_header(&reply)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(index) = state.pending.remove(&unique.0) {
+            // Requests are only pending while the ring exists
+            return state.ring.as_mut().unwrap().commit(index, unique.0, &reply);
+        }
+        let init = state.init == Some(unique.0);
+        if init {
+            state.init = None;
+        }
+        drop(state);
+        if !init || error != 0 {
+            return self.0.device.send(&[IoSlice::new(&reply)]);
+        }
+        self.0.send_init_reply(reply)
+    }
+}
+
+/// State of the transport, shared with its senders
+#[derive(Debug)]
+struct Shared {
+    device: ChannelSender,
+    device_fd: RawFd,
+    /// Waits for the FUSE device and the completion queue of the ring
+    epoll: OwnedFd,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Unique id of the INIT request, if the kernel offered FUSE-over-io_uring
+    init: Option<u64>,
+    ring: Option<Ring>,
+    /// Requests that were received from ring entries and wait for a reply, by unique id
+    pending: HashMap<u64, usize>,
+}
+
+impl Shared {
+    /// Sends the reply to INIT, asking the kernel for FUSE-over-io_uring, and registers the ring
+    /// entries. Falls back to the FUSE device if the ring can't be set up.
+    fn send_init_reply(&self, mut reply: Vec<u8>) -> io::Result<()> {
+        let ring = payload_size(&reply).and_then(|payload_size| {
+            let entries = possible_cpus()? * QUEUE_DEPTH;
+            Ring::new(self.device_fd, entries, payload_size)
+        });
+        let mut ring = match ring {
+            Ok(ring) => ring,
+            Err(err) => {
+                warn!("Failed to set up io_uring, using the FUSE device: {}", err);
+                return self.device.send(&[IoSlice::new(&reply)]);
+            }
+        };
+        request_io_uring(&mut reply);
+        // The kernel is initialized once the reply was written, which registering requires
+        self.device.send(&[IoSlice::new(&reply)])?;
+
+        let registered = (0..ring.entries.len())
+            .try_for_each(|index| ring.submit(index, FUSE_IO_URING_CMD_REGISTER, 0))
+            .and_then(|_| ring.check_registration())
+            .and_then(|_| {
+                let ring = ring.fd.as_raw_fd();
+                sys::epoll_ctl(self.epoll.as_fd(), libc::EPOLL_CTL_ADD, ring, 0, RING)
+            });
+        match registered {
+            Ok(()) => {
+                info!(
+                    "Serving requests over io_uring with {} queues",
+                    ring.entries.len() / QUEUE_DEPTH
+                );
+                self.state.lock().unwrap().ring = Some(ring);
+            }
+            Err(err) => warn!(
+                "Failed to register io_uring entries, using the FUSE device: {}",
+                err
+            ),
+        }
+        Ok(())
+    }
+
+    /// Copies the next request that was received in a ring entry into `buffer`
+    fn next_request(&self, buffer: &mut [u8]) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let State { ring, pending, .. } = &mut *state;
+        let ring = ring.as_mut()?;
+        while let Some((index, result)) = ring.next_completion() {
+            if result < 0 {
+                // The connection ended, or the entry was never registered
+                debug!(
+                    "io_uring entry {} completed with error: {}",
+                    index,
+                    io::Error::from_raw_os_error(-result)
+                );
+                continue;
+            }
+            let entry = &ring.entries[index];
+            let unique = u64_at(&entry.header, 8).unwrap();
+            match entry.read_request(buffer) {
+                Some(size) => {
+                    pending.insert(unique, index);
+                    return Some(size);
+                }
+                None => {
+                    warn!("Received invalid request {} over io_uring", unique);
+                    let mut reply = [0; 16];
+                    reply[..4].copy_from_slice(&16u32.to_ne_bytes());
+                    reply[4..8].copy_from_slice(&(-EIO).to_ne_bytes());
+                    reply[8..].copy_from_slice(&unique.to_ne_bytes());
+                    if let Err(err) = ring.commit(index, unique, &reply) {
+                        warn!("Failed to reply to request {}: {}", unique, err);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// An io_uring instance and the FUSE ring entries registered through it
+#[derive(Debug)]
+struct Ring {
+    fd: OwnedFd,
+    device_fd: RawFd,
+    params: Params,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    /// Entries of all queues, `QUEUE_DEPTH` consecutive ones per queue
+    entries: Vec<Entry>,
+}
+
+impl Ring {
+    fn new(device_fd: RawFd, entries: usize, payload_size: usize) -> io::Result<Ring> {
+        let mut params = Params {
+            flags: IORING_SETUP_SQE128,
+            ..Params::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries as u32,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_size = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_size = params.cq_off.cqes as usize + params.cq_entries as usize * CQE_SIZE;
+        let sqes_size = params.sq_entries as usize * SQE_SIZE;
+        Ok(Ring {
+            sq: Mapping::new(fd.as_fd(), sq_size, IORING_OFF_SQ_RING)?,
+            cq: Mapping::new(fd.as_fd(), cq_size, IORING_OFF_CQ_RING)?,
+            sqes: Mapping::new(fd.as_fd(), sqes_size, IORING_OFF_SQES)?,
+            fd,
+            device_fd,
+            params,
+            entries: (0..entries)
+                .map(|index| Entry::new((index / QUEUE_DEPTH) as u16, payload_size))
+                .collect(),
+        })
+    }
+
+    /// Submits a FUSE command for the entry at `index`
+    fn submit(&mut self, index: usize, command: u32, commit_id: u64) -> io::Result<()> {
+        let entry = &self.entries[index];
+        let sq_off = &self.params.sq_off;
+        let tail = self.sq.atomic(sq_off.tail);
+        let slot = tail.load(Ordering::Relaxed) & unsafe { *self.sq.at::<u32>(sq_off.ring_mask) };
+        unsafe {
+            let sqe = self.sqes.ptr.add(slot as usize * SQE_SIZE);
+            ptr::write_bytes(sqe, 0, SQE_SIZE);
+            *sqe = IORING_OP_URING_CMD;
+            *(sqe.add(4) as *mut i32) = self.device_fd;
+            *(sqe.add(8) as *mut u32) = command;
+            *(sqe.add(16) as *mut u64) = entry.iov.as_ptr() as u64;
+            *(sqe.add(24) as *mut u32) = entry.iov.len() as u32;
+            *(sqe.add(32) as *mut u64) = index as u64;
+            // fuse_uring_cmd_req
+            *(sqe.add(56) as *mut u64) = commit_id;
+            *(sqe.add(64) as *mut u16) = entry.qid;
+            *self.sq.at::<u32>(sq_off.array + slot * 4) = slot;
+        }
+        tail.fetch_add(1, Ordering::Release);
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd.as_raw_fd(),
+                1,
+                0,
+                0,
+                ptr::null::<libc::sigset_t>(),
+                0,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Writes a reply into the entry at `index` and commits it, which fetches the next request
+    fn commit(&mut self, index: usize, unique: u64, reply: &[u8]) -> io::Result<()> {
+        let result = self.entries[index].write_reply(unique, reply);
+        self.submit(index, FUSE_IO_URING_CMD_COMMIT_AND_FETCH, unique)?;
+        result
+    }
+
+    /// Returns the entry index and the result of the next completed command, if any
+    fn next_completion(&mut self) -> Option<(usize, i32)> {
+        let cq_off = &self.params.cq_off;
+        let head = self.cq.atomic(cq_off.head);
+        let current = head.load(Ordering::Relaxed);
+        if current == self.cq.atomic(cq_off.tail).load(Ordering::Acquire) {
+            return None;
+        }
+        let (user_data, result) = self.completion(current);
+        head.store(current.wrapping_add(1), Ordering::Release);
+        Some((user_data as usize, result))
+    }
+
+    /// Fails if the kernel rejected the registration of an entry. Registration commands only
+    /// complete right away if they fail, or once a request was received, so completions are
+    /// checked without consuming them.
+    fn check_registration(&self) -> io::Result<()> {
+        let cq_off = &self.params.cq_off;
+        let head = self.cq.atomic(cq_off.head).load(Ordering::Relaxed);
+        let tail = self.cq.atomic(cq_off.tail).load(Ordering::Acquire);
+        let mut position = head;
+        while position != tail {
+            let (_, result) = self.completion(position);
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            position = position.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// User data and result of the completion queue entry at `position`
+    fn completion(&self, position: u32) -> (u64, i32) {
+        let cq_off = &self.params.cq_off;
+        let mask = unsafe { *self.cq.at::<u32>(cq_off.ring_mask) };
+        let offset = cq_off.cqes + (position & mask) * CQE_SIZE as u32;
+        unsafe { (*self.cq.at::<u64>(offset), *self.cq.at::<i32>(offset + 8)) }
+    }
+}
+
+/// Buffers of a ring entry, written by the kernel while the entry is registered or committed
+#[derive(Debug)]
+struct Entry {
+    qid: u16,
+    header: Vec<u8>,
+    payload: Vec<u8>,
+    /// Describes `header` and `payload` to the kernel
+    iov: [libc::iovec; 2],
+}
+
+impl Entry {
+    fn new(qid: u16, payload_size: usize) -> Entry {
+        let mut header = vec![0; HEADER_SIZE];
+        let mut payload = vec![0; payload_size];
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+                iov_len: payload.len(),
+            },
+        ];
+        Entry {
+            qid,
+            header,
+            payload,
+            iov,
+        }
+    }
+
+    /// Reassembles the request that the kernel split into the header, the argument of the
+    /// operation and the payload
+    fn read_request(&self, buffer: &mut [u8]) -> Option<usize> {
+        let in_header = mem::size_of::<abi::fuse_in_header>();
+        let len = u32_at(&self.header, 0).ok()? as usize;
+        let payload_size = u32_at(&self.header, PAYLOAD_SZ).ok()? as usize;
+        let op_in = len.checked_sub(in_header + payload_size)?;
+        if op_in > ENT_IN_OUT - OP_IN || payload_size > self.payload.len() || len > buffer.len() {
+            return None;
+        }
+        buffer[..in_header].copy_from_slice(&self.header[..in_header]);
+        buffer[in_header..in_header + op_in].copy_from_slice(&self.header[OP_IN..OP_IN + op_in]);
+        buffer[in_header + op_in..len].copy_from_slice(&self.payload[..payload_size]);
+        Some(len)
+    }
+
+    /// Writes the header of `reply` and its data into the buffers. A reply that doesn't fit
+    /// fails the request instead, so the entry can be committed anyway.
+    fn write_reply(&mut self, unique: u64, reply: &[u8]) -> io::Result<()> {
+        let out = mem::size_of::<abi::fuse_out_header>();
+        let data = &reply[out..];
+        if data.len() > self.payload.len() {
+            self.header[..4].copy_from_slice(&(out as u32).to_ne_bytes());
+            self.header[4..8].copy_from_slice(&(-EIO).to_ne_bytes());
+            self.header[8..out].copy_from_slice(&unique.to_ne_bytes());
+            self.set_payload_size(0);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Reply of {} bytes exceeds the io_uring buffer", data.len()),
+            ));
+        }
+        self.header[..out].copy_from_slice(&reply[..out]);
+        self.payload[..data.len()].copy_from_slice(data);
+        self.set_payload_size(data.len());
+        Ok(())
+    }
+
+    fn set_payload_size(&mut self, size: usize) {
+        self.header[PAYLOAD_SZ..PAYLOAD_SZ + 4].copy_from_slice(&(size as u32).to_ne_bytes());
+    }
+}
+
+// The buffers are only accessed by the kernel and by the thread holding the lock of the state
+unsafe impl Send for Entry {}
+
+/// A memory mapped part of an io_uring instance
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+impl Mapping {
+    fn new(fd: BorrowedFd<'_>, len: usize, offset: libc::off_t) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.add(offset as usize) as *mut T
+    }
+
+    /// A head or tail index of a queue, which is shared with the kernel
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*self.at::<AtomicU32>(offset) }
+    }
+}
+
+/// `io_uring_params`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// `io_sqring_offsets`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// `io_cqring_offsets`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// Extends an INIT reply to the current size of `fuse_init_out` and sets the flags that ask the
+/// kernel for FUSE-over-io_uring
+fn request_io_uring(reply: &mut Vec<u8>) {
+    let out = mem::size_of::<abi::fuse_out_header>();
+    reply.resize(out + INIT_OUT_SIZE, 0);
+    let len = reply.len() as u32;
+    reply[..4].copy_from_slice(&len.to_ne_bytes());
+    let flags = u32_at(reply, out + 12).unwrap() | FUSE_INIT_EXT;
+    reply[out + 12..out + 16].copy_from_slice(&flags.to_ne_bytes());
+    let flags2 = u32_at(reply, out + 32).unwrap() | FUSE_OVER_IO_URING;
+    reply[out + 32..out + 36].copy_from_slice(&flags2.to_ne_bytes());
+}
+
+/// Size of the payload buffers the kernel requires for the parameters of an INIT reply
+fn payload_size(reply: &[u8]) -> io::Result<usize> {
+    let init = &reply[mem::size_of::<abi::fuse_out_header>()..];
+    let flags = u32_at(init, 12)?;
+    let max_write = u32_at(init, 20)? as usize;
+    let max_pages = match init.get(28..30) {
+        Some(bytes) if flags & FUSE_MAX_PAGES != 0 => {
+            u16::from_ne_bytes(bytes.try_into().unwrap()) as usize
+        }
+        _ => DEFAULT_MAX_PAGES,
+    };
+    Ok(cmp::max(
+        cmp::max(MIN_PAYLOAD_SIZE, max_write),
+        max_pages * page_size::get(),
+    ))
+}
+
+/// Number of possible CPUs, which is the number of queues the kernel expects
+fn possible_cpus() -> io::Result<usize> {
+    let list = fs::read_to_string("/sys/devices/system/cpu/possible")?;
+    count_cpus(list.trim()).ok_or_else(|| invalid(format!("Invalid CPU list {:?}", list)))
+}
+
+/// Counts the CPUs of a list like `0-3,8`
+fn count_cpus(list: &str) -> Option<usize> {
+    list.split(',').try_fold(0, |count, range| {
+        let (first, last): (usize, usize) = match range.split_once('-') {
+            Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+            None => (range.parse().ok()?, range.parse().ok()?),
+        };
+        Some(count + last.checked_sub(first)? + 1)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(count_cpus("0"), Some(1));
+        assert_eq!(count_cpus("0-7"), Some(8));
+        assert_eq!(count_cpus("0-3,8,10-11"), Some(7));
+        assert_eq!(count_cpus("3-1"), None);
+        assert_eq!(count_cpus(""), None);
+    }
+
+    #[test]
+    fn payload_sizes() {
+        let mut reply = vec![0; 16 + 64];
+        reply[16 + 20..16 + 24].copy_from_slice(&4096u32.to_ne_bytes());
+        assert_eq!(
+            payload_size(&reply).unwrap(),
+            DEFAULT_MAX_PAGES * page_size::get()
+        );
+        reply[16 + 12..16 + 16].copy_from_slice(&FUSE_MAX_PAGES.to_ne_bytes());
+        reply[16 + 28..16 + 30].copy_from_slice(&1u16.to_ne_bytes());
+        assert_eq!(
+            payload_size(&reply).unwrap(),
+            cmp::max(MIN_PAYLOAD_SIZE, page_size::get())
+        );
+        reply[16 + 20..16 + 24].copy_from_slice(&(1u32 << 20).to_ne_bytes());
+        assert_eq!(payload_size(&reply).unwrap(), 1 << 20);
+        assert!(payload_size(&reply[..16 + 16]).is_err());
+    }
+
+    #[test]
+    fn init_reply_flags() {
+        // Reply of protocol 7.23 with a flag set, before the second flags word existed
+        let mut reply = vec![0; 16 + 24];
+        reply[..4].copy_from_slice(&40u32.to_ne_bytes());
+        reply[16 + 12..16 + 16].copy_from_slice(&1u32.to_ne_bytes());
+        request_io_uring(&mut reply);
+        assert_eq!(reply.len(), 16 + INIT_OUT_SIZE);
+        assert_eq!(u32_at(&reply, 0).unwrap() as usize, reply.len());
+        assert_eq!(u32_at(&reply, 16 + 12).unwrap(), 1 | FUSE_INIT_EXT);
+        assert_eq!(u32_at(&reply, 16 + 32).unwrap(), FUSE_OVER_IO_URING);
+    }
+
+    #[test]
+    fn read_request() {
+        let mut entry = Entry::new(0, 16);
+        // WRITE of 5 bytes, with 8 bytes of arguments
+        entry.header[..4].copy_from_slice(&(40u32 + 8 + 5).to_ne_bytes());
+        entry.header[4..8].copy_from_slice(&16u32.to_ne_bytes());
+        entry.header[8..16].copy_from_slice(&7u64.to_ne_bytes());
+        entry.header[OP_IN..OP_IN + 8].copy_from_slice(&[1; 8]);
+        entry.set_payload_size(5);
+        entry.payload[..5].copy_from_slice(b"hello");
+
+        let mut buffer = [0; 64];
+        assert_eq!(entry.read_request(&mut buffer), Some(53));
+        assert_eq!(&buffer[..40], &entry.header[..40]);
+        assert_eq!(&buffer[40..48], &[1; 8]);
+        assert_eq!(&buffer[48..53], b"hello");
+
+        // The buffer is too small
+        assert_eq!(entry.read_request(&mut buffer[..52]), None);
+        // The payload exceeds the buffer of the entry
+        entry.header[..4].copy_from_slice(&(40u32 + 8 + 17).to_ne_bytes());
+        entry.set_payload_size(17);
+        assert_eq!(entry.read_request(&mut buffer), None);
+        // The payload exceeds the length of the request
+        entry.header[..4].copy_from_slice(&40u32.to_ne_bytes());
+        entry.set_payload_size(5);
+        assert_eq!(entry.read_request(&mut buffer), None);
+    }
+
+    #[test]
+    fn write_reply() {
+        let mut entry = Entry::new(0, 8);
+        let mut reply = vec![0; 16 + 8];
+        reply[..4].copy_from_slice(&24u32.to_ne_bytes());
+        reply[8..16].copy_from_slice(&7u64.to_ne_bytes());
+        reply[16..].copy_from_slice(b"contents");
+        entry.write_reply(7, &reply).unwrap();
+        assert_eq!(&entry.header[..16], &reply[..16]);
+        assert_eq!(u32_at(&entry.header, PAYLOAD_SZ).unwrap(), 8);
+        assert_eq!(&entry.payload[..], b"contents");
+
+        // Too large for the entry, so the request fails
+        reply.push(0);
+        assert!(entry.write_reply(7, &reply).is_err());
+        assert_eq!(u32_at(&entry.header, 0).unwrap(), 16);
+        assert_eq!(entry.header[4..8], (-EIO).to_ne_bytes());
+        assert_eq!(u64_at(&entry.header, 8).unwrap(), 7);
+        assert_eq!(u32_at(&entry.header, PAYLOAD_SZ).unwrap(), 0);
+    }
+}
@@ -8,7 +8,7 @@
 
 use libc::{c_int, ENOSYS, EPERM};
 use log::{debug, warn};
-use mnt::mount_options::parse_options_from_args;
+use mnt::mount_options::{parse_option, parse_options_from_args};
 #[cfg(feature = "serializable")]
 use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
@@ -21,33 +21,69 @@ use std::{convert::AsRef, io::ErrorKind};
 
 use crate::ll::fuse_abi::consts::*;
 pub use crate::ll::fuse_abi::FUSE_ROOT_ID;
-pub use crate::ll::{fuse_abi::consts, TimeOrNow};
+pub use crate::ll::{fuse_abi::consts, Operation, RequestError, TimeOrNow};
 use crate::mnt::mount_options::check_option_conflicts;
 use crate::session::MAX_WRITE_SIZE;
+pub use buffer::RequestData;
+pub use channel::{Channel, ChannelSender, Transport};
+pub use daemon::daemonize;
+#[cfg(target_os = "linux")]
+pub use io_uring::{IoUringSender, IoUringTransport};
 #[cfg(feature = "abi-7-16")]
 pub use ll::fuse_abi::fuse_forget_one;
-pub use mnt::mount_options::MountOption;
+pub use mnt::mount_options::{MountOption, MountOptions, ParseMountOptionError};
+#[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+pub use mnt::DetachedMount;
+pub use mnt::MountError;
+#[cfg(target_os = "linux")]
+pub use mnt::{enter_user_namespace, UserNamespace};
+pub use mount_helper::{mount_helper, MountHelperArgs};
+#[cfg(target_os = "linux")]
+pub use mount_set::{MountId, MountSet};
+pub use remote::{Disconnect, Forwarder, StreamSender, StreamTransport};
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::ReplyXattr;
-pub use reply::{Reply, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen};
+pub use reply::{Reply, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyOpen, ReplySender};
 pub use reply::{
     ReplyBmap, ReplyCreate, ReplyDirectory, ReplyDirectoryPlus, ReplyIoctl, ReplyLock, ReplyLseek,
     ReplyStatfs, ReplyWrite,
 };
-pub use request::Request;
-pub use session::{BackgroundSession, Session, SessionUnmounter};
+pub use request::{OwnedRequest, Request};
+pub use session::{
+    BackgroundSession, ConnectionInfo, InvalidRequestError, InvalidRequestPolicy, Processed,
+    ScopedBackgroundSession, Session, SessionError, SessionStopper, SessionUnmounter,
+};
 #[cfg(feature = "abi-7-28")]
 use std::cmp::max;
-#[cfg(feature = "abi-7-13")]
 use std::cmp::min;
+#[cfg(target_os = "linux")]
+pub use vhost_user::{VhostUserSender, VhostUserTransport};
 
+mod buffer;
 mod channel;
+mod daemon;
+mod fd_passing;
+mod handoff;
+#[cfg(target_os = "linux")]
+mod io_uring;
 mod ll;
 mod mnt;
+mod mount_helper;
+#[cfg(target_os = "linux")]
+mod mount_set;
+#[cfg(target_os = "linux")]
+pub mod mounts;
+mod remote;
 mod reply;
 mod request;
+mod sd_notify;
 mod session;
+mod signals;
+#[cfg(target_os = "linux")]
+mod sys;
+#[cfg(target_os = "linux")]
+mod vhost_user;
 
 /// We generally support async reads
 #[cfg(all(not(target_os = "macos"), not(feature = "abi-7-10")))]
@@ -78,6 +114,40 @@ const fn default_init_flags(#[allow(unused_variables)] capabilities: u32) -> u32
     }
 }
 
+/// Number of pages of a request the kernel allows without `FUSE_MAX_PAGES`
+#[cfg(target_os = "linux")]
+const DEFAULT_MAX_PAGES: u32 = 32;
+
+/// Default limit of the kernel for the number of pages of a request with `FUSE_MAX_PAGES`, which
+/// newer kernels make configurable in /proc/sys/fs/fuse/max_pages_limit
+#[cfg(all(target_os = "linux", feature = "abi-7-28"))]
+const DEFAULT_MAX_PAGES_LIMIT: u32 = 256;
+
+/// Largest max_write the kernel makes use of, for the given capabilities
+#[cfg(target_os = "linux")]
+fn max_max_write(#[allow(unused_variables)] capabilities: u32) -> u32 {
+    #[cfg(feature = "abi-7-28")]
+    let max_pages = if capabilities & FUSE_MAX_PAGES != 0 {
+        std::fs::read_to_string("/proc/sys/fs/fuse/max_pages_limit")
+            .ok()
+            .and_then(|x| x.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_PAGES_LIMIT)
+    } else {
+        DEFAULT_MAX_PAGES
+    };
+    #[cfg(not(feature = "abi-7-28"))]
+    let max_pages = DEFAULT_MAX_PAGES;
+    min(
+        max_pages.saturating_mul(page_size::get() as u32),
+        MAX_WRITE_SIZE as u32,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn max_max_write(_capabilities: u32) -> u32 {
+    MAX_WRITE_SIZE as u32
+}
+
 /// File types
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 #[cfg_attr(feature = "serializable", derive(Serialize, Deserialize))]
@@ -146,12 +216,14 @@ pub struct KernelConfig {
     #[cfg(feature = "abi-7-13")]
     congestion_threshold: Option<u16>,
     max_write: u32,
+    max_max_write: u32,
     #[cfg(feature = "abi-7-23")]
     time_gran: Duration,
 }
 
 impl KernelConfig {
     fn new(capabilities: u32, max_readahead: u32) -> Self {
+        let max_max_write = max_max_write(capabilities);
         Self {
             capabilities,
             requested: default_init_flags(capabilities),
@@ -161,8 +233,8 @@ impl KernelConfig {
             max_background: 16,
             #[cfg(feature = "abi-7-13")]
             congestion_threshold: None,
-            // use a max write size that fits into the session's buffer
-            max_write: MAX_WRITE_SIZE as u32,
+            max_write: max_max_write,
+            max_max_write,
             // 1ns means nano-second granularity.
             #[cfg(feature = "abi-7-23")]
             time_gran: Duration::new(0, 1),
@@ -195,7 +267,13 @@ impl KernelConfig {
         Ok(previous)
     }
 
-    /// Set the maximum write size for a single request
+    /// Set the maximum write size for a single request. The buffers of the session are sized
+    /// to fit it.
+    ///
+    /// On Linux, writes of more than 128 KiB need the `abi-7-28` feature, so the size can be
+    /// negotiated with `FUSE_MAX_PAGES`. The kernel limits it to `/proc/sys/fs/fuse/max_pages_limit`
+    /// pages (1 MiB by default). Larger values up to 16 MiB are accepted, but reduced
+    /// to the largest size the kernel makes use of.
     ///
     /// On success returns the previous value. On error returns the nearest value which will succeed
     pub fn set_max_write(&mut self, value: u32) -> Result<u32, u32> {
@@ -206,7 +284,7 @@ impl KernelConfig {
             return Err(MAX_WRITE_SIZE as u32);
         }
         let previous = self.max_write;
-        self.max_write = value;
+        self.max_write = min(value, self.max_max_write);
         Ok(previous)
     }
 
@@ -272,6 +350,30 @@ impl KernelConfig {
         }
     }
 
+    /// The connection parameters that are replied to the kernel's INIT request
+    fn connection_info(
+        &self,
+        proto_major: u32,
+        proto_minor: u32,
+        capabilities: u32,
+    ) -> ConnectionInfo {
+        ConnectionInfo {
+            proto_major,
+            proto_minor,
+            flags: capabilities & self.requested,
+            max_readahead: self.max_readahead,
+            max_write: self.max_write,
+            #[cfg(feature = "abi-7-13")]
+            max_background: self.max_background,
+            #[cfg(not(feature = "abi-7-13"))]
+            max_background: 0,
+            #[cfg(feature = "abi-7-13")]
+            congestion_threshold: self.congestion_threshold(),
+            #[cfg(not(feature = "abi-7-13"))]
+            congestion_threshold: 0,
+        }
+    }
+
     #[cfg(feature = "abi-7-28")]
     fn max_pages(&self) -> u16 {
         ((max(self.max_write, self.max_readahead) - 1) / page_size::get() as u32) as u16 + 1
@@ -834,7 +936,8 @@ pub trait Filesystem {
 
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
-    /// with the 'blkdev' option
+    /// with the 'blkdev' option (see `MountOptions::block_device`), the kernel
+    /// doesn't send it otherwise
     fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
         debug!(
             "[Not Implemented] bmap(ino: {:#x?}, blocksize: {}, idx: {})",
@@ -927,6 +1030,46 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Map a range of a file into the DAX window of a virtio-fs device, see
+    /// `VhostUserTransport`. `flags` is a combination of `FUSE_SETUPMAPPING_FLAG_READ` and
+    /// `FUSE_SETUPMAPPING_FLAG_WRITE`, and `moffset` is the offset of the range in the window.
+    #[cfg(feature = "abi-7-31")]
+    fn setupmapping(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            "[Not Implemented] setupmapping(ino: {:#x?}, fh: {}, foffset: {}, len: {}, \
+            flags: {:#x}, moffset: {})",
+            ino, fh, foffset, len, flags, moffset
+        );
+        reply.error(ENOSYS);
+    }
+
+    /// Remove ranges from the DAX window of a virtio-fs device. `mappings` are the offsets in
+    /// the window and the lengths of the ranges.
+    #[cfg(feature = "abi-7-31")]
+    fn removemapping(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mappings: &[(u64, u64)],
+        reply: ReplyEmpty,
+    ) {
+        debug!(
+            "[Not Implemented] removemapping(ino: {:#x?}, mappings: {:?})",
+            ino, mappings
+        );
+        reply.error(ENOSYS);
+    }
+
     /// macOS only: Rename the volume. Set fuse_init_out.flags during init to
     /// FUSE_VOL_RENAME to enable
     #[cfg(target_os = "macos")]
@@ -976,7 +1119,7 @@ pub fn mount<FS: Filesystem, P: AsRef<Path>>(
     options: &[&OsStr],
 ) -> io::Result<()> {
     let options = parse_options_from_args(options)?;
-    mount2(filesystem, mountpoint, options.as_ref())
+    mount2(filesystem, mountpoint, options.as_ref()).map_err(Into::into)
 }
 
 /// Mount the given filesystem to the given mountpoint. This function will
@@ -987,9 +1130,9 @@ pub fn mount2<FS: Filesystem, P: AsRef<Path>>(
     filesystem: FS,
     mountpoint: P,
     options: &[MountOption],
-) -> io::Result<()> {
+) -> Result<(), SessionError> {
     check_option_conflicts(options)?;
-    Session::new(filesystem, mountpoint.as_ref(), options).and_then(|mut se| se.run())
+    Session::new(filesystem, mountpoint.as_ref(), options)?.run()
 }
 
 /// Mount the given filesystem to the given mountpoint. This function spawns
@@ -1005,10 +1148,12 @@ pub fn spawn_mount<'a, FS: Filesystem + Send + 'static + 'a, P: AsRef<Path>>(
 ) -> io::Result<BackgroundSession> {
     let options: Option<Vec<_>> = options
         .iter()
-        .map(|x| Some(MountOption::from_str(x.to_str()?)))
+        .map(|x| parse_option(x.to_str()?, false).ok())
         .collect();
     let options = options.ok_or(ErrorKind::InvalidData)?;
-    Session::new(filesystem, mountpoint.as_ref(), options.as_ref()).and_then(|se| se.spawn())
+    Session::new(filesystem, mountpoint.as_ref(), options.as_ref())
+        .and_then(|se| se.spawn())
+        .map_err(Into::into)
 }
 
 /// Mount the given filesystem to the given mountpoint. This function spawns
@@ -1022,7 +1167,20 @@ pub fn spawn_mount2<'a, FS: Filesystem + Send + 'static + 'a, P: AsRef<Path>>(
     filesystem: FS,
     mountpoint: P,
     options: &[MountOption],
-) -> io::Result<BackgroundSession> {
+) -> Result<BackgroundSession, MountError> {
     check_option_conflicts(options)?;
     Session::new(filesystem, mountpoint.as_ref(), options).and_then(|se| se.spawn())
 }
+
+/// Mount the given filesystem to the given mountpoint, and handle its operations in a thread of
+/// the given scope. Unlike `spawn_mount2`, the filesystem may borrow from outside of the scope;
+/// it is unmounted when the returned handle is dropped, at the latest when the scope ends.
+pub fn spawn_mount_scoped<'scope, 'env, FS: Filesystem + Send + 'scope, P: AsRef<Path>>(
+    scope: &'scope std::thread::Scope<'scope, 'env>,
+    filesystem: FS,
+    mountpoint: P,
+    options: &[MountOption],
+) -> Result<ScopedBackgroundSession<'scope>, MountError> {
+    check_option_conflicts(options)?;
+    Session::new(filesystem, mountpoint.as_ref(), options).and_then(|se| se.spawn_scoped(scope))
+}
@@ -294,6 +294,12 @@ pub mod consts {
     #[cfg(feature = "abi-7-9")]
     pub const FUSE_POLL_SCHEDULE_NOTIFY: u32 = 1 << 0; // request poll notify
 
+    // Setupmapping flags
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_SETUPMAPPING_FLAG_WRITE: u64 = 1 << 0;
+    #[cfg(feature = "abi-7-31")]
+    pub const FUSE_SETUPMAPPING_FLAG_READ: u64 = 1 << 1;
+
     // fsync flags
     pub const FUSE_FSYNC_FDATASYNC: u32 = 1 << 0; // Sync data only, not metadata
 
@@ -363,6 +369,10 @@ pub enum fuse_opcode {
     FUSE_LSEEK = 46,
     #[cfg(feature = "abi-7-28")]
     FUSE_COPY_FILE_RANGE = 47,
+    #[cfg(feature = "abi-7-31")]
+    FUSE_SETUPMAPPING = 48,
+    #[cfg(feature = "abi-7-31")]
+    FUSE_REMOVEMAPPING = 49,
 
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
@@ -434,6 +444,10 @@ impl TryFrom<u32> for fuse_opcode {
             46 => Ok(fuse_opcode::FUSE_LSEEK),
             #[cfg(feature = "abi-7-28")]
             47 => Ok(fuse_opcode::FUSE_COPY_FILE_RANGE),
+            #[cfg(feature = "abi-7-31")]
+            48 => Ok(fuse_opcode::FUSE_SETUPMAPPING),
+            #[cfg(feature = "abi-7-31")]
+            49 => Ok(fuse_opcode::FUSE_REMOVEMAPPING),
 
             #[cfg(target_os = "macos")]
             61 => Ok(fuse_opcode::FUSE_SETVOLNAME),
@@ -1135,3 +1149,30 @@ pub struct fuse_copy_file_range_in {
     pub len: u64,
     pub flags: u64,
 }
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+pub struct fuse_setupmapping_in {
+    pub fh: u64,
+    pub foffset: u64,
+    pub len: u64,
+    pub flags: u64,
+    pub moffset: u64,
+}
+
+// Followed by `count` fuse_removemapping_one entries, which are therefore not 8 byte aligned
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+pub struct fuse_removemapping_in {
+    pub count: u32,
+}
+
+#[cfg(feature = "abi-7-31")]
+#[repr(C)]
+#[derive(Debug, FromBytes)]
+pub struct fuse_removemapping_one {
+    pub moffset: u64,
+    pub len: u64,
+}
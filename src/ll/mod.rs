@@ -9,7 +9,8 @@ use std::{convert::TryInto, num::NonZeroI32, time::SystemTime};
 
 pub use reply::Response;
 pub use request::{
-    AnyRequest, FileHandle, INodeNo, Lock, Operation, Request, RequestError, RequestId, Version,
+    expects_reply, peek_header, AnyRequest, FileHandle, INodeNo, Lock, Operation, Request,
+    RequestError, RequestId, Version,
 };
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -266,7 +267,7 @@ impl From<Generation> for u64 {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use std::ops::{Deref, DerefMut};
     /// If we want to be able to cast bytes to our fuse C struct types we need it
     /// to be aligned.  This struct helps getting &[u8]s which are 8 byte aligned.
@@ -285,4 +286,20 @@ mod test {
             &mut self.0
         }
     }
+
+    /// Builds a request for node 1 with the given opcode, unique id and body words
+    pub(crate) fn request(opcode: u32, unique: u64, body: &[u32]) -> Vec<u8> {
+        let len = 40 + 4 * body.len() as u32;
+        let mut data = vec![];
+        data.extend_from_slice(&len.to_ne_bytes());
+        data.extend_from_slice(&opcode.to_ne_bytes());
+        data.extend_from_slice(&unique.to_ne_bytes());
+        // Node id, uid, gid, pid and padding
+        data.extend_from_slice(&1u64.to_ne_bytes());
+        data.extend_from_slice(&[0; 16]);
+        for x in body {
+            data.extend_from_slice(&x.to_ne_bytes());
+        }
+        data
+    }
 }
@@ -23,6 +23,14 @@ pub enum Response {
     Data(ResponseBuf),
 }
 
+/// Reads the unique id of the request and the error of a serialized reply
+pub(crate) fn peek_reply_header(data: &[u8]) -> Option<(RequestId, i32)> {
+    let header = data.get(..size_of::<abi::fuse_out_header>())?;
+    let error = i32::from_ne_bytes(header[4..8].try_into().unwrap());
+    let unique = u64::from_ne_bytes(header[8..16].try_into().unwrap());
+    Some((RequestId(unique), error))
+}
+
 #[must_use]
 impl Response {
     pub(crate) fn with_iovec<F: FnOnce(&[IoSlice<'_>]) -> T, T>(
@@ -513,6 +521,14 @@ mod test {
         );
     }
 
+    #[test]
+    fn peek_reply() {
+        let r = Response::new_error(Errno(NonZeroI32::new(66).unwrap()));
+        let data = r.with_iovec(RequestId(0xdeadbeef), ioslice_to_vec);
+        assert_eq!(peek_reply_header(&data), Some((RequestId(0xdeadbeef), -66)));
+        assert_eq!(peek_reply_header(&data[..15]), None);
+    }
+
     #[test]
     fn reply_data() {
         let r = Response::new_data([0xde, 0xad, 0xbe, 0xef].as_ref());
@@ -274,6 +274,8 @@ mod op {
     use super::{
         abi::consts::*, abi::*, FileHandle, INodeNo, Lock, LockOwner, Operation, RequestId,
     };
+    #[cfg(feature = "abi-7-31")]
+    use std::mem;
     use std::{
         convert::TryInto,
         ffi::OsStr,
@@ -283,6 +285,8 @@ mod op {
         time::{Duration, SystemTime},
     };
     use zerocopy::AsBytes;
+    #[cfg(feature = "abi-7-31")]
+    use zerocopy::FromBytes;
 
     /// Look up a directory entry by name and get its attributes.
     ///
@@ -1526,6 +1530,62 @@ mod op {
         }
     }
 
+    /// Map a range of a file into the DAX window of a virtio-fs device, so the guest can access
+    /// it directly instead of sending [Read] and [Write] requests.
+    #[cfg(feature = "abi-7-31")]
+    #[derive(Debug)]
+    pub struct SetupMapping<'a> {
+        header: &'a fuse_in_header,
+        arg: &'a fuse_setupmapping_in,
+    }
+    #[cfg(feature = "abi-7-31")]
+    impl_request!(SetupMapping<'a>);
+    #[cfg(feature = "abi-7-31")]
+    impl<'a> SetupMapping<'a> {
+        /// The value set by the [Open] method. See [FileHandle].
+        pub fn file_handle(&self) -> FileHandle {
+            FileHandle(self.arg.fh)
+        }
+        /// Offset of the range in the file
+        pub fn file_offset(&self) -> u64 {
+            self.arg.foffset
+        }
+        pub fn len(&self) -> u64 {
+            self.arg.len
+        }
+        /// `FUSE_SETUPMAPPING_FLAG_READ` and `FUSE_SETUPMAPPING_FLAG_WRITE`
+        pub fn flags(&self) -> u64 {
+            self.arg.flags
+        }
+        /// Offset of the range in the DAX window
+        pub fn window_offset(&self) -> u64 {
+            self.arg.moffset
+        }
+    }
+
+    /// Remove ranges from the DAX window of a virtio-fs device
+    #[cfg(feature = "abi-7-31")]
+    #[derive(Debug)]
+    pub struct RemoveMapping<'a> {
+        header: &'a fuse_in_header,
+        arg: &'a fuse_removemapping_in,
+        mappings: &'a [u8],
+    }
+    #[cfg(feature = "abi-7-31")]
+    impl_request!(RemoveMapping<'a>);
+    #[cfg(feature = "abi-7-31")]
+    impl<'a> RemoveMapping<'a> {
+        /// Offsets in the DAX window and lengths of the ranges to remove
+        pub fn mappings(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+            self.mappings
+                .chunks_exact(mem::size_of::<fuse_removemapping_one>())
+                .take(self.arg.count as usize)
+                // Copied, since the entries are not aligned
+                .map(|x| fuse_removemapping_one::read_from(x).unwrap())
+                .map(|x| (x.moffset, x.len))
+        }
+    }
+
     /// MacOS only: Rename the volume. Set `fuse_init_out.flags` during init to
     /// `FUSE_VOL_RENAME` to enable
     #[cfg(target_os = "macos")]
@@ -1810,6 +1870,25 @@ mod op {
                 header,
                 arg: data.fetch()?,
             }),
+            #[cfg(feature = "abi-7-31")]
+            fuse_opcode::FUSE_SETUPMAPPING => Operation::SetupMapping(SetupMapping {
+                header,
+                arg: data.fetch()?,
+            }),
+            #[cfg(feature = "abi-7-31")]
+            fuse_opcode::FUSE_REMOVEMAPPING => {
+                let arg: &fuse_removemapping_in = data.fetch()?;
+                let mappings = data.fetch_all();
+                let len = arg.count as usize * mem::size_of::<fuse_removemapping_one>();
+                if mappings.len() < len {
+                    return None;
+                }
+                Operation::RemoveMapping(RemoveMapping {
+                    header,
+                    arg,
+                    mappings,
+                })
+            }
 
             #[cfg(target_os = "macos")]
             fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName(SetVolName {
@@ -1895,6 +1974,10 @@ pub enum Operation<'a> {
     Lseek(Lseek<'a>),
     #[cfg(feature = "abi-7-28")]
     CopyFileRange(CopyFileRange<'a>),
+    #[cfg(feature = "abi-7-31")]
+    SetupMapping(SetupMapping<'a>),
+    #[cfg(feature = "abi-7-31")]
+    RemoveMapping(RemoveMapping<'a>),
 
     #[cfg(target_os = "macos")]
     SetVolName(SetVolName<'a>),
@@ -2080,6 +2163,22 @@ impl<'a> fmt::Display for Operation<'a> {
                 x.dest(),
                 x.len()
             ),
+            #[cfg(feature = "abi-7-31")]
+            Operation::SetupMapping(x) => write!(
+                f,
+                "SETUPMAPPING fh {:?}, foffset {}, len {}, flags {:#x}, moffset {}",
+                x.file_handle(),
+                x.file_offset(),
+                x.len(),
+                x.flags(),
+                x.window_offset()
+            ),
+            #[cfg(feature = "abi-7-31")]
+            Operation::RemoveMapping(x) => write!(
+                f,
+                "REMOVEMAPPING mappings {:?}",
+                x.mappings().collect::<Vec<_>>()
+            ),
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName(x) => write!(f, "SETVOLNAME name {:?}", x.name()),
@@ -2118,6 +2217,25 @@ impl<'a> AnyRequest<'a> {
     }
 }
 
+/// Reads the unique id and opcode from the header of a raw request. This succeeds as long as the
+/// header itself is complete, even if the rest of the request is malformed.
+pub fn peek_header(data: &[u8]) -> Option<(RequestId, u32)> {
+    let header: &fuse_in_header = ArgumentIterator::new(data).fetch()?;
+    Some((RequestId(header.unique), header.opcode))
+}
+
+/// Whether the kernel waits for a reply to a request with the given opcode
+pub fn expects_reply(opcode: u32) -> bool {
+    match fuse_opcode::try_from(opcode) {
+        Ok(fuse_opcode::FUSE_FORGET) => false,
+        #[cfg(feature = "abi-7-16")]
+        Ok(fuse_opcode::FUSE_BATCH_FORGET) => false,
+        // Replies to interrupts are optional
+        Ok(fuse_opcode::FUSE_INTERRUPT) => false,
+        _ => true,
+    }
+}
+
 impl<'a> fmt::Display for AnyRequest<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Ok(op) = self.operation() {
@@ -2237,6 +2355,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn peek_header_of_short_read() {
+        assert_eq!(
+            peek_header(&INIT_REQUEST[..48]),
+            Some((RequestId(0xdead_beef_baad_f00d), 26))
+        );
+        assert_eq!(peek_header(&INIT_REQUEST[..20]), None);
+    }
+
     #[test]
     fn init() {
         let req = AnyRequest::try_from(&INIT_REQUEST[..]).unwrap();
@@ -2279,4 +2406,73 @@ mod tests {
             _ => panic!("Unexpected request operation"),
         }
     }
+
+    #[cfg(all(target_endian = "little", feature = "abi-7-31"))]
+    const SETUPMAPPING_REQUEST: AlignedData<[u8; 80]> = AlignedData([
+        0x50, 0x00, 0x00, 0x00, 0x30, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // fh
+        0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // foffset
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // len
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // flags
+        0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, // moffset
+    ]);
+
+    #[cfg(all(target_endian = "little", feature = "abi-7-31"))]
+    const REMOVEMAPPING_REQUEST: AlignedData<[u8; 76]> = AlignedData([
+        0x4c, 0x00, 0x00, 0x00, 0x31, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x02, 0x00, 0x00, 0x00, // count
+        0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, // moffset
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // len
+        0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // moffset
+        0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // len
+    ]);
+
+    #[test]
+    #[cfg(all(target_endian = "little", feature = "abi-7-31"))]
+    fn setupmapping() {
+        let req = AnyRequest::try_from(&SETUPMAPPING_REQUEST[..]).unwrap();
+        assert_eq!(req.header.len, 80);
+        assert_eq!(req.header.opcode, 48);
+        assert_eq!(req.nodeid(), INodeNo(0x1122_3344_5566_7788));
+        match req.operation().unwrap() {
+            Operation::SetupMapping(x) => {
+                assert_eq!(x.file_handle(), FileHandle(42));
+                assert_eq!(x.file_offset(), 0x2000);
+                assert_eq!(x.len(), 0x1000);
+                let flags = abi::consts::FUSE_SETUPMAPPING_FLAG_READ
+                    | abi::consts::FUSE_SETUPMAPPING_FLAG_WRITE;
+                assert_eq!(x.flags(), flags);
+                assert_eq!(x.window_offset(), 0x20_0000);
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_endian = "little", feature = "abi-7-31"))]
+    fn removemapping() {
+        let req = AnyRequest::try_from(&REMOVEMAPPING_REQUEST[..]).unwrap();
+        assert_eq!(req.header.len, 76);
+        assert_eq!(req.header.opcode, 49);
+        match req.operation().unwrap() {
+            Operation::RemoveMapping(x) => {
+                let mappings: Vec<_> = x.mappings().collect();
+                assert_eq!(mappings, [(0x20_0000, 0x1000), (0x40_0000, 0x2000)]);
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+        // Fewer entries than announced
+        let mut short = AlignedData(REMOVEMAPPING_REQUEST.0);
+        short[0] = 60;
+        let req = AnyRequest::try_from(&short[..60]).unwrap();
+        assert!(req.operation().is_err());
+    }
 }
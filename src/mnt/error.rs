@@ -0,0 +1,243 @@
+//! Errors that may occur while mounting a filesystem
+
+use std::path::{Path, PathBuf};
+use std::{error, fmt, io};
+
+use super::mount_options::MountOption;
+
+/// Error that may occur while mounting a filesystem
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MountError {
+    /// Neither `fusermount3` nor `fusermount` could be found, but they are needed to mount
+    /// without root privileges
+    FusermountNotFound,
+    /// `allow_other` or `allow_root` was requested, but `user_allow_other` is not set in
+    /// /etc/fuse.conf
+    UserAllowOtherNotConfigured,
+    /// The mountpoint does not exist
+    MountpointNotFound(PathBuf),
+    /// The mountpoint is not a directory
+    MountpointNotDirectory(PathBuf),
+    /// The mountpoint is not empty
+    MountpointNotEmpty(PathBuf),
+    /// The mountpoint is a FUSE mount whose filesystem process has died
+    StaleMountpoint(PathBuf),
+    /// The mountpoint is a stale FUSE mount, and unmounting it failed
+    StaleMountpointNotRecovered {
+        /// The stale mountpoint
+        mountpoint: PathBuf,
+        /// Error that occurred while unmounting
+        error: io::Error,
+    },
+    /// The current user is not permitted to mount at the mountpoint
+    PermissionDenied(PathBuf),
+    /// The device given for a `BlkDev` mount is not a block device
+    NotBlockDevice(PathBuf),
+    /// The option needs a block device, given with `BlkDev` and `FSName`, but `BlkDev` or the
+    /// device is missing
+    BlockDeviceRequired(MountOption),
+    /// The FUSE device could not be opened. The fuse kernel module may not be loaded
+    DeviceUnavailable(io::Error),
+    /// The given mount options conflict with each other
+    ConflictingOptions(Vec<MountOption>),
+    /// `fusermount` failed for another reason. Contains the message it printed
+    Fusermount(String),
+    /// The kernel rejected the mount. Contains the messages it logged, if any
+    Kernel {
+        /// Error returned by the kernel
+        error: io::Error,
+        /// Messages the kernel logged while setting up the mount
+        log: Vec<String>,
+    },
+    /// Any other I/O error
+    Io(io::Error),
+}
+
+impl MountError {
+    /// Classify an error returned by a system call that operated on the given mountpoint
+    pub(crate) fn from_io_at(err: io::Error, mountpoint: &Path) -> MountError {
+        let mountpoint = mountpoint.to_path_buf();
+        match err.raw_os_error() {
+            Some(libc::ENOENT) => MountError::MountpointNotFound(mountpoint),
+            Some(libc::ENOTDIR) => MountError::MountpointNotDirectory(mountpoint),
+            Some(libc::ENOTEMPTY) => MountError::MountpointNotEmpty(mountpoint),
+            Some(libc::ENOTCONN) => MountError::StaleMountpoint(mountpoint),
+            Some(libc::EACCES) | Some(libc::EPERM) => MountError::PermissionDenied(mountpoint),
+            _ => MountError::Io(err),
+        }
+    }
+
+    /// Classify the message `fusermount` printed to stderr when it failed to mount
+    pub(crate) fn from_fusermount_stderr(stderr: String, mountpoint: &Path) -> MountError {
+        let mountpoint = mountpoint.to_path_buf();
+        if stderr.contains("only allowed if 'user_allow_other' is set") {
+            MountError::UserAllowOtherNotConfigured
+        } else if stderr.contains("Transport endpoint is not connected") {
+            MountError::StaleMountpoint(mountpoint)
+        } else if stderr.contains("mountpoint is not empty") {
+            MountError::MountpointNotEmpty(mountpoint)
+        } else if stderr.contains("fuse device not found")
+            || stderr.contains("failed to open /dev/fuse")
+        {
+            MountError::DeviceUnavailable(io::Error::new(io::ErrorKind::NotFound, stderr))
+        } else if stderr.contains("Not a directory") {
+            MountError::MountpointNotDirectory(mountpoint)
+        } else if stderr.contains("No such file or directory") {
+            MountError::MountpointNotFound(mountpoint)
+        } else if stderr.contains("Permission denied") {
+            MountError::PermissionDenied(mountpoint)
+        } else {
+            MountError::Fusermount(stderr)
+        }
+    }
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountError::FusermountNotFound => write!(f, "fusermount binary not found"),
+            MountError::UserAllowOtherNotConfigured => write!(
+                f,
+                "allow_other and allow_root require 'user_allow_other' to be set in /etc/fuse.conf"
+            ),
+            MountError::MountpointNotFound(path) => {
+                write!(f, "Mountpoint {} does not exist", path.display())
+            }
+            MountError::MountpointNotDirectory(path) => {
+                write!(f, "Mountpoint {} is not a directory", path.display())
+            }
+            MountError::MountpointNotEmpty(path) => {
+                write!(f, "Mountpoint {} is not empty", path.display())
+            }
+            MountError::StaleMountpoint(path) => write!(
+                f,
+                "Mountpoint {} is a stale FUSE mount (transport endpoint is not connected)",
+                path.display()
+            ),
+            MountError::StaleMountpointNotRecovered { mountpoint, error } => write!(
+                f,
+                "Failed to unmount stale FUSE mount at {}: {}",
+                mountpoint.display(),
+                error
+            ),
+            MountError::PermissionDenied(path) => {
+                write!(f, "Permission denied to mount at {}", path.display())
+            }
+            MountError::NotBlockDevice(path) => {
+                write!(f, "{} is not a block device", path.display())
+            }
+            MountError::BlockDeviceRequired(option) => write!(
+                f,
+                "Mount option {} requires a block device, given with blkdev and fsname",
+                option
+            ),
+            MountError::DeviceUnavailable(err) => {
+                write!(f, "FUSE device is unavailable: {}", err)
+            }
+            MountError::ConflictingOptions(options) => {
+                write!(f, "Conflicting mount options found: {:?}", options)
+            }
+            MountError::Fusermount(message) => write!(f, "fusermount failed: {}", message),
+            MountError::Kernel { error, log } if log.is_empty() => {
+                write!(f, "Mount failed: {}", error)
+            }
+            MountError::Kernel { error, log } => {
+                write!(f, "Mount failed: {} ({})", error, log.join("; "))
+            }
+            MountError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for MountError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MountError::DeviceUnavailable(err)
+            | MountError::Kernel { error: err, .. }
+            | MountError::StaleMountpointNotRecovered { error: err, .. }
+            | MountError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MountError {
+    fn from(err: io::Error) -> Self {
+        MountError::Io(err)
+    }
+}
+
+impl From<MountError> for io::Error {
+    fn from(err: MountError) -> Self {
+        if let MountError::Io(err) = err {
+            return err;
+        }
+        let kind = match &err {
+            MountError::FusermountNotFound | MountError::MountpointNotFound(_) => {
+                io::ErrorKind::NotFound
+            }
+            MountError::UserAllowOtherNotConfigured | MountError::PermissionDenied(_) => {
+                io::ErrorKind::PermissionDenied
+            }
+            MountError::StaleMountpoint(_) | MountError::StaleMountpointNotRecovered { .. } => {
+                io::ErrorKind::NotConnected
+            }
+            MountError::ConflictingOptions(_)
+            | MountError::NotBlockDevice(_)
+            | MountError::BlockDeviceRequired(_) => io::ErrorKind::InvalidInput,
+            MountError::DeviceUnavailable(err) | MountError::Kernel { error: err, .. } => {
+                err.kind()
+            }
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_fusermount_stderr() {
+        let mnt = Path::new("/mnt");
+        assert!(matches!(
+            MountError::from_fusermount_stderr(
+                "fusermount3: option allow_other only allowed if 'user_allow_other' is set in /etc/fuse.conf\n".to_owned(),
+                mnt
+            ),
+            MountError::UserAllowOtherNotConfigured
+        ));
+        assert!(matches!(
+            MountError::from_fusermount_stderr(
+                "fusermount3: failed to access mountpoint /mnt: Transport endpoint is not connected\n".to_owned(),
+                mnt
+            ),
+            MountError::StaleMountpoint(_)
+        ));
+        assert!(matches!(
+            MountError::from_fusermount_stderr(
+                "fusermount3: failed to access mountpoint /mnt: No such file or directory\n"
+                    .to_owned(),
+                mnt
+            ),
+            MountError::MountpointNotFound(_)
+        ));
+        assert!(matches!(
+            MountError::from_fusermount_stderr(
+                "fusermount3: unknown option 'foo'\n".to_owned(),
+                mnt
+            ),
+            MountError::Fusermount(_)
+        ));
+    }
+
+    #[test]
+    fn into_io_error() {
+        let err: io::Error = MountError::UserAllowOtherNotConfigured.into();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err: io::Error = MountError::Io(io::Error::from_raw_os_error(libc::EIO)).into();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+}
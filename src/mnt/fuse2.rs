@@ -1,34 +1,84 @@
-use super::{ensure_last_os_error, fuse2_sys::*, with_fuse_args, MountOption};
+use super::{
+    ensure_last_os_error, fuse2_sys::*, with_fuse_args, AutoUnmountKind, MountError, MountOption,
+    ReleasedMount,
+};
 use log::warn;
 use std::{
-    ffi::CString,
+    ffi::{CString, OsString},
     fs::File,
     io,
-    os::unix::prelude::{FromRawFd, OsStrExt},
+    mem::ManuallyDrop,
+    os::unix::prelude::{FromRawFd, OsStrExt, OsStringExt, OwnedFd},
     path::Path,
+    ptr,
     sync::Arc,
 };
 
 #[derive(Debug)]
 pub struct Mount {
     mountpoint: CString,
+    /// Auto-unmount socket of a mount that was taken over from another process, which is only
+    /// closed once the filesystem was unmounted
+    _auto_unmount: Option<(AutoUnmountKind, OwnedFd)>,
 }
 impl Mount {
-    pub fn new(mountpoint: &Path, options: &[MountOption]) -> io::Result<(Arc<File>, Mount)> {
+    pub fn new(
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> Result<(Arc<File>, Mount), MountError> {
+        let path = mountpoint;
         let mountpoint = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
         with_fuse_args(options, |args| {
             let fd = unsafe { fuse_mount_compat25(mountpoint.as_ptr(), args) };
             if fd < 0 {
-                Err(ensure_last_os_error())
+                Err(MountError::from_io_at(ensure_last_os_error(), path))
             } else {
                 let file = unsafe { File::from_raw_fd(fd) };
-                Ok((Arc::new(file), Mount { mountpoint }))
+                let mount = Mount {
+                    mountpoint,
+                    _auto_unmount: None,
+                };
+                Ok((Arc::new(file), mount))
             }
         })
     }
-}
-impl Drop for Mount {
-    fn drop(&mut self) {
+
+    /// Release the mount without unmounting it, so that another process can take it over.
+    /// libfuse keeps the auto-unmount socket of fusermount to itself, so only the socket of a
+    /// mount that was taken over is handed on.
+    pub(crate) fn release(self) -> ReleasedMount {
+        let this = ManuallyDrop::new(self);
+        // Move the fields out without running `Drop`, which would unmount
+        let (mountpoint, auto_unmount) =
+            unsafe { (ptr::read(&this.mountpoint), ptr::read(&this._auto_unmount)) };
+        ReleasedMount {
+            mountpoint: OsString::from_vec(mountpoint.into_bytes()).into(),
+            auto_unmount,
+        }
+    }
+
+    /// Take over a mount that another process released
+    pub(crate) fn take_over(released: ReleasedMount, _fuse_device: Arc<File>) -> io::Result<Mount> {
+        Ok(Mount {
+            mountpoint: CString::new(released.mountpoint.into_os_string().into_vec())?,
+            _auto_unmount: released.auto_unmount,
+        })
+    }
+
+    /// Unmount the filesystem like dropping the mount does, but report if that failed
+    pub(crate) fn unmount(self) -> io::Result<()> {
+        let this = ManuallyDrop::new(self);
+        let result = this.try_unmount();
+        // Drop the fields without trying again. The auto-unmount socket is only closed after
+        // the unmount.
+        unsafe {
+            drop(ptr::read(&this._auto_unmount));
+            drop(ptr::read(&this.mountpoint));
+        }
+        result
+    }
+
+    fn try_unmount(&self) -> io::Result<()> {
         use std::io::ErrorKind::PermissionDenied;
 
         // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
@@ -51,9 +101,17 @@ impl Drop for Mount {
                 )))]
                 unsafe {
                     fuse_unmount_compat22(self.mountpoint.as_ptr());
-                    return;
+                    return Ok(());
                 }
             }
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if let Err(err) = self.try_unmount() {
             warn!("umount failed with {:?}", err);
         }
     }
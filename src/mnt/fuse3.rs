@@ -2,55 +2,134 @@ use super::fuse3_sys::{
     fuse_session_destroy, fuse_session_fd, fuse_session_mount, fuse_session_new,
     fuse_session_unmount,
 };
-use super::{ensure_last_os_error, with_fuse_args, MountOption};
+use super::{
+    ensure_last_os_error, with_fuse_args, AutoUnmountKind, MountError, MountOption, ReleasedMount,
+};
+use log::{debug, warn};
 use std::{
-    ffi::{c_void, CString},
+    ffi::{c_void, CString, OsStr, OsString},
     fs::File,
     io,
-    os::unix::{ffi::OsStrExt, io::FromRawFd},
+    mem::ManuallyDrop,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        io::{FromRawFd, OwnedFd},
+    },
     path::Path,
+    process::{Command, Stdio},
     ptr,
     sync::Arc,
 };
 
 #[derive(Debug)]
 pub struct Mount {
+    /// Null if the mount was taken over from another process
     fuse_session: *mut c_void,
+    mountpoint: CString,
+    /// Auto-unmount socket of a mount that was taken over from another process, which is only
+    /// closed once the filesystem was unmounted
+    _auto_unmount: Option<(AutoUnmountKind, OwnedFd)>,
 }
 impl Mount {
-    pub fn new(mnt: &Path, options: &[MountOption]) -> io::Result<(Arc<File>, Mount)> {
-        let mnt = CString::new(mnt.as_os_str().as_bytes()).unwrap();
+    pub fn new(
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> Result<(Arc<File>, Mount), MountError> {
+        let mnt = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
         with_fuse_args(options, |args| {
             let fuse_session = unsafe { fuse_session_new(args, ptr::null(), 0, ptr::null_mut()) };
             if fuse_session.is_null() {
-                return Err(io::Error::last_os_error());
+                return Err(io::Error::last_os_error().into());
             }
-            let mount = Mount { fuse_session };
+            let mount = Mount {
+                fuse_session,
+                mountpoint: mnt.clone(),
+                _auto_unmount: None,
+            };
             let result = unsafe { fuse_session_mount(mount.fuse_session, mnt.as_ptr()) };
             if result != 0 {
-                return Err(ensure_last_os_error());
+                return Err(MountError::from_io_at(ensure_last_os_error(), mountpoint));
             }
             let fd = unsafe { fuse_session_fd(mount.fuse_session) };
             if fd < 0 {
-                return Err(io::Error::last_os_error());
+                return Err(io::Error::last_os_error().into());
             }
             // We dup the fd here as the existing fd is owned by the fuse_session, and we
             // don't want it being closed out from under us:
             let fd = unsafe { libc::dup(fd) };
             if fd < 0 {
-                return Err(io::Error::last_os_error());
+                return Err(io::Error::last_os_error().into());
             }
             let file = unsafe { File::from_raw_fd(fd) };
             Ok((Arc::new(file), mount))
         })
     }
+
+    /// Release the mount without unmounting it, so that another process can take it over.
+    /// libfuse keeps the auto-unmount socket of fusermount3 to itself, so only the socket of a
+    /// mount that was taken over is handed on.
+    pub(crate) fn release(self) -> ReleasedMount {
+        let this = ManuallyDrop::new(self);
+        // Move the fields out without running `Drop`, which would unmount
+        let (mountpoint, auto_unmount) =
+            unsafe { (ptr::read(&this.mountpoint), ptr::read(&this._auto_unmount)) };
+        if !this.fuse_session.is_null() {
+            // Frees the session without unmounting
+            unsafe { fuse_session_destroy(this.fuse_session) };
+        }
+        ReleasedMount {
+            mountpoint: OsString::from_vec(mountpoint.into_bytes()).into(),
+            auto_unmount,
+        }
+    }
+
+    /// Take over a mount that another process released. It is unmounted like fusermount3 would,
+    /// since libfuse can't unmount without its own session.
+    pub(crate) fn take_over(released: ReleasedMount, _fuse_device: Arc<File>) -> io::Result<Mount> {
+        Ok(Mount {
+            fuse_session: ptr::null_mut(),
+            mountpoint: CString::new(released.mountpoint.into_os_string().into_vec())?,
+            _auto_unmount: released.auto_unmount,
+        })
+    }
+
+    /// Unmount the filesystem. libfuse doesn't report whether that worked, so this never fails.
+    pub(crate) fn unmount(self) -> io::Result<()> {
+        drop(self);
+        Ok(())
+    }
 }
 impl Drop for Mount {
     fn drop(&mut self) {
+        if self.fuse_session.is_null() {
+            unmount_taken_over(&self.mountpoint);
+            return;
+        }
         unsafe {
             fuse_session_unmount(self.fuse_session);
             fuse_session_destroy(self.fuse_session);
         }
     }
 }
+
+/// Unmount a filesystem without a libfuse session, through fusermount3 if this process isn't
+/// allowed to
+fn unmount_taken_over(mountpoint: &CString) {
+    if super::libc_umount(mountpoint).is_ok() {
+        return;
+    }
+    let output = Command::new("fusermount3")
+        .arg("-u")
+        .arg("-q")
+        .arg("-z")
+        .arg("--")
+        .arg(OsStr::from_bytes(mountpoint.as_bytes()))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output();
+    match output {
+        Ok(output) => debug!("fusermount3: {}", String::from_utf8_lossy(&output.stderr)),
+        Err(err) => warn!("Unmount failed: {}", err),
+    }
+}
 unsafe impl Send for Mount {}
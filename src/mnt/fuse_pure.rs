@@ -7,21 +7,26 @@
 #![allow(missing_docs)]
 
 use super::is_mounted;
+#[cfg(target_os = "linux")]
+use super::mount_api::{move_mount, FsContext};
 use super::mount_options::{option_to_string, MountOption};
-use libc::c_int;
+use super::{AutoUnmountKind, MountError, ReleasedMount};
+use crate::fd_passing::recv_fd;
 use log::{debug, error};
-use std::ffi::{CStr, CString, OsStr};
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fs::{File, OpenOptions};
-use std::io;
 use std::io::{Error, ErrorKind, Read};
-use std::os::unix::ffi::OsStrExt;
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsFd;
+use std::os::unix::io::{AsRawFd, OwnedFd};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::sync::Arc;
-use std::{mem, ptr};
+use std::{io, mem, ptr};
 
 const FUSERMOUNT_BIN: &str = "fusermount";
 const FUSERMOUNT3_BIN: &str = "fusermount3";
@@ -30,69 +35,304 @@ const FUSERMOUNT_COMM_ENV: &str = "_FUSE_COMMFD";
 #[derive(Debug)]
 pub struct Mount {
     mountpoint: CString,
-    auto_unmount_socket: Option<UnixStream>,
+    auto_unmount: Option<AutoUnmount>,
     fuse_device: Arc<File>,
 }
+
+/// Who takes care of unmounting when this process exits
+#[derive(Debug)]
+enum AutoUnmount {
+    /// fusermount keeps running until this socket is closed, and then unmounts
+    Fusermount { _socket: UnixStream },
+    /// A forked helper process unmounts once this process exits
+    Watchdog { _watchdog: Watchdog },
+}
 impl Mount {
-    pub fn new(mountpoint: &Path, options: &[MountOption]) -> io::Result<(Arc<File>, Mount)> {
-        let mountpoint = mountpoint.canonicalize()?;
-        let (file, sock) = fuse_mount_pure(mountpoint.as_os_str(), options)?;
+    pub fn new(
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> Result<(Arc<File>, Mount), MountError> {
+        let mountpoint = mountpoint
+            .canonicalize()
+            .map_err(|err| MountError::from_io_at(err, mountpoint))?;
+        let mountpoint = CString::new(mountpoint.as_os_str().as_bytes()).map_err(Error::from)?;
+        let (file, auto_unmount) = fuse_mount_pure(&mountpoint, options)?;
         let file = Arc::new(file);
         Ok((
             file.clone(),
             Mount {
-                mountpoint: CString::new(mountpoint.as_os_str().as_bytes())?,
-                auto_unmount_socket: sock,
+                mountpoint,
+                auto_unmount,
                 fuse_device: file,
             },
         ))
     }
-}
 
-impl Drop for Mount {
-    fn drop(&mut self) {
+    /// Release the mount without unmounting it, so that another process can take it over
+    pub(crate) fn release(self) -> ReleasedMount {
+        let this = ManuallyDrop::new(self);
+        // Move the fields out without running `Drop`, which would unmount
+        let (mountpoint, auto_unmount, fuse_device) = unsafe {
+            (
+                ptr::read(&this.mountpoint),
+                ptr::read(&this.auto_unmount),
+                ptr::read(&this.fuse_device),
+            )
+        };
+        drop(fuse_device);
+        let auto_unmount = auto_unmount.map(|x| match x {
+            AutoUnmount::Fusermount { _socket } => (AutoUnmountKind::Fusermount, _socket.into()),
+            AutoUnmount::Watchdog { _watchdog } => (AutoUnmountKind::Watchdog, _watchdog.release()),
+        });
+        ReleasedMount {
+            mountpoint: OsString::from_vec(mountpoint.into_bytes()).into(),
+            auto_unmount,
+        }
+    }
+
+    /// Take over a mount that another process released, whose FUSE device was passed along
+    pub(crate) fn take_over(released: ReleasedMount, fuse_device: Arc<File>) -> io::Result<Mount> {
+        let mountpoint = CString::new(released.mountpoint.into_os_string().into_vec())?;
+        let auto_unmount = released.auto_unmount.map(|(kind, socket)| {
+            let socket = UnixStream::from(socket);
+            match kind {
+                AutoUnmountKind::Fusermount => AutoUnmount::Fusermount { _socket: socket },
+                AutoUnmountKind::Watchdog => AutoUnmount::Watchdog {
+                    _watchdog: Watchdog { socket, pid: None },
+                },
+            }
+        });
+        Ok(Mount {
+            mountpoint,
+            auto_unmount,
+            fuse_device,
+        })
+    }
+
+    /// Unmount the filesystem like dropping the mount does, but report if that failed
+    pub(crate) fn unmount(self) -> io::Result<()> {
+        let this = ManuallyDrop::new(self);
+        let result = this.try_unmount();
+        // Drop the fields without trying again. A watchdog is only stopped after the unmount.
+        unsafe {
+            drop(ptr::read(&this.auto_unmount));
+            drop(ptr::read(&this.fuse_device));
+            drop(ptr::read(&this.mountpoint));
+        }
+        result
+    }
+
+    fn try_unmount(&self) -> io::Result<()> {
         use std::io::ErrorKind::PermissionDenied;
         if !is_mounted(&self.fuse_device) {
             // If the filesystem has already been unmounted, avoid unmounting it again.
             // Unmounting it a second time could cause a race with a newly mounted filesystem
             // living at the same mountpoint
-            return;
+            return Ok(());
         }
-        if let Some(sock) = mem::take(&mut self.auto_unmount_socket) {
-            drop(sock);
-            // fusermount in auto-unmount mode, no more work to do.
-            return;
+        if let Some(AutoUnmount::Fusermount { .. }) = self.auto_unmount {
+            // fusermount in auto-unmount mode unmounts once the socket is closed, which happens
+            // right after this. No more work to do. (A watchdog is only stopped after we
+            // unmounted below.)
+            return Ok(());
         }
-        if let Err(err) = super::libc_umount(&self.mountpoint) {
-            if err.kind() == PermissionDenied {
-                // Linux always returns EPERM for non-root users.  We have to let the
-                // library go through the setuid-root "fusermount -u" to unmount.
-                fuse_unmount_pure(&self.mountpoint)
-            } else {
-                error!("Unmount failed: {}", err)
+        match super::libc_umount(&self.mountpoint) {
+            // Linux always returns EPERM for non-root users.  We have to let the
+            // library go through the setuid-root "fusermount -u" to unmount.
+            Err(err) if err.kind() == PermissionDenied => {
+                fuse_unmount_pure(&self.mountpoint);
+                Ok(())
             }
+            result => result,
         }
     }
 }
 
-fn fuse_mount_pure(
-    mountpoint: &OsStr,
-    options: &[MountOption],
-) -> Result<(File, Option<UnixStream>), io::Error> {
-    if options.contains(&MountOption::AutoUnmount) {
-        // Auto unmount is only supported via fusermount
-        return fuse_mount_fusermount(mountpoint, options);
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if let Err(err) = self.try_unmount() {
+            error!("Unmount failed: {}", err)
+        }
     }
+}
 
-    let res = fuse_mount_sys(mountpoint, options)?;
+fn fuse_mount_pure(
+    mountpoint: &CStr,
+    options: &[MountOption],
+) -> Result<(File, Option<AutoUnmount>), MountError> {
+    let mountpoint_os = OsStr::from_bytes(mountpoint.to_bytes());
+    let res = fuse_mount_sys(mountpoint_os, options)?;
     if let Some(file) = res {
-        Ok((file, None))
+        let mut auto_unmount = None;
+        if options.contains(&MountOption::AutoUnmount) {
+            // We mounted without fusermount, so we need our own watchdog to unmount
+            match Watchdog::spawn(mountpoint, &file) {
+                Ok(watchdog) => {
+                    auto_unmount = Some(AutoUnmount::Watchdog {
+                        _watchdog: watchdog,
+                    })
+                }
+                Err(err) => {
+                    let _ = super::libc_umount(mountpoint);
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok((file, auto_unmount))
     } else {
         // Retry
-        fuse_mount_fusermount(mountpoint, options)
+        let (file, sock) = fuse_mount_fusermount(mountpoint_os, options)?;
+        Ok((
+            file,
+            sock.map(|_socket| AutoUnmount::Fusermount { _socket }),
+        ))
     }
 }
 
+/// A forked helper process which lazily unmounts the filesystem once the process that mounted
+/// it exits, or drops the `Watchdog`.
+///
+/// The helper waits for EOF on a socket whose other end only this process holds. It also keeps
+/// a handle to the FUSE device, so the connection can't go away and be replaced by an unrelated
+/// mount at the same mountpoint before it had a chance to check.
+#[derive(Debug)]
+struct Watchdog {
+    socket: UnixStream,
+    /// Set if this process spawned the helper, and has to reap it
+    pid: Option<libc::pid_t>,
+}
+
+impl Watchdog {
+    fn spawn(mountpoint: &CStr, fuse_device: &File) -> io::Result<Watchdog> {
+        let (socket, child_socket) = UnixStream::pair()?;
+        // Everything the child needs is prepared before forking, since only async-signal-safe
+        // functions may be called in the child of a multithreaded process
+        let max_fd = max_open_fds();
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(Error::last_os_error());
+        }
+        if pid == 0 {
+            unsafe {
+                watchdog_main(
+                    mountpoint,
+                    child_socket.as_raw_fd(),
+                    fuse_device.as_raw_fd(),
+                    max_fd,
+                )
+            }
+        }
+        Ok(Watchdog {
+            socket,
+            pid: Some(pid),
+        })
+    }
+
+    /// Stop watching this process, and return the socket whose closing makes the helper
+    /// unmount. The helper is not reaped.
+    fn release(self) -> OwnedFd {
+        let this = ManuallyDrop::new(self);
+        unsafe { ptr::read(&this.socket) }.into()
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Closing our end of the socket makes the helper check the mount and exit
+        let _ = self.socket.shutdown(std::net::Shutdown::Both);
+        let pid = match self.pid {
+            Some(pid) => pid,
+            // Spawned by another process, which handed the mount over
+            None => return,
+        };
+        loop {
+            let res = unsafe { libc::waitpid(pid, ptr::null_mut(), 0) };
+            if res == -1 && Error::last_os_error().kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+    }
+}
+
+fn max_open_fds() -> libc::c_int {
+    let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+    if max < 0 {
+        1024
+    } else {
+        max.min(libc::c_int::MAX as libc::c_long) as libc::c_int
+    }
+}
+
+/// Close all descriptors after stderr except `first` and `second`. Async-signal-safe.
+///
+/// Uses `close_range` where the kernel has it (Linux 5.9), and otherwise closes every descriptor
+/// below `max_fd`, which may take long if the limit of open files is high.
+unsafe fn close_other_fds(first: libc::c_int, second: libc::c_int, max_fd: libc::c_int) {
+    #[cfg(target_os = "linux")]
+    {
+        let (low, high) = (i64::from(first.min(second)), i64::from(first.max(second)));
+        let ranges = [
+            (3, low - 1),
+            (low + 1, high - 1),
+            (high + 1, i64::from(libc::c_uint::MAX)),
+        ];
+        let closed = ranges.iter().all(|&(start, end)| {
+            start > end
+                || libc::syscall(
+                    libc::SYS_close_range,
+                    start as libc::c_uint,
+                    end as libc::c_uint,
+                    0,
+                ) == 0
+        });
+        if closed {
+            return;
+        }
+    }
+    for fd in 3..max_fd {
+        if fd != first && fd != second {
+            libc::close(fd);
+        }
+    }
+}
+
+/// Body of the watchdog process. Never returns.
+unsafe fn watchdog_main(
+    mountpoint: &CStr,
+    socket: libc::c_int,
+    fuse_device: libc::c_int,
+    max_fd: libc::c_int,
+) -> ! {
+    // Detach from the terminal's process group, so Ctrl-C only stops the filesystem process
+    libc::setsid();
+    // Don't keep any other descriptors (like other mounts' watchdog sockets) alive
+    close_other_fds(socket, fuse_device, max_fd);
+    // Nothing is ever sent, so this returns once the other end is closed
+    let mut buf = [0u8; 1];
+    loop {
+        let res = libc::read(socket, buf.as_mut_ptr() as *mut libc::c_void, 1);
+        if res > 0 || (res == -1 && Error::last_os_error().kind() == ErrorKind::Interrupted) {
+            continue;
+        }
+        break;
+    }
+    // The connection reports POLLERR once it was unmounted
+    let mut poll_fd = libc::pollfd {
+        fd: fuse_device,
+        events: 0,
+        revents: 0,
+    };
+    let unmounted = libc::poll(&mut poll_fd, 1, 0) == 1 && (poll_fd.revents & libc::POLLERR) != 0;
+    if !unmounted {
+        #[cfg(target_os = "linux")]
+        libc::umount2(mountpoint.as_ptr(), libc::MNT_DETACH);
+        #[cfg(target_os = "macos")]
+        libc::unmount(mountpoint.as_ptr(), libc::MNT_FORCE);
+    }
+    libc::_exit(0)
+}
+
 fn fuse_unmount_pure(mountpoint: &CStr) {
     #[cfg(target_os = "linux")]
     unsafe {
@@ -142,96 +382,23 @@ fn detect_fusermount_bin() -> String {
 }
 
 fn receive_fusermount_message(socket: &UnixStream) -> Result<File, Error> {
-    let mut io_vec_buf = [0u8];
-    let mut io_vec = libc::iovec {
-        iov_base: (&mut io_vec_buf).as_mut_ptr() as *mut libc::c_void,
-        iov_len: io_vec_buf.len(),
-    };
-    let cmsg_buffer_len = unsafe { libc::CMSG_SPACE(mem::size_of::<c_int>() as libc::c_uint) };
-    let mut cmsg_buffer = vec![0u8; cmsg_buffer_len as usize];
-    let mut message: libc::msghdr;
-    #[cfg(all(target_os = "linux", not(target_env = "musl")))]
-    {
-        message = libc::msghdr {
-            msg_name: ptr::null_mut(),
-            msg_namelen: 0,
-            msg_iov: &mut io_vec,
-            msg_iovlen: 1,
-            msg_control: (&mut cmsg_buffer).as_mut_ptr() as *mut libc::c_void,
-            msg_controllen: cmsg_buffer.len(),
-            msg_flags: 0,
-        };
-    }
-    #[cfg(all(target_os = "linux", target_env = "musl"))]
-    {
-        message = unsafe { std::mem::MaybeUninit::zeroed().assume_init() };
-        message.msg_name = ptr::null_mut();
-        message.msg_namelen = 0;
-        message.msg_iov = &mut io_vec;
-        message.msg_iovlen = 1;
-        message.msg_control = (&mut cmsg_buffer).as_mut_ptr() as *mut libc::c_void;
-        message.msg_controllen = cmsg_buffer.len() as u32;
-        message.msg_flags = 0;
-    }
-    #[cfg(target_os = "macos")]
-    {
-        message = libc::msghdr {
-            msg_name: ptr::null_mut(),
-            msg_namelen: 0,
-            msg_iov: &mut io_vec,
-            msg_iovlen: 1,
-            msg_control: (&mut cmsg_buffer).as_mut_ptr() as *mut libc::c_void,
-            msg_controllen: cmsg_buffer.len() as u32,
-            msg_flags: 0,
-        };
-    }
-
-    let mut result;
-    loop {
-        unsafe {
-            result = libc::recvmsg(socket.as_raw_fd(), &mut message, 0);
-        }
-        if result != -1 {
-            break;
-        }
-        let err = Error::last_os_error();
-        if err.kind() != ErrorKind::Interrupted {
-            return Err(err);
-        }
-    }
-    if result == 0 {
-        return Err(Error::new(
-            ErrorKind::UnexpectedEof,
-            "Unexpected EOF reading from fusermount",
-        ));
-    }
-
-    unsafe {
-        let control_msg = libc::CMSG_FIRSTHDR(&message);
-        if (*control_msg).cmsg_type != libc::SCM_RIGHTS {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                format!(
-                    "Unknown control message from fusermount: {}",
-                    (*control_msg).cmsg_type
-                ),
-            ));
-        }
-        let fd_data = libc::CMSG_DATA(control_msg);
+    // fusermount sends a single byte along with the fd
+    let mut buf = [0u8];
+    recv_fd(socket, &mut buf).map(|(file, _)| file)
+}
 
-        let fd = *(fd_data as *const c_int);
-        if fd < 0 {
-            Err(ErrorKind::InvalidData.into())
-        } else {
-            Ok(File::from_raw_fd(fd))
-        }
-    }
+/// Options that fusermount determines by itself, and refuses to accept
+fn reserved_by_fusermount(option: &MountOption) -> bool {
+    matches!(
+        option,
+        MountOption::RootMode(_) | MountOption::UserId(_) | MountOption::GroupId(_)
+    ) || option_group(option) == MountOptionGroup::Fuser
 }
 
 fn fuse_mount_fusermount(
     mountpoint: &OsStr,
     options: &[MountOption],
-) -> Result<(File, Option<UnixStream>), Error> {
+) -> Result<(File, Option<UnixStream>), MountError> {
     let (child_socket, receive_socket) = UnixStream::pair()?;
 
     unsafe {
@@ -242,7 +409,11 @@ fn fuse_mount_fusermount(
     builder.stdout(Stdio::piped()).stderr(Stdio::piped());
     if !options.is_empty() {
         builder.arg("-o");
-        let options_strs: Vec<String> = options.iter().map(option_to_string).collect();
+        let options_strs: Vec<String> = options
+            .iter()
+            .filter(|x| !reserved_by_fusermount(x))
+            .map(option_to_string)
+            .collect();
         builder.arg(options_strs.join(","));
     }
     builder
@@ -250,7 +421,13 @@ fn fuse_mount_fusermount(
         .arg(mountpoint)
         .env(FUSERMOUNT_COMM_ENV, child_socket.as_raw_fd().to_string());
 
-    let fusermount_child = builder.spawn()?;
+    let fusermount_child = builder.spawn().map_err(|err| {
+        if err.kind() == ErrorKind::NotFound {
+            MountError::FusermountNotFound
+        } else {
+            MountError::Io(err)
+        }
+    })?;
 
     drop(child_socket); // close socket in parent
 
@@ -261,11 +438,10 @@ fn fuse_mount_fusermount(
             drop(receive_socket);
             let output = fusermount_child.wait_with_output().unwrap();
             let stderr_string = String::from_utf8_lossy(&output.stderr).to_string();
-            return if stderr_string.contains("only allowed if 'user_allow_other' is set") {
-                Err(io::Error::new(ErrorKind::PermissionDenied, stderr_string))
-            } else {
-                Err(io::Error::new(ErrorKind::Other, stderr_string))
-            };
+            return Err(MountError::from_fusermount_stderr(
+                stderr_string,
+                Path::new(mountpoint),
+            ));
         }
     };
     let mut receive_socket = Some(receive_socket);
@@ -312,14 +488,8 @@ fn fuse_mount_fusermount(
 }
 
 // If returned option is none. Then fusermount binary should be tried
-fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<File>, Error> {
+pub(crate) fn open_fuse_device() -> Result<File, MountError> {
     let fuse_device_name = "/dev/fuse";
-
-    let mountpoint_mode = File::open(mountpoint)?.metadata()?.permissions().mode();
-
-    // Auto unmount requests must be sent to fusermount binary
-    assert!(!options.contains(&MountOption::AutoUnmount));
-
     let file = match OpenOptions::new()
         .read(true)
         .write(true)
@@ -330,7 +500,7 @@ fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<
             if error.kind() == ErrorKind::NotFound {
                 error!("{} not found. Try 'modprobe fuse'", fuse_device_name);
             }
-            return Err(error);
+            return Err(MountError::DeviceUnavailable(error));
         }
     };
     assert!(
@@ -338,17 +508,90 @@ fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<
         "Conflict with stdin/stdout/stderr. fd={}",
         file.as_raw_fd()
     );
+    Ok(file)
+}
 
-    let mut mount_options = format!(
-        "fd={},rootmode={:o},user_id={},group_id={}",
-        file.as_raw_fd(),
-        mountpoint_mode,
-        users::get_current_uid(),
-        users::get_current_gid()
-    );
+/// Default name is "/dev/fuse", then use the subtype, and lastly prefer the name
+pub(crate) fn mount_source(options: &[MountOption]) -> &str {
+    let mut source = "/dev/fuse";
+    if let Some(MountOption::Subtype(subtype)) = options
+        .iter()
+        .find(|x| matches!(**x, MountOption::Subtype(_)))
+    {
+        source = subtype;
+    }
+    if let Some(MountOption::FSName(name)) = options
+        .iter()
+        .find(|x| matches!(**x, MountOption::FSName(_)))
+    {
+        source = name;
+    }
+    source
+}
+
+/// Filesystem type to mount: "fuseblk" if the filesystem is backed by a block device
+pub(crate) fn mount_fs_type(options: &[MountOption]) -> &'static str {
+    if options.contains(&MountOption::BlkDev) {
+        "fuseblk"
+    } else {
+        "fuse"
+    }
+}
+
+/// The root mode, user id and group id which the kernel requires, unless they were given
+pub(crate) fn default_kernel_options(
+    mountpoint_mode: u32,
+    options: &[MountOption],
+) -> Vec<MountOption> {
+    let mut defaults = vec![];
+    if !options
+        .iter()
+        .any(|x| matches!(x, MountOption::RootMode(_)))
+    {
+        defaults.push(MountOption::RootMode(mountpoint_mode));
+    }
+    if !options.iter().any(|x| matches!(x, MountOption::UserId(_))) {
+        defaults.push(MountOption::UserId(users::get_current_uid()));
+    }
+    if !options.iter().any(|x| matches!(x, MountOption::GroupId(_))) {
+        defaults.push(MountOption::GroupId(users::get_current_gid()));
+    }
+    defaults
+}
+
+// If returned option is none. Then fusermount binary should be tried
+fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<File>, MountError> {
+    let mountpoint_mode = File::open(mountpoint)
+        .and_then(|file| file.metadata())
+        .map_err(|err| MountError::from_io_at(err, Path::new(mountpoint)))?
+        .permissions()
+        .mode();
+
+    let file = open_fuse_device()?;
+
+    #[cfg(target_os = "linux")]
+    match FsContext::open(mount_fs_type(options)) {
+        Ok(context) => {
+            context.configure_fuse(&file, mountpoint_mode, options)?;
+            let mount = context.mount(options)?;
+            return match move_mount(mount.as_fd(), Path::new(mountpoint)) {
+                Ok(()) => Ok(Some(file)),
+                // Retry with fusermount
+                Err(MountError::PermissionDenied(_)) => Ok(None),
+                Err(err) => Err(err),
+            };
+        }
+        // Kernels before 5.2 don't have the new mount API
+        Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {}
+        // Retry with fusermount
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
 
-    for option in options
+    let mut mount_options = format!("fd={}", file.as_raw_fd());
+    for option in default_kernel_options(mountpoint_mode, options)
         .iter()
+        .chain(options)
         .filter(|x| option_group(*x) == MountOptionGroup::KernelOption)
     {
         mount_options.push(',');
@@ -385,29 +628,14 @@ fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<
         flags |= option_to_flag(flag);
     }
 
-    // Default name is "/dev/fuse", then use the subtype, and lastly prefer the name
-    let mut source = fuse_device_name;
-    if let Some(MountOption::Subtype(subtype)) = options
-        .iter()
-        .find(|x| matches!(**x, MountOption::Subtype(_)))
-    {
-        source = subtype;
-    }
-    if let Some(MountOption::FSName(name)) = options
-        .iter()
-        .find(|x| matches!(**x, MountOption::FSName(_)))
-    {
-        source = name;
-    }
-
-    let c_source = CString::new(source).unwrap();
+    let c_source = CString::new(mount_source(options)).unwrap();
     let c_mountpoint = CString::new(mountpoint.as_bytes()).unwrap();
 
     let result = unsafe {
         #[cfg(target_os = "linux")]
         {
             let c_options = CString::new(mount_options).unwrap();
-            let c_type = CString::new("fuse").unwrap();
+            let c_type = CString::new(mount_fs_type(options)).unwrap();
             libc::mount(
                 c_source.as_ptr(),
                 c_mountpoint.as_ptr(),
@@ -429,14 +657,16 @@ fn fuse_mount_sys(mountpoint: &OsStr, options: &[MountOption]) -> Result<Option<
     };
     if result == -1 {
         let err = Error::last_os_error();
-        if err.kind() == ErrorKind::PermissionDenied {
-            return Ok(None); // Retry with fusermount
-        } else {
-            return Err(Error::new(
+        return match err.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) => Ok(None), // Retry with fusermount
+            Some(libc::ENOENT) | Some(libc::ENOTDIR) | Some(libc::ENOTCONN) => {
+                Err(MountError::from_io_at(err, Path::new(mountpoint)))
+            }
+            _ => Err(MountError::Io(Error::new(
                 err.kind(),
                 format!("Error calling mount() at {:?}: {}", mountpoint, err),
-            ));
-        }
+            ))),
+        };
     }
 
     Ok(Some(file))
@@ -447,6 +677,8 @@ pub enum MountOptionGroup {
     KernelOption,
     KernelFlag,
     Fusermount,
+    /// Handled by fuser before mounting
+    Fuser,
 }
 
 pub fn option_group(option: &MountOption) -> MountOptionGroup {
@@ -454,6 +686,16 @@ pub fn option_group(option: &MountOption) -> MountOptionGroup {
         MountOption::FSName(_) => MountOptionGroup::Fusermount,
         MountOption::Subtype(_) => MountOptionGroup::Fusermount,
         MountOption::CUSTOM(_) => MountOptionGroup::KernelOption,
+        MountOption::MaxRead(_) => MountOptionGroup::KernelOption,
+        MountOption::BlkSize(_) => MountOptionGroup::KernelOption,
+        MountOption::RootMode(_) => MountOptionGroup::KernelOption,
+        MountOption::UserId(_) => MountOptionGroup::KernelOption,
+        MountOption::GroupId(_) => MountOptionGroup::KernelOption,
+        MountOption::Context(_) => MountOptionGroup::KernelOption,
+        MountOption::FSContext(_) => MountOptionGroup::KernelOption,
+        MountOption::NonEmpty => MountOptionGroup::Fusermount,
+        MountOption::BlkDev => MountOptionGroup::Fusermount,
+        MountOption::RecoverStale => MountOptionGroup::Fuser,
         MountOption::AutoUnmount => MountOptionGroup::Fusermount,
         MountOption::AllowOther => MountOptionGroup::KernelOption,
         MountOption::Dev => MountOptionGroup::KernelFlag,
@@ -466,6 +708,9 @@ pub fn option_group(option: &MountOption) -> MountOptionGroup {
         MountOption::NoExec => MountOptionGroup::KernelFlag,
         MountOption::Atime => MountOptionGroup::KernelFlag,
         MountOption::NoAtime => MountOptionGroup::KernelFlag,
+        MountOption::RelAtime => MountOptionGroup::KernelFlag,
+        MountOption::StrictAtime => MountOptionGroup::KernelFlag,
+        MountOption::LazyTime => MountOptionGroup::KernelFlag,
         MountOption::DirSync => MountOptionGroup::KernelFlag,
         MountOption::Sync => MountOptionGroup::KernelFlag,
         MountOption::Async => MountOptionGroup::KernelFlag,
@@ -487,6 +732,9 @@ pub fn option_to_flag(option: &MountOption) -> libc::c_ulong {
         MountOption::NoExec => libc::MS_NOEXEC,
         MountOption::Atime => 0,
         MountOption::NoAtime => libc::MS_NOATIME,
+        MountOption::RelAtime => libc::MS_RELATIME,
+        MountOption::StrictAtime => libc::MS_STRICTATIME,
+        MountOption::LazyTime => libc::MS_LAZYTIME,
         MountOption::Async => 0,
         MountOption::Sync => libc::MS_SYNCHRONOUS,
         MountOption::DirSync => libc::MS_DIRSYNC,
@@ -507,8 +755,62 @@ pub fn option_to_flag(option: &MountOption) -> libc::c_int {
         MountOption::NoExec => libc::MNT_NOEXEC,
         MountOption::Atime => 0,
         MountOption::NoAtime => libc::MNT_NOATIME,
+        // Not supported by macOS
+        MountOption::RelAtime => 0,
+        MountOption::StrictAtime => 0,
+        MountOption::LazyTime => 0,
         MountOption::Async => 0,
         MountOption::Sync => libc::MNT_SYNCHRONOUS,
         _ => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem::ManuallyDrop;
+
+    fn is_listed(mountpoint: &Path) -> bool {
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap();
+        mounts.contains(&*mountpoint.to_string_lossy())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn watchdog_unmounts() {
+        if unsafe { libc::geteuid() } != 0 {
+            // The watchdog is only used for mounts without fusermount
+            return;
+        }
+        // Leak the directory on failure, it may still be a mountpoint
+        let tmp = ManuallyDrop::new(tempfile::tempdir().unwrap());
+        let mountpoint = tmp.path().canonicalize().unwrap();
+        let (file, mount) = Mount::new(&mountpoint, &[]).unwrap();
+        assert!(is_listed(&mountpoint));
+        let watchdog = Watchdog::spawn(&mount.mountpoint, &file).unwrap();
+        // Dropping the watchdog looks the same to the helper as this process exiting
+        drop(watchdog);
+        assert!(!is_listed(&mountpoint));
+        drop(mount);
+        ManuallyDrop::into_inner(tmp);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn auto_unmount_without_fusermount() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let tmp = ManuallyDrop::new(tempfile::tempdir().unwrap());
+        let mountpoint = tmp.path().canonicalize().unwrap();
+        let (_file, mount) = Mount::new(&mountpoint, &[MountOption::AutoUnmount]).unwrap();
+        assert!(matches!(
+            mount.auto_unmount,
+            Some(AutoUnmount::Watchdog { .. })
+        ));
+        assert!(is_listed(&mountpoint));
+        drop(mount);
+        assert!(!is_listed(&mountpoint));
+        ManuallyDrop::into_inner(tmp);
+    }
+}
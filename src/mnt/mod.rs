@@ -11,15 +11,32 @@ mod fuse3;
 #[cfg(feature = "libfuse3")]
 mod fuse3_sys;
 
+mod error;
 #[cfg(not(feature = "libfuse"))]
 mod fuse_pure;
+#[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+mod mount_api;
 pub mod mount_options;
+#[cfg(target_os = "linux")]
+pub(crate) mod mountinfo;
+mod stale;
+#[cfg(target_os = "linux")]
+mod userns;
+
+pub use error::MountError;
+#[cfg(all(target_os = "linux", not(feature = "libfuse")))]
+pub use mount_api::DetachedMount;
+pub(crate) use stale::recover_stale_mount;
+#[cfg(target_os = "linux")]
+pub use userns::{enter_user_namespace, UserNamespace};
 
 #[cfg(any(feature = "libfuse", test))]
 use fuse2_sys::fuse_args;
 #[cfg(any(test, not(feature = "libfuse")))]
 use std::fs::File;
 use std::io;
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
 
 #[cfg(any(feature = "libfuse", test))]
 use mount_options::MountOption;
@@ -32,7 +49,7 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[MountOption], f: F)
     use std::ffi::CString;
 
     let mut args = vec![CString::new("rust-fuse").unwrap()];
-    for x in options {
+    for x in options.iter().filter(|x| **x != MountOption::RecoverStale) {
         args.extend_from_slice(&[
             CString::new("-o").unwrap(),
             CString::new(option_to_string(x)).unwrap(),
@@ -46,16 +63,32 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[MountOption], f: F)
     })
 }
 
+/// A mount that was released without unmounting it, so that another process can take it over
+/// with `Mount::take_over` and unmount it eventually
+#[derive(Debug)]
+pub(crate) struct ReleasedMount {
+    /// Where the filesystem is mounted, as it was passed to the kernel
+    pub(crate) mountpoint: PathBuf,
+    /// Socket that keeps the auto-unmount of fusermount or of a watchdog process from triggering
+    /// while it is open
+    pub(crate) auto_unmount: Option<(AutoUnmountKind, OwnedFd)>,
+}
+
+/// Who unmounts a released mount once the process holding its auto-unmount socket exits
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum AutoUnmountKind {
+    Fusermount,
+    Watchdog,
+}
+
 #[cfg(feature = "libfuse2")]
 pub use fuse2::Mount;
 #[cfg(feature = "libfuse3")]
 pub use fuse3::Mount;
 #[cfg(not(feature = "libfuse"))]
 pub use fuse_pure::Mount;
-#[cfg(not(feature = "libfuse3"))]
 use std::ffi::CStr;
 
-#[cfg(not(feature = "libfuse3"))]
 #[inline]
 fn libc_umount(mnt: &CStr) -> io::Result<()> {
     #[cfg(any(
@@ -117,6 +150,31 @@ fn is_mounted(fuse_device: &File) -> bool {
     }
 }
 
+/// Parses libfuse's `/dev/fd/N` mountpoint convention, which indicates that the caller already
+/// mounted the filesystem and passed the open FUSE device as file descriptor N.
+pub(crate) fn fd_mountpoint(mountpoint: &Path) -> Option<RawFd> {
+    let fd: RawFd = mountpoint
+        .to_str()?
+        .strip_prefix("/dev/fd/")?
+        .parse()
+        .ok()?;
+    // Only accept descriptors that are actually open
+    if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return None;
+    }
+    Some(fd)
+}
+
+/// Make a mountpoint absolute, the way it appears in the mount table. The mountpoint itself is not
+/// resolved, since accessing it may block or fail if it is a FUSE mount.
+pub(crate) fn resolve_mountpoint(mountpoint: &Path) -> io::Result<PathBuf> {
+    let mountpoint = std::env::current_dir()?.join(mountpoint);
+    match (mountpoint.parent(), mountpoint.file_name()) {
+        (Some(parent), Some(name)) => Ok(parent.canonicalize()?.join(name)),
+        _ => Ok(mountpoint),
+    }
+}
+
 /// Ensures that an os error is never 0/Success
 fn ensure_last_os_error() -> io::Error {
     let err = io::Error::last_os_error();
@@ -129,6 +187,7 @@ fn ensure_last_os_error() -> io::Error {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::os::unix::io::AsRawFd;
     use std::{ffi::CStr, mem::ManuallyDrop};
 
     #[test]
@@ -150,6 +209,21 @@ mod test {
             },
         );
     }
+    #[test]
+    fn parse_fd_mountpoint() {
+        let file = File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+        assert_eq!(
+            fd_mountpoint(Path::new(&format!("/dev/fd/{}", fd))),
+            Some(fd)
+        );
+        assert_eq!(fd_mountpoint(Path::new("/dev/fd/abc")), None);
+        assert_eq!(fd_mountpoint(Path::new("/dev/fd/-1")), None);
+        assert_eq!(fd_mountpoint(Path::new("/mnt/fuse")), None);
+        drop(file);
+        assert_eq!(fd_mountpoint(Path::new(&format!("/dev/fd/{}", fd))), None);
+    }
+
     fn cmd_mount() -> String {
         std::str::from_utf8(
             std::process::Command::new("sh")
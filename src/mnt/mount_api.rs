@@ -0,0 +1,334 @@
+//! Mounting with the Linux mount API (`fsopen`, `fsconfig`, `fsmount` and `move_mount`)
+//!
+//! Compared to `mount(2)`, every option is passed and checked separately, the kernel explains
+//! why it rejected a mount in a log that can be read from the filesystem context, and a mount
+//! can be created detached, to be attached later (possibly in another mount namespace).
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, c_uint};
+use log::warn;
+
+use super::fuse_pure::{
+    default_kernel_options, mount_fs_type, mount_source, open_fuse_device, option_group,
+    MountOptionGroup,
+};
+use super::mount_options::{check_block_device, option_to_string, MountOption};
+use super::MountError;
+
+const FSOPEN_CLOEXEC: c_uint = 0x1;
+const FSCONFIG_SET_FLAG: c_uint = 0;
+const FSCONFIG_SET_STRING: c_uint = 1;
+const FSCONFIG_CMD_CREATE: c_uint = 6;
+const FSMOUNT_CLOEXEC: c_uint = 0x1;
+const MOUNT_ATTR_RDONLY: c_uint = 0x1;
+const MOUNT_ATTR_NOSUID: c_uint = 0x2;
+const MOUNT_ATTR_NODEV: c_uint = 0x4;
+const MOUNT_ATTR_NOEXEC: c_uint = 0x8;
+const MOUNT_ATTR_RELATIME: c_uint = 0x0;
+const MOUNT_ATTR_NOATIME: c_uint = 0x10;
+const MOUNT_ATTR_STRICTATIME: c_uint = 0x20;
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x4;
+
+/// A filesystem context created by `fsopen`
+#[derive(Debug)]
+pub(crate) struct FsContext(OwnedFd);
+
+impl FsContext {
+    /// Create a new filesystem context. Fails with `ENOSYS` on kernels before 5.2.
+    pub(crate) fn open(fstype: &str) -> io::Result<FsContext> {
+        let fstype = CString::new(fstype).unwrap();
+        let fd = unsafe { libc::syscall(libc::SYS_fsopen, fstype.as_ptr(), FSOPEN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FsContext(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+    }
+
+    /// Configure the filesystem context for a FUSE filesystem served on `fuse_device`
+    pub(crate) fn configure_fuse(
+        &self,
+        fuse_device: &File,
+        rootmode: u32,
+        options: &[MountOption],
+    ) -> Result<(), MountError> {
+        self.set_string("source", mount_source(options))?;
+        self.set_string("fd", &fuse_device.as_raw_fd().to_string())?;
+        for option in default_kernel_options(rootmode, options)
+            .iter()
+            .chain(options)
+        {
+            match option_group(option) {
+                MountOptionGroup::KernelOption => {
+                    let option = option_to_string(option);
+                    match option.split_once('=') {
+                        Some((key, value)) => self.set_string(key, value)?,
+                        None => self.set_flag(&option)?,
+                    }
+                }
+                // These are superblock flags. The other flags are per-mount attributes.
+                MountOptionGroup::KernelFlag => match option {
+                    MountOption::RO => self.set_flag("ro")?,
+                    MountOption::Sync => self.set_flag("sync")?,
+                    MountOption::DirSync => self.set_flag("dirsync")?,
+                    MountOption::LazyTime => self.set_flag("lazytime")?,
+                    _ => {}
+                },
+                MountOptionGroup::Fusermount | MountOptionGroup::Fuser => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn set_flag(&self, key: &str) -> Result<(), MountError> {
+        let c_key = CString::new(key).unwrap();
+        self.fsconfig(FSCONFIG_SET_FLAG, c_key.as_ptr(), std::ptr::null(), key)
+    }
+
+    fn set_string(&self, key: &str, value: &str) -> Result<(), MountError> {
+        let c_key = CString::new(key).unwrap();
+        let c_value = CString::new(value).unwrap();
+        self.fsconfig(FSCONFIG_SET_STRING, c_key.as_ptr(), c_value.as_ptr(), key)
+    }
+
+    fn fsconfig(
+        &self,
+        cmd: c_uint,
+        key: *const libc::c_char,
+        value: *const libc::c_char,
+        name: &str,
+    ) -> Result<(), MountError> {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                self.0.as_raw_fd(),
+                cmd,
+                key,
+                value,
+                0 as c_int,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return Err(self.error(err, &format!("Setting mount option {}", name)));
+        }
+        Ok(())
+    }
+
+    /// Create the superblock and return a detached mount of it
+    pub(crate) fn mount(&self, options: &[MountOption]) -> Result<OwnedFd, MountError> {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_fsconfig,
+                self.0.as_raw_fd(),
+                FSCONFIG_CMD_CREATE,
+                std::ptr::null::<libc::c_char>(),
+                std::ptr::null::<libc::c_char>(),
+                0 as c_int,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            return Err(self.error(err, "Creating FUSE filesystem"));
+        }
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_fsmount,
+                self.0.as_raw_fd(),
+                FSMOUNT_CLOEXEC,
+                mount_attributes(options),
+            )
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return Err(self.error(err, "Mounting FUSE filesystem"));
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+
+    /// Build an error, including the messages the kernel logged to the filesystem context
+    fn error(&self, err: io::Error, action: &str) -> MountError {
+        let log = self.read_log();
+        for message in log.iter() {
+            warn!("{}: {}", action, message);
+        }
+        MountError::Kernel { error: err, log }
+    }
+
+    fn read_log(&self) -> Vec<String> {
+        let mut log = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.0.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            // Fails with ENODATA once all messages were read
+            if len <= 0 {
+                break;
+            }
+            let message = String::from_utf8_lossy(&buf[..len as usize]);
+            // Messages are prefixed with their severity: "e ", "w " or "i "
+            let message = match message.get(1..2) {
+                Some(" ") => &message[2..],
+                _ => &message,
+            };
+            log.push(message.trim_end().to_owned());
+        }
+        log
+    }
+}
+
+/// Attach a detached mount at `mountpoint`
+pub(crate) fn move_mount(mount: BorrowedFd<'_>, mountpoint: &Path) -> Result<(), MountError> {
+    let empty = CStr::from_bytes_with_nul(b"\0").unwrap();
+    let c_mountpoint = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            mount.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            c_mountpoint.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    };
+    if res < 0 {
+        return Err(MountError::from_io_at(
+            io::Error::last_os_error(),
+            mountpoint,
+        ));
+    }
+    Ok(())
+}
+
+/// Per-mount attributes. Like with mount(2), default to nodev and nosuid.
+fn mount_attributes(options: &[MountOption]) -> c_uint {
+    let mut attributes = 0;
+    if !options.contains(&MountOption::Dev) {
+        attributes |= MOUNT_ATTR_NODEV;
+    }
+    if !options.contains(&MountOption::Suid) {
+        attributes |= MOUNT_ATTR_NOSUID;
+    }
+    for option in options {
+        attributes |= match option {
+            MountOption::NoDev => MOUNT_ATTR_NODEV,
+            MountOption::NoSuid => MOUNT_ATTR_NOSUID,
+            MountOption::RO => MOUNT_ATTR_RDONLY,
+            MountOption::NoExec => MOUNT_ATTR_NOEXEC,
+            MountOption::RelAtime => MOUNT_ATTR_RELATIME,
+            MountOption::NoAtime => MOUNT_ATTR_NOATIME,
+            MountOption::StrictAtime => MOUNT_ATTR_STRICTATIME,
+            _ => 0,
+        };
+    }
+    attributes
+}
+
+/// A FUSE filesystem that was mounted with the Linux mount API, but is not attached to any
+/// mountpoint yet.
+///
+/// The mount can be attached with `attach`, or its file descriptor can be passed to another
+/// process (e.g. inside a container's mount namespace) which attaches it with `move_mount(2)`.
+/// In either case, the filesystem is served by a `Session` created with `Session::from_fd` on
+/// the FUSE device.
+#[derive(Debug)]
+pub struct DetachedMount {
+    fuse_device: File,
+    mount: OwnedFd,
+}
+
+impl DetachedMount {
+    /// Create a detached mount of a new FUSE filesystem. Unless `RootMode` is given, the root
+    /// of the filesystem is a directory.
+    pub fn new(options: &[MountOption]) -> Result<DetachedMount, MountError> {
+        check_block_device(options)?;
+        let fuse_device = open_fuse_device()?;
+        let context = FsContext::open(mount_fs_type(options))?;
+        context.configure_fuse(&fuse_device, libc::S_IFDIR, options)?;
+        let mount = context.mount(options)?;
+        Ok(DetachedMount { fuse_device, mount })
+    }
+
+    /// File descriptor of the mount, as returned by `fsmount(2)`
+    pub fn mount_fd(&self) -> BorrowedFd<'_> {
+        self.mount.as_fd()
+    }
+
+    /// Attach the mount at the given mountpoint
+    pub fn attach(&self, mountpoint: &Path) -> Result<(), MountError> {
+        move_mount(self.mount.as_fd(), mountpoint)
+    }
+
+    /// Split into the FUSE device, which is used to serve the filesystem, and the mount fd
+    pub fn into_parts(self) -> (OwnedFd, OwnedFd) {
+        (self.fuse_device.into(), self.mount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CString;
+    use std::mem::ManuallyDrop;
+
+    #[test]
+    fn attach_detached_mount() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let options = [
+            MountOption::FSName("detached".to_string()),
+            MountOption::MaxRead(65536),
+        ];
+        let mount = match DetachedMount::new(&options) {
+            Ok(mount) => mount,
+            // Kernel without the new mount API
+            Err(MountError::Io(err)) if err.raw_os_error() == Some(libc::ENOSYS) => return,
+            Err(err) => panic!("{}", err),
+        };
+        // Leak the directory on failure, it may still be a mountpoint
+        let tmp = ManuallyDrop::new(tempfile::tempdir().unwrap());
+        let mountpoint = tmp.path().canonicalize().unwrap();
+        mount.attach(&mountpoint).unwrap();
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap();
+        let line = mounts
+            .lines()
+            .find(|x| x.starts_with(&format!("detached {} fuse", mountpoint.display())))
+            .unwrap();
+        assert!(line.contains("max_read=65536"));
+
+        let c_mountpoint = CString::new(mountpoint.as_os_str().as_bytes()).unwrap();
+        assert_eq!(
+            unsafe { libc::umount2(c_mountpoint.as_ptr(), libc::MNT_DETACH) },
+            0
+        );
+        ManuallyDrop::into_inner(tmp);
+    }
+
+    #[test]
+    fn kernel_rejects_unknown_option() {
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        let context = match FsContext::open("fuse") {
+            Ok(context) => context,
+            Err(_) => return,
+        };
+        match context.set_flag("no_such_option") {
+            Err(MountError::Kernel { error, .. }) => {
+                assert_eq!(error.raw_os_error(), Some(libc::EINVAL))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -1,10 +1,16 @@
 use std::io;
 use std::io::ErrorKind;
-use std::{collections::HashSet, ffi::OsStr};
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{collections::HashSet, ffi::OsStr, fmt, fs};
+
+use super::MountError;
 
 /// Mount options accepted by the FUSE filesystem type
 /// See 'man mount.fuse' for details
-// TODO: add all options that 'man mount.fuse' documents and libfuse supports
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 pub enum MountOption {
     /// Set the name of the source in mtab
@@ -14,6 +20,24 @@ pub enum MountOption {
     /// Allows passing an option which is not otherwise supported in these enums
     #[allow(clippy::upper_case_acronyms)]
     CUSTOM(String),
+    /// Maximum size of read requests in bytes
+    MaxRead(u32),
+    /// Block size of the filesystem. Only valid together with `BlkDev`
+    BlkSize(u32),
+    /// File mode of the root directory, including the file type bits. Defaults to the mode of
+    /// the mountpoint. Ignored when mounting through `fusermount`, which always uses the mode of
+    /// the mountpoint.
+    RootMode(u32),
+    /// Numeric user id of the mount owner. Defaults to the current user. Ignored when mounting
+    /// through `fusermount`.
+    UserId(u32),
+    /// Numeric group id of the mount owner. Defaults to the current group. Ignored when mounting
+    /// through `fusermount`.
+    GroupId(u32),
+    /// SELinux context of all files in the filesystem
+    Context(String),
+    /// SELinux context of the filesystem itself
+    FSContext(String),
 
     /* Parameterless options */
     /// Allow all users to access files on this filesystem. By default access is restricted to the
@@ -24,9 +48,25 @@ pub enum MountOption {
     /// Automatically unmount when the mounting process exits
     ///
     /// `AutoUnmount` requires `AllowOther` or `AllowRoot`. If `AutoUnmount` is set and neither `Allow...` is set, the FUSE configuration must permit `allow_other`, otherwise mounting will fail.
+    ///
+    /// Without libfuse, a process that is allowed to mount by itself (e.g. root) forks a small
+    /// helper process to unmount, instead of relying on the `fusermount` binary.
     AutoUnmount,
     /// Enable permission checking in the kernel
+    ///
+    /// With `AllowRoot`, the kernel checks permissions for root as well, so root is still subject
+    /// to the file modes returned by the filesystem.
     DefaultPermissions,
+    /// Allow mounting over a non-empty directory. Only understood by `fusermount` from libfuse 2;
+    /// mounting without `fusermount` always allows it.
+    NonEmpty,
+    /// Mount a filesystem that is backed by a block device (`fuseblk`). The device is given with
+    /// `FSName`, see `MountOptions::block_device`. The kernel opens the device itself, so the
+    /// mounting user must have access to it. Only such filesystems receive `bmap` requests.
+    BlkDev,
+    /// If the mountpoint is a FUSE mount whose filesystem process has died, unmount it lazily
+    /// before mounting. Handled by fuser itself; it is never passed to the kernel or `fusermount`.
+    RecoverStale,
 
     /* Flags */
     /// Enable special character and block devices
@@ -49,6 +89,12 @@ pub enum MountOption {
     Atime,
     /// Don't update inode access time
     NoAtime,
+    /// Update inode access time only if it is older than the modification or change time
+    RelAtime,
+    /// Always update inode access time
+    StrictAtime,
+    /// Only update inode times in memory, and write them back lazily
+    LazyTime,
     /// All modifications to directories will be done synchronously
     DirSync,
     /// All I/O will be done synchronously
@@ -59,57 +105,410 @@ pub enum MountOption {
     to libfuse, and not part of the kernel ABI */
 }
 
-impl MountOption {
-    pub(crate) fn from_str(s: &str) -> MountOption {
-        match s {
-            "auto_unmount" => MountOption::AutoUnmount,
-            "allow_other" => MountOption::AllowOther,
-            "allow_root" => MountOption::AllowRoot,
-            "default_permissions" => MountOption::DefaultPermissions,
-            "dev" => MountOption::Dev,
-            "nodev" => MountOption::NoDev,
-            "suid" => MountOption::Suid,
-            "nosuid" => MountOption::NoSuid,
-            "ro" => MountOption::RO,
-            "rw" => MountOption::RW,
-            "exec" => MountOption::Exec,
-            "noexec" => MountOption::NoExec,
-            "atime" => MountOption::Atime,
-            "noatime" => MountOption::NoAtime,
-            "dirsync" => MountOption::DirSync,
-            "sync" => MountOption::Sync,
-            "async" => MountOption::Async,
-            x if x.starts_with("fsname=") => MountOption::FSName(x[7..].into()),
-            x if x.starts_with("subtype=") => MountOption::Subtype(x[8..].into()),
-            x => MountOption::CUSTOM(x.into()),
+impl FromStr for MountOption {
+    type Err = ParseMountOptionError;
+
+    /// Parse a single option, such as `"ro"` or `"max_read=131072"`. Unknown options, such as
+    /// typos, and known options with an invalid value, such as `"max_read=lots"`, are rejected;
+    /// use `MountOption::CUSTOM` to pass them anyway.
+    fn from_str(s: &str) -> Result<MountOption, ParseMountOptionError> {
+        parse_option(s, true)
+    }
+}
+
+impl fmt::Display for MountOption {
+    /// Format the option the way it is written on the command line or in fstab, without
+    /// escaping
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountOption::AllowRoot => f.write_str("allow_root"),
+            option => f.write_str(&option_to_string(option)),
+        }
+    }
+}
+
+/// Parse a single unescaped option. If `strict` is false, options that can't be parsed are
+/// passed on as `CUSTOM`.
+pub(crate) fn parse_option(s: &str, strict: bool) -> Result<MountOption, ParseMountOptionError> {
+    let option = match s {
+        "auto_unmount" => MountOption::AutoUnmount,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "relatime" => MountOption::RelAtime,
+        "strictatime" => MountOption::StrictAtime,
+        "lazytime" => MountOption::LazyTime,
+        "nonempty" => MountOption::NonEmpty,
+        "blkdev" => MountOption::BlkDev,
+        "recover_stale" => MountOption::RecoverStale,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        x if x.starts_with("fsname=") => MountOption::FSName(x[7..].into()),
+        x if x.starts_with("subtype=") => MountOption::Subtype(x[8..].into()),
+        x if x.starts_with("context=") => MountOption::Context(x[8..].into()),
+        x if x.starts_with("fscontext=") => MountOption::FSContext(x[10..].into()),
+        x => {
+            let parsed = match x.split_once('=') {
+                Some(("max_read", value)) => Some(value.parse().map(MountOption::MaxRead)),
+                Some(("blksize", value)) => Some(value.parse().map(MountOption::BlkSize)),
+                Some(("rootmode", value)) => {
+                    Some(u32::from_str_radix(value, 8).map(MountOption::RootMode))
+                }
+                Some(("user_id", value)) => Some(value.parse().map(MountOption::UserId)),
+                Some(("group_id", value)) => Some(value.parse().map(MountOption::GroupId)),
+                _ => None,
+            };
+            match parsed {
+                Some(Ok(option)) => option,
+                _ if !strict => MountOption::CUSTOM(x.into()),
+                Some(Err(_)) => return Err(ParseMountOptionError::InvalidValue(x.into())),
+                None => return Err(ParseMountOptionError::Unknown(x.into())),
+            }
+        }
+    };
+    Ok(option)
+}
+
+/// Error returned when parsing mount options fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseMountOptionError {
+    /// The option is not known. Contains the option
+    Unknown(String),
+    /// The option is known, but its value is invalid. Contains the option
+    InvalidValue(String),
+    /// The options are malformed, e.g. end in an incomplete escape sequence. Contains a
+    /// description of the problem
+    Syntax(String),
+}
+
+impl fmt::Display for ParseMountOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseMountOptionError::Unknown(option) => write!(f, "Unknown mount option: {}", option),
+            ParseMountOptionError::InvalidValue(option) => {
+                write!(f, "Invalid value in mount option: {}", option)
+            }
+            ParseMountOptionError::Syntax(message) => {
+                write!(f, "Error parsing mount options: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseMountOptionError {}
+
+impl From<ParseMountOptionError> for io::Error {
+    fn from(err: ParseMountOptionError) -> Self {
+        io::Error::new(ErrorKind::InvalidInput, err)
+    }
+}
+
+/// A list of mount options, which can be parsed from and formatted to the comma separated
+/// form used on the command line and in fstab.
+///
+/// Commas, backslashes and whitespace within an option are escaped with a backslash: `\,` and
+/// `\\` as understood by libfuse, or fstab's octal escapes such as `\040` for a space.
+/// Formatting escapes whitespace with octal escapes, so the result can be used as a field in
+/// fstab, and parses back to the same options.
+///
+/// `parse` (and `FromStr`) rejects unknown options and known options with an invalid value,
+/// while `parse_permissive` passes them on as `MountOption::CUSTOM`. Formatted options that
+/// contain `CUSTOM` options thus only parse back with `parse_permissive`. Empty options, such as
+/// in `"ro,,nodev"`, are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountOptions(Vec<MountOption>);
+
+impl MountOptions {
+    /// Create an empty list of options
+    pub fn new() -> MountOptions {
+        MountOptions(vec![])
+    }
+
+    /// Parse comma separated options, such as `"ro,fsname=foo"`. Unknown options and known
+    /// options with an invalid value are rejected.
+    pub fn parse(s: &str) -> Result<MountOptions, ParseMountOptionError> {
+        parse_options(s, true).map(MountOptions)
+    }
+
+    /// Parse comma separated options, such as `"ro,fsname=foo"`. Unknown options and options
+    /// with invalid values are passed on as `MountOption::CUSTOM`.
+    pub fn parse_permissive(s: &str) -> Result<MountOptions, ParseMountOptionError> {
+        parse_options(s, false).map(MountOptions)
+    }
+
+    /// Parse command line arguments, such as `["-o", "ro,nodev", "-osync"]`. Unknown options
+    /// and known options with an invalid value are rejected.
+    pub fn from_args<S: AsRef<OsStr>>(args: &[S]) -> Result<MountOptions, ParseMountOptionError> {
+        let mut options = vec![];
+        for arg in option_args(args)? {
+            options.extend(parse_options(arg, true)?);
+        }
+        Ok(MountOptions(options))
+    }
+
+    /// Options to mount a filesystem that is backed by the block device at `device` (`fuseblk`),
+    /// with the given block size in bytes. Fails if the path is not valid UTF-8, since it is
+    /// passed as `FSName`.
+    pub fn block_device(device: &Path, block_size: u32) -> Result<MountOptions, MountError> {
+        let device = device.to_str().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Block device path {} is not valid UTF-8", device.display()),
+            )
+        })?;
+        Ok(MountOptions(vec![
+            MountOption::FSName(device.to_owned()),
+            MountOption::BlkDev,
+            MountOption::BlkSize(block_size),
+        ]))
+    }
+
+    /// Append an option
+    pub fn push(&mut self, option: MountOption) {
+        self.0.push(option);
+    }
+
+    /// Check that no two options conflict, such as `ro` and `rw`
+    pub fn check_conflicts(&self) -> Result<(), MountError> {
+        check_option_conflicts(&self.0)
+    }
+
+    /// Return the options as a `Vec`
+    pub fn into_vec(self) -> Vec<MountOption> {
+        self.0
+    }
+}
+
+impl FromStr for MountOptions {
+    type Err = ParseMountOptionError;
+
+    fn from_str(s: &str) -> Result<MountOptions, ParseMountOptionError> {
+        MountOptions::parse(s)
+    }
+}
+
+impl fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, option) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            for c in option.to_string().chars() {
+                match c {
+                    ',' => f.write_str("\\,")?,
+                    '\\' => f.write_str("\\\\")?,
+                    ' ' | '\t' | '\n' => write!(f, "\\{:03o}", c as u32)?,
+                    c => write!(f, "{}", c)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Deref for MountOptions {
+    type Target = [MountOption];
+
+    fn deref(&self) -> &[MountOption] {
+        &self.0
+    }
+}
+
+impl AsRef<[MountOption]> for MountOptions {
+    fn as_ref(&self) -> &[MountOption] {
+        &self.0
+    }
+}
+
+impl From<Vec<MountOption>> for MountOptions {
+    fn from(options: Vec<MountOption>) -> MountOptions {
+        MountOptions(options)
+    }
+}
+
+impl From<MountOptions> for Vec<MountOption> {
+    fn from(options: MountOptions) -> Vec<MountOption> {
+        options.0
+    }
+}
+
+impl FromIterator<MountOption> for MountOptions {
+    fn from_iter<I: IntoIterator<Item = MountOption>>(iter: I) -> MountOptions {
+        MountOptions(iter.into_iter().collect())
+    }
+}
+
+impl Extend<MountOption> for MountOptions {
+    fn extend<I: IntoIterator<Item = MountOption>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl IntoIterator for MountOptions {
+    type Item = MountOption;
+    type IntoIter = std::vec::IntoIter<MountOption>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a MountOptions {
+    type Item = &'a MountOption;
+    type IntoIter = std::slice::Iter<'a, MountOption>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// Split comma separated options, and resolve escape sequences
+fn split_options(s: &str) -> Result<Vec<String>, ParseMountOptionError> {
+    let mut out = vec![];
+    let mut current = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' => out.push(std::mem::take(&mut current)),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='7') => {
+                    let mut value = digit.to_digit(8).unwrap();
+                    for _ in 0..2 {
+                        match chars.next().and_then(|x| x.to_digit(8)) {
+                            Some(x) => value = value * 8 + x,
+                            None => {
+                                return Err(ParseMountOptionError::Syntax(
+                                    "Incomplete octal escape sequence".to_owned(),
+                                ))
+                            }
+                        }
+                    }
+                    let escaped =
+                        char::from_u32(value)
+                            .filter(char::is_ascii)
+                            .ok_or_else(|| {
+                                ParseMountOptionError::Syntax(format!(
+                                    "Invalid escape sequence \\{:o}",
+                                    value
+                                ))
+                            })?;
+                    current.push(escaped);
+                }
+                Some(escaped) => current.push(escaped),
+                None => {
+                    return Err(ParseMountOptionError::Syntax(
+                        "Trailing backslash".to_owned(),
+                    ))
+                }
+            },
+            c => current.push(c),
         }
     }
+    out.push(current);
+    out.retain(|x| !x.is_empty());
+    Ok(out)
+}
+
+fn parse_options(s: &str, strict: bool) -> Result<Vec<MountOption>, ParseMountOptionError> {
+    split_options(s)?
+        .iter()
+        .map(|x| parse_option(x, strict))
+        .collect()
+}
+
+/// Collect the values of the `-o` arguments
+fn option_args<S: AsRef<OsStr>>(args: &[S]) -> Result<Vec<&str>, ParseMountOptionError> {
+    let err = |x: &str| ParseMountOptionError::Syntax(x.to_owned());
+    let args: Option<Vec<_>> = args.iter().map(|x| x.as_ref().to_str()).collect();
+    let args = args.ok_or_else(|| err("Invalid UTF-8"))?;
+    let mut it = args.iter();
+    let mut out = vec![];
+    loop {
+        let opt = match it.next() {
+            None => break,
+            Some(&"-o") => *it
+                .next()
+                .ok_or_else(|| err("Expected option, reached end of args"))?,
+            Some(x) if x.starts_with("-o") => &x[2..],
+            Some(x) => return Err(err(&format!("expected -o, got {}", x))),
+        };
+        out.push(opt);
+    }
+    Ok(out)
 }
 
-pub fn check_option_conflicts(options: &[MountOption]) -> Result<(), io::Error> {
+pub fn check_option_conflicts(options: &[MountOption]) -> Result<(), MountError> {
     let mut options_set = HashSet::new();
     options_set.extend(options.iter().cloned());
     let conflicting: HashSet<MountOption> = options.iter().map(conflicts_with).flatten().collect();
     let intersection: Vec<MountOption> = conflicting.intersection(&options_set).cloned().collect();
     if !intersection.is_empty() {
-        Err(io::Error::new(
-            ErrorKind::InvalidInput,
-            format!("Conflicting mount options found: {:?}", intersection),
-        ))
+        Err(MountError::ConflictingOptions(intersection))
     } else {
         Ok(())
     }
 }
 
+/// Check that the device given for a `BlkDev` mount is a block device, and that `BlkSize` is
+/// only given for such mounts
+pub(crate) fn check_block_device(options: &[MountOption]) -> Result<(), MountError> {
+    if !options.contains(&MountOption::BlkDev) {
+        return match options
+            .iter()
+            .find(|x| matches!(x, MountOption::BlkSize(_)))
+        {
+            Some(option) => Err(MountError::BlockDeviceRequired(option.clone())),
+            None => Ok(()),
+        };
+    }
+    let device = match options.iter().rev().find_map(|x| match x {
+        MountOption::FSName(name) => Some(PathBuf::from(name)),
+        _ => None,
+    }) {
+        Some(device) => device,
+        None => return Err(MountError::BlockDeviceRequired(MountOption::BlkDev)),
+    };
+    match fs::metadata(&device) {
+        Ok(metadata) if metadata.file_type().is_block_device() => Ok(()),
+        Ok(_) => Err(MountError::NotBlockDevice(device)),
+        Err(err) => Err(MountError::Io(io::Error::new(
+            err.kind(),
+            format!("Block device {}: {}", device.display(), err),
+        ))),
+    }
+}
+
 fn conflicts_with(option: &MountOption) -> Vec<MountOption> {
     match option {
         MountOption::FSName(_) => vec![],
         MountOption::Subtype(_) => vec![],
         MountOption::CUSTOM(_) => vec![],
+        MountOption::MaxRead(_) => vec![],
+        MountOption::BlkSize(_) => vec![],
+        MountOption::RootMode(_) => vec![],
+        MountOption::UserId(_) => vec![],
+        MountOption::GroupId(_) => vec![],
+        MountOption::Context(_) => vec![],
+        MountOption::FSContext(_) => vec![],
         MountOption::AllowOther => vec![MountOption::AllowRoot],
         MountOption::AllowRoot => vec![MountOption::AllowOther],
         MountOption::AutoUnmount => vec![],
         MountOption::DefaultPermissions => vec![],
+        MountOption::NonEmpty => vec![],
+        MountOption::BlkDev => vec![],
+        MountOption::RecoverStale => vec![],
         MountOption::Dev => vec![MountOption::NoDev],
         MountOption::NoDev => vec![MountOption::Dev],
         MountOption::Suid => vec![MountOption::NoSuid],
@@ -119,7 +518,14 @@ fn conflicts_with(option: &MountOption) -> Vec<MountOption> {
         MountOption::Exec => vec![MountOption::NoExec],
         MountOption::NoExec => vec![MountOption::Exec],
         MountOption::Atime => vec![MountOption::NoAtime],
-        MountOption::NoAtime => vec![MountOption::Atime],
+        MountOption::NoAtime => vec![
+            MountOption::Atime,
+            MountOption::RelAtime,
+            MountOption::StrictAtime,
+        ],
+        MountOption::RelAtime => vec![MountOption::NoAtime, MountOption::StrictAtime],
+        MountOption::StrictAtime => vec![MountOption::NoAtime, MountOption::RelAtime],
+        MountOption::LazyTime => vec![],
         MountOption::DirSync => vec![],
         MountOption::Sync => vec![MountOption::Async],
         MountOption::Async => vec![MountOption::Sync],
@@ -132,12 +538,22 @@ pub fn option_to_string(option: &MountOption) -> String {
         MountOption::FSName(name) => format!("fsname={}", name),
         MountOption::Subtype(subtype) => format!("subtype={}", subtype),
         MountOption::CUSTOM(value) => value.to_string(),
+        MountOption::MaxRead(size) => format!("max_read={}", size),
+        MountOption::BlkSize(size) => format!("blksize={}", size),
+        MountOption::RootMode(mode) => format!("rootmode={:o}", mode),
+        MountOption::UserId(uid) => format!("user_id={}", uid),
+        MountOption::GroupId(gid) => format!("group_id={}", gid),
+        MountOption::Context(context) => format!("context={}", context),
+        MountOption::FSContext(context) => format!("fscontext={}", context),
         MountOption::AutoUnmount => "auto_unmount".to_string(),
         MountOption::AllowOther => "allow_other".to_string(),
         // AllowRoot is implemented by allowing everyone access and then restricting to
         // root + owner within fuser
         MountOption::AllowRoot => "allow_other".to_string(),
         MountOption::DefaultPermissions => "default_permissions".to_string(),
+        MountOption::NonEmpty => "nonempty".to_string(),
+        MountOption::BlkDev => "blkdev".to_string(),
+        MountOption::RecoverStale => "recover_stale".to_string(),
         MountOption::Dev => "dev".to_string(),
         MountOption::NoDev => "nodev".to_string(),
         MountOption::Suid => "suid".to_string(),
@@ -148,36 +564,29 @@ pub fn option_to_string(option: &MountOption) -> String {
         MountOption::NoExec => "noexec".to_string(),
         MountOption::Atime => "atime".to_string(),
         MountOption::NoAtime => "noatime".to_string(),
+        MountOption::RelAtime => "relatime".to_string(),
+        MountOption::StrictAtime => "strictatime".to_string(),
+        MountOption::LazyTime => "lazytime".to_string(),
         MountOption::DirSync => "dirsync".to_string(),
         MountOption::Sync => "sync".to_string(),
         MountOption::Async => "async".to_string(),
     }
 }
 
-/// Parses mount command args.
+/// Parses mount command args. Options that can't be parsed are passed on as `CUSTOM`. Unlike
+/// `MountOptions::from_args`, backslashes are not escape characters, as before `MountOptions`
+/// existed.
 ///
 /// Input: ["-o", "suid", "-o", "ro,nodev,noexec", "-osync"]
 /// Output Ok([Suid, RO, NoDev, NoExec, Sync])
 pub(crate) fn parse_options_from_args(args: &[&OsStr]) -> io::Result<Vec<MountOption>> {
-    let err = |x| io::Error::new(ErrorKind::InvalidInput, x);
-    let args: Option<Vec<_>> = args.iter().map(|x| x.to_str()).collect();
-    let args = args.ok_or_else(|| err("Error parsing args: Invalid UTF-8".to_owned()))?;
-    let mut it = args.iter();
-    let mut out = vec![];
-    loop {
-        let opt = match it.next() {
-            None => break,
-            Some(&"-o") => *it.next().ok_or_else(|| {
-                err("Error parsing args: Expected option, reached end of args".to_owned())
-            })?,
-            Some(x) if x.starts_with("-o") => &x[2..],
-            Some(x) => return Err(err(format!("Error parsing args: expected -o, got {}", x))),
-        };
-        for x in opt.split(',') {
-            out.push(MountOption::from_str(x))
+    let mut options = vec![];
+    for arg in option_args(args)? {
+        for option in arg.split(',') {
+            options.push(parse_option(option, false)?);
         }
     }
-    Ok(out)
+    Ok(options)
 }
 
 #[cfg(test)]
@@ -190,6 +599,8 @@ mod test {
     fn option_checking() {
         assert!(check_option_conflicts(&[MountOption::Suid, MountOption::NoSuid]).is_err());
         assert!(check_option_conflicts(&[MountOption::Suid, MountOption::NoExec]).is_ok());
+        assert!(check_option_conflicts(&[MountOption::RelAtime, MountOption::NoAtime]).is_err());
+        assert!(check_option_conflicts(&[MountOption::RelAtime, MountOption::LazyTime]).is_ok());
     }
     #[test]
     fn option_round_trip() {
@@ -198,9 +609,19 @@ mod test {
             FSName("Blah".to_owned()),
             Subtype("Bloo".to_owned()),
             CUSTOM("bongos".to_owned()),
+            MaxRead(131072),
+            BlkSize(4096),
+            RootMode(0o40755),
+            UserId(1000),
+            GroupId(100),
+            Context("system_u:object_r:tmp_t:s0".to_owned()),
+            FSContext("system_u:object_r:fusefs_t:s0".to_owned()),
             AllowOther,
             AutoUnmount,
             DefaultPermissions,
+            NonEmpty,
+            BlkDev,
+            RecoverStale,
             Dev,
             NoDev,
             Suid,
@@ -211,16 +632,120 @@ mod test {
             NoExec,
             Atime,
             NoAtime,
+            RelAtime,
+            StrictAtime,
+            LazyTime,
             DirSync,
             Sync,
             Async,
         ]
         .iter()
         {
-            assert_eq!(*x, MountOption::from_str(option_to_string(x).as_ref()))
+            assert_eq!(
+                *x,
+                parse_option(option_to_string(x).as_ref(), false).unwrap()
+            )
         }
     }
 
+    #[test]
+    fn invalid_typed_values() {
+        assert_eq!(
+            parse_option("max_read=lots", false),
+            Ok(MountOption::CUSTOM("max_read=lots".to_owned()))
+        );
+        assert_eq!(
+            "rootmode=999".parse::<MountOption>(),
+            Err(ParseMountOptionError::InvalidValue(
+                "rootmode=999".to_owned()
+            ))
+        );
+        assert_eq!(
+            "nosuidd".parse::<MountOption>(),
+            Err(ParseMountOptionError::Unknown("nosuidd".to_owned()))
+        );
+        assert_eq!(
+            parse_option("nosuidd", false),
+            Ok(MountOption::CUSTOM("nosuidd".to_owned()))
+        );
+        assert_eq!("allow_root".parse(), Ok(MountOption::AllowRoot));
+    }
+
+    #[test]
+    fn parse_escaped_options() {
+        use super::MountOption::*;
+
+        let options =
+            MountOptions::parse(r"ro,fsname=a\,b,,subtype=my\040fs,context=x\\y").unwrap();
+        assert_eq!(
+            options.into_vec(),
+            [
+                RO,
+                FSName("a,b".to_owned()),
+                Subtype("my fs".to_owned()),
+                Context(r"x\y".to_owned())
+            ]
+        );
+
+        // A typo is not silently passed on
+        assert_eq!(
+            MountOptions::parse("ro,nodevv"),
+            Err(ParseMountOptionError::Unknown("nodevv".to_owned()))
+        );
+        assert_eq!(
+            MountOptions::parse_permissive("ro,bongos")
+                .unwrap()
+                .into_vec(),
+            [RO, CUSTOM("bongos".to_owned())]
+        );
+        assert!(MountOptions::parse("ro,max_read=lots").is_err());
+        assert_eq!(
+            MountOptions::parse_permissive("ro,max_read=lots")
+                .unwrap()
+                .into_vec(),
+            [RO, CUSTOM("max_read=lots".to_owned())]
+        );
+        assert!(MountOptions::parse(r"ro\").is_err());
+        assert!(MountOptions::parse(r"fsname=\04").is_err());
+    }
+
+    #[test]
+    fn format_round_trip() {
+        use super::MountOption::*;
+
+        let options: MountOptions = vec![
+            AllowRoot,
+            FSName(r"a,b\c d".to_owned()),
+            MaxRead(4096),
+            RootMode(0o40755),
+            CUSTOM("bongos".to_owned()),
+        ]
+        .into();
+        let formatted = options.to_string();
+        assert_eq!(
+            formatted,
+            r"allow_root,fsname=a\,b\\c\040d,max_read=4096,rootmode=40755,bongos"
+        );
+        assert!(!formatted.contains(' '));
+        // Custom options only parse back permissively
+        assert!(formatted.parse::<MountOptions>().is_err());
+        assert_eq!(MountOptions::parse_permissive(&formatted).unwrap(), options);
+    }
+
+    #[test]
+    fn options_from_args() {
+        use super::MountOption::*;
+
+        let options = MountOptions::from_args(&["-o", "ro,nodev", "-osync"]).unwrap();
+        assert_eq!(&*options, &[RO, NoDev, Sync]);
+        assert_eq!(
+            MountOptions::from_args(&["-o", "ro,nodevv"]),
+            Err(ParseMountOptionError::Unknown("nodevv".to_owned()))
+        );
+        assert!(MountOptions::from_args(&["-o", "max_read=lots"]).is_err());
+        assert!(MountOptions::from_args(&["-o"]).is_err());
+    }
+
     #[test]
     fn test_parse_options() {
         use super::MountOption::*;
@@ -237,5 +762,35 @@ mod test {
         assert!(parse_options_from_args(&[OsStr::new("-o")]).is_err());
         assert!(parse_options_from_args(&[OsStr::new("not o")]).is_err());
         assert!(parse_options_from_args(&[OsStr::from_bytes(b"-o\xc3\x28")]).is_err());
+
+        // No escape sequences
+        let out = parse_options_from_args(&[OsStr::new(r"-ofsname=a\,b")]).unwrap();
+        assert_eq!(out, [FSName(r"a\".to_owned()), CUSTOM("b".to_owned())]);
+    }
+
+    #[test]
+    fn block_device() {
+        let options = MountOptions::block_device(Path::new("/dev/loop0"), 4096).unwrap();
+        assert_eq!(options.to_string(), "fsname=/dev/loop0,blkdev,blksize=4096");
+        assert!(
+            MountOptions::block_device(Path::new(OsStr::from_bytes(b"/dev/\xff")), 512).is_err()
+        );
+        assert!(check_block_device(&[]).is_ok());
+        assert!(matches!(
+            check_block_device(&[MountOption::BlkDev]),
+            Err(MountError::BlockDeviceRequired(MountOption::BlkDev))
+        ));
+        assert!(matches!(
+            check_block_device(&[MountOption::BlkSize(512)]),
+            Err(MountError::BlockDeviceRequired(MountOption::BlkSize(512)))
+        ));
+        let options = MountOptions::block_device(Path::new("/dev/null"), 512).unwrap();
+        assert!(matches!(
+            check_block_device(&options),
+            Err(MountError::NotBlockDevice(_))
+        ));
+        let options = MountOptions::block_device(Path::new("/nowhere"), 512).unwrap();
+        let err = check_block_device(&options);
+        assert!(matches!(err, Err(MountError::Io(err)) if err.kind() == ErrorKind::NotFound));
     }
 }
@@ -0,0 +1,135 @@
+//! Parsing of /proc/self/mountinfo
+//!
+//! See `proc(5)` for the format. Fields that may contain spaces are escaped with octal escape
+//! sequences like `\040`.
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::{fs, io};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// A line of /proc/self/mountinfo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MountInfo {
+    /// Device number of the filesystem (major, minor). For FUSE mounts the minor number is the
+    /// id of the connection in /sys/fs/fuse/connections.
+    pub(crate) device: (u32, u32),
+    /// Mount point relative to the root of the process
+    pub(crate) mount_point: PathBuf,
+    /// Per-mount options, such as `nosuid`
+    pub(crate) mount_options: String,
+    /// Filesystem type, e.g. `fuse.sshfs`
+    pub(crate) fstype: String,
+    /// Filesystem specific source, e.g. the `fsname` of a FUSE filesystem
+    pub(crate) source: String,
+    /// Per-superblock options, such as `user_id=1000`
+    pub(crate) super_options: String,
+}
+
+/// Read the mounts of the current process
+pub(crate) fn read() -> io::Result<Vec<MountInfo>> {
+    parse(&fs::read_to_string(MOUNTINFO)?)
+}
+
+pub(crate) fn parse(contents: &str) -> io::Result<Vec<MountInfo>> {
+    contents
+        .lines()
+        .filter(|x| !x.is_empty())
+        .map(|line| {
+            parse_line(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid line in {}: {}", MOUNTINFO, line),
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<MountInfo> {
+    let mut fields = line.split(' ');
+    // Mount id and parent id
+    fields.nth(1)?;
+    let (major, minor) = fields.next()?.split_once(':')?;
+    let device = (major.parse().ok()?, minor.parse().ok()?);
+    // Root of the mount within the filesystem
+    fields.next()?;
+    let mount_point = unescape(fields.next()?).into();
+    let mount_options = lossy(unescape(fields.next()?));
+    // Skip the optional fields, which are terminated by a single hyphen
+    fields.by_ref().find(|x| *x == "-")?;
+    let fstype = lossy(unescape(fields.next()?));
+    let source = lossy(unescape(fields.next()?));
+    let super_options = lossy(unescape(fields.next()?));
+    Some(MountInfo {
+        device,
+        mount_point,
+        mount_options,
+        fstype,
+        source,
+        super_options,
+    })
+}
+
+fn lossy(value: OsString) -> String {
+    value.to_string_lossy().into_owned()
+}
+
+/// Resolve octal escape sequences, such as `\040` for a space
+fn unescape(field: &str) -> OsString {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 4)
+            .filter(|x| bytes[i] == b'\\' && x.iter().all(|digit| (b'0'..=b'7').contains(digit)));
+        match escape {
+            Some(digits) => {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |acc, digit| acc * 8 + (digit - b'0') as u32);
+                out.push(value as u8);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    OsString::from_vec(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn parse_mountinfo() {
+        let contents = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+36 22 0:45 / /mnt/my\\040files rw,nosuid,nodev - fuse.sshfs host:/home rw,user_id=1000,group_id=1000
+";
+        let mounts = parse(contents).unwrap();
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].fstype, "ext4");
+        assert_eq!(mounts[1].device, (0, 45));
+        assert_eq!(mounts[1].mount_point, Path::new("/mnt/my files"));
+        assert_eq!(mounts[1].mount_options, "rw,nosuid,nodev");
+        assert_eq!(mounts[1].fstype, "fuse.sshfs");
+        assert_eq!(mounts[1].source, "host:/home");
+        assert_eq!(mounts[1].super_options, "rw,user_id=1000,group_id=1000");
+
+        assert!(parse("garbage\n").is_err());
+    }
+
+    #[test]
+    fn read_own_mounts() {
+        let mounts = read().unwrap();
+        assert!(mounts.iter().any(|x| x.mount_point == Path::new("/")));
+    }
+}
@@ -0,0 +1,94 @@
+//! Recovery of stale mountpoints
+//!
+//! When a filesystem process dies without unmounting, its mount stays in place and every access
+//! to the mountpoint fails with ENOTCONN, including the attempt to mount over it again. With
+//! `MountOption::RecoverStale` such a mount is unmounted lazily before mounting.
+
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process::Command;
+use std::{fs, io};
+
+use log::{debug, warn};
+
+#[cfg(target_os = "linux")]
+use super::mountinfo;
+use super::{resolve_mountpoint, MountError};
+
+/// Lazily unmount the mount at `mountpoint`, if it is a FUSE mount whose filesystem process has
+/// died. Does nothing if the mountpoint is accessible.
+pub(crate) fn recover_stale_mount(mountpoint: &Path) -> Result<(), MountError> {
+    match fs::metadata(mountpoint) {
+        Err(err) if err.raw_os_error() == Some(libc::ENOTCONN) => {}
+        _ => return Ok(()),
+    }
+    let mountpoint = resolve_mountpoint(mountpoint)?;
+
+    // Only unmount what is known to be a FUSE mount
+    #[cfg(target_os = "linux")]
+    let source = match mountinfo::read()?
+        .into_iter()
+        .rev()
+        .find(|x| x.mount_point == mountpoint && x.fstype.starts_with("fuse"))
+    {
+        Some(mount) => mount.source,
+        None => return Err(MountError::StaleMountpoint(mountpoint)),
+    };
+    #[cfg(not(target_os = "linux"))]
+    let source = "unknown source".to_owned();
+
+    match lazy_unmount(&mountpoint) {
+        Ok(()) => {
+            warn!(
+                "Unmounted stale FUSE mount of {} at {}",
+                source,
+                mountpoint.display()
+            );
+            Ok(())
+        }
+        Err(error) => Err(MountError::StaleMountpointNotRecovered { mountpoint, error }),
+    }
+}
+
+fn lazy_unmount(mountpoint: &Path) -> io::Result<()> {
+    let c_mountpoint = CString::new(mountpoint.as_os_str().as_bytes())?;
+    #[cfg(target_os = "linux")]
+    let result = unsafe { libc::umount2(c_mountpoint.as_ptr(), libc::MNT_DETACH) };
+    #[cfg(not(target_os = "linux"))]
+    let result = unsafe { libc::unmount(c_mountpoint.as_ptr(), libc::MNT_FORCE) };
+    if result == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // Unprivileged users may unmount their own FUSE mounts with fusermount
+        Some(libc::EPERM) | Some(libc::EACCES) => fusermount_unmount(mountpoint.as_os_str()),
+        _ => Err(err),
+    }
+}
+
+fn fusermount_unmount(mountpoint: &OsStr) -> io::Result<()> {
+    let mut result = Err(io::Error::from(io::ErrorKind::NotFound));
+    for name in ["fusermount3", "fusermount"].iter() {
+        result = Command::new(name)
+            .arg("-u")
+            .arg("-z")
+            .arg("--")
+            .arg(mountpoint)
+            .output();
+        if let Ok(output) = &result {
+            debug!("{}: {}", name, String::from_utf8_lossy(&output.stderr));
+            break;
+        }
+    }
+    let output = result?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ))
+    }
+}
@@ -0,0 +1,92 @@
+//! Mounting without privileges inside a user namespace
+//!
+//! Kernels since 4.18 allow mounting FUSE filesystems in a mount namespace that is owned by a
+//! user namespace, without the setuid `fusermount` binary. The mount is only visible to
+//! processes in that mount namespace.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+
+use log::info;
+
+/// Namespaces to mount in, see `enter_user_namespace`
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UserNamespace {
+    /// Create a new user and mount namespace. The current user and group keep their ids.
+    New,
+    /// Create a new user and mount namespace, in which the current user and group appear as root
+    NewAsRoot,
+    /// Join existing namespaces, such as `/proc/<pid>/ns/user` and `/proc/<pid>/ns/mnt` of
+    /// another process
+    Existing {
+        /// The user namespace
+        user: OwnedFd,
+        /// A mount namespace owned by `user`
+        mount: OwnedFd,
+    },
+}
+
+/// Move the current process into a user and mount namespace, in which it may mount FUSE
+/// filesystems directly. Sessions created afterwards are mounted and served inside the
+/// namespace.
+///
+/// The kernel only allows this for single threaded processes, so it must be called before any
+/// threads are started.
+pub fn enter_user_namespace(namespace: UserNamespace) -> io::Result<()> {
+    if fs::read_dir("/proc/self/task")?.count() > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "User namespaces can only be entered by single threaded processes",
+        ));
+    }
+    match namespace {
+        UserNamespace::New => unshare(false),
+        UserNamespace::NewAsRoot => unshare(true),
+        UserNamespace::Existing { user, mount } => {
+            setns(&user, libc::CLONE_NEWUSER)?;
+            setns(&mount, libc::CLONE_NEWNS)?;
+            info!("Joined existing user and mount namespace");
+            Ok(())
+        }
+    }
+}
+
+fn unshare(as_root: bool) -> io::Result<()> {
+    let uid = users::get_current_uid();
+    let gid = users::get_current_gid();
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (inner_uid, inner_gid) = if as_root { (0, 0) } else { (uid, gid) };
+    fs::write("/proc/self/uid_map", format!("{} {} 1", inner_uid, uid))?;
+    // Unprivileged processes must give up setgroups() before they may map groups
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/gid_map", format!("{} {} 1", inner_gid, gid))?;
+    // Keep mounts inside the namespace
+    let result = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            b"/\0".as_ptr() as *const libc::c_char,
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    info!(
+        "Entered new user and mount namespace as uid {}, gid {}",
+        inner_uid, inner_gid
+    );
+    Ok(())
+}
+
+fn setns(namespace: &OwnedFd, kind: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::setns(namespace.as_raw_fd(), kind) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
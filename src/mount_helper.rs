@@ -0,0 +1,369 @@
+//! Entry point for filesystems that are mounted by mount(8), e.g. from /etc/fstab
+//!
+//! mount(8) runs `mount.fuse` (or `mount.fuse.<type>`) for filesystems of type `fuse.<type>`,
+//! with the arguments `source mountpoint [-t type] [-o options]`. Older setups use the type
+//! `fuse` with a `type#source` source. The helper mounts the filesystem, and keeps serving it in
+//! the background after mount(8) returned.
+
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::daemon::daemonize;
+use crate::mnt::mount_options::{parse_option, MountOption, MountOptions, ParseMountOptionError};
+use crate::session::{self, Session, SessionError};
+use crate::Filesystem;
+
+/// Options that mount(8) and fstab use, and which are not meant for the filesystem
+const IGNORED_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner", "group", "_netdev", "nofail",
+];
+
+/// Arguments a mount helper was called with by mount(8)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountHelperArgs {
+    /// Filesystem type, without the `fuse.` prefix, if one was given
+    pub fstype: Option<String>,
+    /// Source of the filesystem, e.g. a device, URL or the name of the filesystem
+    pub source: String,
+    /// Directory to mount at
+    pub mountpoint: PathBuf,
+    /// Mount options understood by FUSE
+    pub options: MountOptions,
+    /// Options which are not FUSE mount options, for the filesystem itself to interpret
+    pub filesystem_options: Vec<String>,
+    /// Run the filesystem as this user (`setuid=USER`). It is mounted as that user as well.
+    pub setuid: Option<String>,
+    /// Drop all capabilities once mounted (`drop_privileges`)
+    pub drop_privileges: bool,
+    /// Keep running in the foreground instead of detaching once mounted. mount(8) waits for the
+    /// helper to exit, so this is false by default.
+    pub foreground: bool,
+}
+
+impl MountHelperArgs {
+    /// Parse the arguments of a mount helper, including the program name, which determines the
+    /// filesystem type if it is named like `mount.fuse.<type>`. Usually called with
+    /// `std::env::args_os()`.
+    pub fn parse<I, S>(args: I) -> Result<MountHelperArgs, ParseMountOptionError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let err = |x: &str| ParseMountOptionError::Syntax(x.to_owned());
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let args: Option<Vec<&str>> = args.iter().map(|x| x.to_str()).collect();
+        let args = args.ok_or_else(|| err("Invalid UTF-8"))?;
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| err("Missing program name"))?;
+
+        let program = Path::new(program)
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default();
+        let mut blkdev = program.starts_with("mount.fuseblk");
+        let mut fstype = program
+            .strip_prefix("mount.fuseblk.")
+            .or_else(|| program.strip_prefix("mount.fuse3."))
+            .or_else(|| program.strip_prefix("mount.fuse."))
+            .map(str::to_owned);
+
+        let mut positional = vec![];
+        let mut option_strings = vec![];
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            match *arg {
+                "-o" => option_strings.push(
+                    *it.next()
+                        .ok_or_else(|| err("Expected options, reached end of args"))?,
+                ),
+                "-t" => {
+                    let t = it
+                        .next()
+                        .ok_or_else(|| err("Expected type, reached end of args"))?;
+                    if let Some(t) = t.strip_prefix("fuseblk.") {
+                        blkdev = true;
+                        fstype = Some(t.to_owned());
+                    } else if let Some(t) = t.strip_prefix("fuse.") {
+                        fstype = Some(t.to_owned());
+                    } else if *t == "fuseblk" {
+                        blkdev = true;
+                    }
+                }
+                // No mtab, sloppy and verbose flags of mount(8)
+                "-n" | "-s" | "-v" => {}
+                x if x.starts_with("-o") => option_strings.push(&x[2..]),
+                x if x.starts_with('-') => {
+                    return Err(err(&format!("Unknown argument {}", x)));
+                }
+                x => positional.push(x),
+            }
+        }
+        let (source, mountpoint) = match positional[..] {
+            [source, mountpoint] => (source, mountpoint),
+            _ => return Err(err("Expected source and mountpoint")),
+        };
+        let source = match source.split_once('#') {
+            Some((t, source)) if fstype.is_none() => {
+                fstype = Some(t.to_owned());
+                source
+            }
+            _ => source,
+        };
+
+        let mut result = MountHelperArgs {
+            fstype,
+            source: source.to_owned(),
+            mountpoint: mountpoint.into(),
+            options: MountOptions::new(),
+            filesystem_options: vec![],
+            setuid: None,
+            drop_privileges: false,
+            foreground: false,
+        };
+        for options in option_strings {
+            // Parse permissively to keep escape handling in one place, then re-check each option
+            for option in MountOptions::parse_permissive(options)? {
+                let option = match option {
+                    MountOption::CUSTOM(x) => x,
+                    option => {
+                        result.options.push(option);
+                        continue;
+                    }
+                };
+                // Options unknown to fuser are filesystem options
+                match parse_option(&option, true) {
+                    Err(ParseMountOptionError::Unknown(_)) => {}
+                    Err(error) => return Err(error),
+                    Ok(_) => unreachable!(),
+                }
+                if let Some(user) = option.strip_prefix("setuid=") {
+                    result.setuid = Some(user.to_owned());
+                } else if option == "drop_privileges" {
+                    result.drop_privileges = true;
+                } else if IGNORED_OPTIONS.contains(&option.as_str())
+                    || option.starts_with("x-")
+                    || option.starts_with("comment=")
+                {
+                    continue;
+                } else {
+                    result.filesystem_options.push(option);
+                }
+            }
+        }
+        if blkdev && !result.options.contains(&MountOption::BlkDev) {
+            result.options.push(MountOption::BlkDev);
+        }
+        Ok(result)
+    }
+
+    /// Mount options to mount with: the given options, plus the source as `fsname` and the
+    /// type as `subtype`, unless these were set explicitly
+    pub fn mount_options(&self) -> Vec<MountOption> {
+        let mut options = self.options.to_vec();
+        if !options.iter().any(|x| matches!(x, MountOption::FSName(_))) {
+            options.push(MountOption::FSName(self.source.clone()));
+        }
+        if let Some(fstype) = &self.fstype {
+            if !options.iter().any(|x| matches!(x, MountOption::Subtype(_))) {
+                options.push(MountOption::Subtype(fstype.clone()));
+            }
+        }
+        options
+    }
+}
+
+/// Mount a filesystem as a mount helper, and serve it until it is unmounted.
+///
+/// Switches to the `setuid` user first, then mounts, drops privileges if requested, and calls
+/// `build` to create the filesystem. Unless `foreground` is set, this happens in a background
+/// process (see `daemonize`), and the calling process exits once the filesystem is usable, so
+/// mount(8) returns and reports any error. SIGINT, SIGTERM and SIGHUP unmount the filesystem.
+pub fn mount_helper<FS, F>(args: MountHelperArgs, build: F) -> Result<(), SessionError>
+where
+    FS: Filesystem,
+    F: FnOnce(&MountHelperArgs) -> io::Result<FS>,
+{
+    let setup = || {
+        if let Some(user) = &args.setuid {
+            switch_user(user)?;
+        }
+        let options = args.mount_options();
+        let (file, mount, allowed) = session::mount(&args.mountpoint, &options)?;
+        info!("Mounted {} at {}", args.source, args.mountpoint.display());
+        if args.drop_privileges {
+            drop_privileges()?;
+        }
+        let filesystem = build(&args)?;
+        Ok(Session::from_parts(
+            filesystem,
+            file,
+            Some(mount),
+            &args.mountpoint,
+            allowed,
+        ))
+    };
+    let mut session = if args.foreground {
+        setup()?
+    } else {
+        daemonize(setup)?
+    };
+    session.run_with_signal_handlers().map(|_| ())
+}
+
+/// Switch the user, group and supplementary groups of this process to those of `name`
+fn switch_user(name: &str) -> io::Result<()> {
+    let user = users::get_user_by_name(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Unknown user {}", name)))?;
+    let c_name = CString::new(name.as_bytes())?;
+    unsafe {
+        if libc::setgid(user.primary_group_id()) != 0
+            || libc::initgroups(c_name.as_ptr(), user.primary_group_id() as _) != 0
+            || libc::setuid(user.uid()) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Drop all capabilities, and prevent regaining them, like `mount.fuse3 -o drop_privileges`
+#[cfg(target_os = "linux")]
+fn drop_privileges() -> io::Result<()> {
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+    const SECBIT_NOROOT: libc::c_ulong = 1 << 0;
+    const SECBIT_NOROOT_LOCKED: libc::c_ulong = 1 << 1;
+    const SECBIT_NO_SETUID_FIXUP: libc::c_ulong = 1 << 2;
+    const SECBIT_NO_SETUID_FIXUP_LOCKED: libc::c_ulong = 1 << 3;
+    const SECBIT_KEEP_CAPS_LOCKED: libc::c_ulong = 1 << 5;
+
+    #[repr(C)]
+    struct CapHeader {
+        version: u32,
+        pid: libc::c_int,
+    }
+    #[repr(C)]
+    struct CapData {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    let securebits = SECBIT_NOROOT
+        | SECBIT_NOROOT_LOCKED
+        | SECBIT_NO_SETUID_FIXUP
+        | SECBIT_NO_SETUID_FIXUP_LOCKED
+        | SECBIT_KEEP_CAPS_LOCKED;
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: 0,
+            permitted: 0,
+            inheritable: 0,
+        },
+        CapData {
+            effective: 0,
+            permitted: 0,
+            inheritable: 0,
+        },
+    ];
+    unsafe {
+        // Changing the secure bits requires CAP_SETPCAP, so they are set first
+        if libc::prctl(libc::PR_SET_SECUREBITS, securebits) != 0
+            || libc::syscall(libc::SYS_capset, &header, data.as_ptr()) != 0
+            || libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as libc::c_ulong, 0, 0, 0) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_privileges() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "drop_privileges is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_fstab_style() {
+        let args = MountHelperArgs::parse(vec![
+            "/sbin/mount.fuse.ourfs",
+            "server:/export",
+            "/mnt/ours",
+            "-o",
+            r"ro,allow_other,cache=loose,setuid=nobody,noauto,x-systemd.automount,fsname=a\,b",
+        ])
+        .unwrap();
+        assert_eq!(args.fstype.as_deref(), Some("ourfs"));
+        assert_eq!(args.source, "server:/export");
+        assert_eq!(args.mountpoint, Path::new("/mnt/ours"));
+        assert_eq!(
+            &*args.options,
+            &[
+                MountOption::RO,
+                MountOption::AllowOther,
+                MountOption::FSName("a,b".to_owned())
+            ]
+        );
+        assert_eq!(args.filesystem_options, ["cache=loose"]);
+        assert_eq!(args.setuid.as_deref(), Some("nobody"));
+        assert!(!args.drop_privileges);
+        assert!(!args.foreground);
+        assert_eq!(
+            args.mount_options().last(),
+            Some(&MountOption::Subtype("ourfs".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parse_type_in_source() {
+        let args = MountHelperArgs::parse(vec![
+            "mount.fuse",
+            "ourfs#/dev/sdb1",
+            "/mnt",
+            "-oblkdev,drop_privileges",
+        ])
+        .unwrap();
+        assert_eq!(args.fstype.as_deref(), Some("ourfs"));
+        assert_eq!(args.source, "/dev/sdb1");
+        assert_eq!(&*args.options, &[MountOption::BlkDev]);
+        assert!(args.drop_privileges);
+        assert_eq!(
+            args.mount_options(),
+            [
+                MountOption::BlkDev,
+                MountOption::FSName("/dev/sdb1".to_owned()),
+                MountOption::Subtype("ourfs".to_owned())
+            ]
+        );
+
+        let args = MountHelperArgs::parse(vec!["mount.fuse", "src", "/mnt", "-t", "fuseblk.ourfs"])
+            .unwrap();
+        assert_eq!(args.fstype.as_deref(), Some("ourfs"));
+        assert_eq!(&*args.options, &[MountOption::BlkDev]);
+    }
+
+    #[test]
+    fn reject_invalid_args() {
+        assert!(MountHelperArgs::parse(vec!["mount.fuse", "src"]).is_err());
+        assert!(MountHelperArgs::parse(vec!["mount.fuse", "src", "/mnt", "-o"]).is_err());
+        assert!(MountHelperArgs::parse(vec!["mount.fuse", "src", "/mnt", "-x"]).is_err());
+        assert!(
+            MountHelperArgs::parse(vec!["mount.fuse", "src", "/mnt", "-o", "max_read=lots"])
+                .is_err()
+        );
+    }
+}
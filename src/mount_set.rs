@@ -0,0 +1,293 @@
+//! Serving many mounts from a shared pool of threads
+//!
+//! A `MountSet` puts the FUSE devices of its sessions into non-blocking mode and waits for all of
+//! them with a single epoll instance. Its worker threads share that instance: each readiness
+//! event is delivered to one worker (`EPOLLONESHOT`), which processes the available requests of
+//! that session with `Session::process_next` and then rearms it. A session is thus only ever
+//! processed by one thread at a time, while different sessions are processed in parallel.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use log::{error, info, warn};
+
+use crate::session::{Processed, Session, SessionError};
+use crate::sys::{self, epoll_ctl};
+use crate::Filesystem;
+
+/// Epoll token of the eventfd that stops the workers
+const SHUTDOWN: u64 = u64::MAX;
+/// Number of requests a worker processes for one session before it serves the others
+const BATCH_SIZE: usize = 64;
+
+/// Identifies a mount of a `MountSet`
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MountId(u64);
+
+/// Serves the sessions of many mounts with a fixed number of worker threads
+///
+/// Sessions are added and removed at runtime. A session is removed by the set once its
+/// filesystem was unmounted, or if processing a request fails or panics; the error is logged. Dropping
+/// the set stops the workers and drops the remaining sessions, which unmounts their filesystems.
+#[derive(Debug)]
+pub struct MountSet {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    epoll: OwnedFd,
+    /// Becomes readable when the workers should stop
+    shutdown: OwnedFd,
+    sessions: Mutex<HashMap<u64, Arc<Entry>>>,
+    next_id: AtomicU64,
+}
+
+struct Entry {
+    mountpoint: PathBuf,
+    fd: RawFd,
+    /// Taken when the session is removed
+    session: Mutex<Option<Box<dyn Served>>>,
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("mountpoint", &self.mountpoint)
+            .field("fd", &self.fd)
+            .finish()
+    }
+}
+
+/// A session whose filesystem may be of any type
+trait Served: Send {
+    fn process_next(&mut self) -> Result<Processed, SessionError>;
+}
+
+impl<FS: Filesystem + Send> Served for Session<FS> {
+    fn process_next(&mut self) -> Result<Processed, SessionError> {
+        Session::process_next(self)
+    }
+}
+
+impl MountSet {
+    /// Create a set that serves its sessions with `workers` threads
+    pub fn new(workers: usize) -> io::Result<MountSet> {
+        let epoll = sys::epoll_create()?;
+        let shutdown = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if shutdown < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let shutdown = unsafe { OwnedFd::from_raw_fd(shutdown) };
+        // Level-triggered, so all workers see it
+        epoll_ctl(
+            epoll.as_fd(),
+            libc::EPOLL_CTL_ADD,
+            shutdown.as_raw_fd(),
+            0,
+            SHUTDOWN,
+        )?;
+        let shared = Arc::new(Shared {
+            epoll,
+            shutdown,
+            sessions: Mutex::default(),
+            next_id: AtomicU64::new(0),
+        });
+        let workers = (0..workers)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+        Ok(MountSet { shared, workers })
+    }
+
+    /// Add a session to the set, whose requests are served from now on. Its FUSE device is put
+    /// into non-blocking mode.
+    pub fn add<FS>(&self, mut session: Session<FS>) -> io::Result<MountId>
+    where
+        FS: Filesystem + Send + 'static,
+    {
+        session.set_nonblocking(true)?;
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            mountpoint: session.mountpoint().to_owned(),
+            fd: session.as_raw_fd(),
+            session: Mutex::new(Some(Box::new(session))),
+        });
+        let fd = entry.fd;
+        self.shared
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(id, entry.clone());
+        if let Err(err) = epoll_ctl(
+            self.shared.epoll.as_fd(),
+            libc::EPOLL_CTL_ADD,
+            fd,
+            libc::EPOLLONESHOT,
+            id,
+        ) {
+            self.shared.sessions.lock().unwrap().remove(&id);
+            return Err(err);
+        }
+        info!("Serving {} in mount set", entry.mountpoint.display());
+        Ok(MountId(id))
+    }
+
+    /// Remove a session from the set and drop it, which unmounts its filesystem if the session
+    /// owns the mount. Waits until the session finished processing its current requests. Returns
+    /// `false` if the session was removed already.
+    pub fn remove(&self, id: MountId) -> bool {
+        match self.shared.remove(id.0) {
+            Some(session) => {
+                drop(session);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Mountpoint of a session of the set
+    pub fn mountpoint(&self, id: MountId) -> Option<PathBuf> {
+        let sessions = self.shared.sessions.lock().unwrap();
+        sessions.get(&id.0).map(|x| x.mountpoint.clone())
+    }
+
+    /// Ids of the sessions in the set
+    pub fn mounts(&self) -> Vec<MountId> {
+        let sessions = self.shared.sessions.lock().unwrap();
+        sessions.keys().map(|&id| MountId(id)).collect()
+    }
+
+    /// Number of sessions in the set
+    pub fn len(&self) -> usize {
+        self.shared.sessions.lock().unwrap().len()
+    }
+
+    /// Whether the set has no sessions
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for MountSet {
+    fn drop(&mut self) {
+        let one = 1u64.to_ne_bytes();
+        unsafe { libc::write(self.shared.shutdown.as_raw_fd(), one.as_ptr().cast(), 8) };
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        let ids: Vec<u64> = self
+            .shared
+            .sessions
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for id in ids {
+            self.shared.remove(id);
+        }
+    }
+}
+
+impl Shared {
+    /// Loop of a worker thread
+    fn work(&self) {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        loop {
+            let count = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), &mut event, 1, -1) };
+            if count < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Failed to wait for requests: {}", err);
+                return;
+            }
+            if count == 0 {
+                continue;
+            }
+            if event.u64 == SHUTDOWN {
+                return;
+            }
+            self.serve(event.u64);
+        }
+    }
+
+    /// Process the available requests of a session, and wait for more
+    fn serve(&self, id: u64) {
+        let entry = match self.sessions.lock().unwrap().get(&id) {
+            Some(entry) => entry.clone(),
+            None => return,
+        };
+        let mut session = entry.session.lock().unwrap();
+        let served = match session.as_mut() {
+            Some(served) => served,
+            // Removed in the meantime
+            None => return,
+        };
+        for _ in 0..BATCH_SIZE {
+            // A panicking filesystem only takes down its own session, not the worker
+            let processed = match panic::catch_unwind(AssertUnwindSafe(|| served.process_next())) {
+                Ok(processed) => processed,
+                Err(_) => {
+                    error!("Session {} panicked", entry.mountpoint.display());
+                    drop(session);
+                    self.remove(id);
+                    return;
+                }
+            };
+            match processed {
+                Ok(Processed::Unmounted) => {
+                    drop(session);
+                    self.remove(id);
+                    return;
+                }
+                Ok(_) => {}
+                Err(SessionError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    error!("Session {} failed: {}", entry.mountpoint.display(), err);
+                    drop(session);
+                    self.remove(id);
+                    return;
+                }
+            }
+        }
+        if let Err(err) = epoll_ctl(
+            self.epoll.as_fd(),
+            libc::EPOLL_CTL_MOD,
+            entry.fd,
+            libc::EPOLLONESHOT,
+            id,
+        ) {
+            warn!(
+                "Failed to wait for requests of {}: {}",
+                entry.mountpoint.display(),
+                err
+            );
+        }
+    }
+
+    /// Remove a session, once it finished processing requests
+    fn remove(&self, id: u64) -> Option<Box<dyn Served>> {
+        let entry = self.sessions.lock().unwrap().remove(&id)?;
+        let _ = epoll_ctl(self.epoll.as_fd(), libc::EPOLL_CTL_DEL, entry.fd, 0, id);
+        let session = entry
+            .session
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        info!("Removed {} from mount set", entry.mountpoint.display());
+        session
+    }
+}
@@ -0,0 +1,214 @@
+//! FUSE mounts and their connections to the kernel
+//!
+//! Mounts are read from /proc/self/mountinfo. Every FUSE mount belongs to a connection, which can
+//! be inspected and controlled through the fusectl filesystem, usually mounted at
+//! /sys/fs/fuse/connections. Its files are owned by root, so most of the operations on
+//! [`Connection`] require root privileges.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::path::{Path, PathBuf};
+
+use crate::mnt::mountinfo::{self, MountInfo};
+use crate::mnt::resolve_mountpoint;
+
+/// Where the fusectl filesystem is mounted
+const CONNECTIONS: &str = "/sys/fs/fuse/connections";
+
+/// A mounted FUSE filesystem
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FuseMount {
+    /// Source of the filesystem, as given with `MountOption::FSName`
+    pub source: String,
+    /// Filesystem type: `fuse`, or `fuseblk` for filesystems backed by a block device
+    pub fstype: String,
+    /// Subtype of the filesystem, as given with `MountOption::Subtype`
+    pub subtype: Option<String>,
+    /// Where the filesystem is mounted
+    pub mountpoint: PathBuf,
+    /// Per-mount options, such as `nosuid`
+    pub mount_options: Vec<String>,
+    /// Options of the filesystem, such as `user_id=1000`
+    pub super_options: Vec<String>,
+    /// User that mounted the filesystem, if known
+    pub user_id: Option<u32>,
+    /// Connection that serves the filesystem
+    pub connection: Connection,
+}
+
+impl FuseMount {
+    fn from_mountinfo(info: MountInfo) -> Option<FuseMount> {
+        let (fstype, subtype) = match info.fstype.split_once('.') {
+            Some((fstype, subtype)) => (fstype.to_owned(), Some(subtype.to_owned())),
+            None => (info.fstype, None),
+        };
+        if fstype != "fuse" && fstype != "fuseblk" {
+            return None;
+        }
+        let super_options: Vec<String> = split_options(&info.super_options);
+        let user_id = super_options
+            .iter()
+            .find_map(|x| x.strip_prefix("user_id=")?.parse().ok());
+        Some(FuseMount {
+            source: info.source,
+            fstype,
+            subtype,
+            mountpoint: info.mount_point,
+            mount_options: split_options(&info.mount_options),
+            super_options,
+            user_id,
+            connection: Connection(info.device.1),
+        })
+    }
+}
+
+fn split_options(options: &str) -> Vec<String> {
+    options.split(',').map(str::to_owned).collect()
+}
+
+/// List the FUSE filesystems mounted in the mount namespace of this process
+pub fn list() -> io::Result<Vec<FuseMount>> {
+    Ok(mountinfo::read()?
+        .into_iter()
+        .filter_map(FuseMount::from_mountinfo)
+        .collect())
+}
+
+/// Find the FUSE filesystem that is mounted at `mountpoint`. If several filesystems are mounted
+/// on top of each other, the visible one is returned. The mountpoint itself is not accessed.
+pub fn find(mountpoint: &Path) -> io::Result<Option<FuseMount>> {
+    let mountpoint = resolve_mountpoint(mountpoint)?;
+    Ok(list()?
+        .into_iter()
+        .rev()
+        .find(|x| x.mountpoint == mountpoint))
+}
+
+/// Connection between the kernel and a FUSE filesystem, identified by the minor device number
+/// of its mounts
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Connection(u32);
+
+impl Connection {
+    /// Connection with the given id
+    pub fn from_id(id: u32) -> Connection {
+        Connection(id)
+    }
+
+    /// Connection that the given FUSE device is attached to. Returns `None` if the device isn't
+    /// mounted yet, or if the kernel is too old to report the connection of devices.
+    pub fn of_device(device: BorrowedFd<'_>) -> io::Result<Option<Connection>> {
+        let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", device.as_raw_fd()))?;
+        Ok(parse_fdinfo(&fdinfo))
+    }
+
+    /// Id of the connection
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+
+    /// Directory that controls the connection in the fusectl filesystem
+    pub fn path(&self) -> PathBuf {
+        Path::new(CONNECTIONS).join(self.0.to_string())
+    }
+
+    /// Abort the connection. All pending and future requests fail with ENOTCONN, and the
+    /// filesystem has to be unmounted.
+    pub fn abort(&self) -> io::Result<()> {
+        self.write("abort", 1)
+    }
+
+    /// Number of requests that are waiting to be processed by the filesystem
+    pub fn waiting(&self) -> io::Result<u32> {
+        self.read("waiting")
+    }
+
+    /// Maximum number of pending background requests
+    pub fn max_background(&self) -> io::Result<u32> {
+        self.read("max_background")
+    }
+
+    /// Change the maximum number of pending background requests
+    pub fn set_max_background(&self, value: u32) -> io::Result<()> {
+        self.write("max_background", value)
+    }
+
+    /// Number of background requests at which the kernel considers the queue congested
+    pub fn congestion_threshold(&self) -> io::Result<u32> {
+        self.read("congestion_threshold")
+    }
+
+    /// Change the number of background requests at which the kernel considers the queue
+    /// congested
+    pub fn set_congestion_threshold(&self, value: u32) -> io::Result<()> {
+        self.write("congestion_threshold", value)
+    }
+
+    fn read(&self, name: &str) -> io::Result<u32> {
+        let path = self.path().join(name);
+        let value = fs::read_to_string(&path)?;
+        value.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid value in {}: {:?}", path.display(), value),
+            )
+        })
+    }
+
+    fn write(&self, name: &str, value: u32) -> io::Result<()> {
+        fs::write(self.path().join(name), value.to_string())
+    }
+}
+
+/// Reads the connection from the fdinfo of a FUSE device
+fn parse_fdinfo(fdinfo: &str) -> Option<Connection> {
+    fdinfo
+        .lines()
+        .find_map(|x| x.strip_prefix("fuse_connection:"))
+        .and_then(|x| x.trim().parse().ok())
+        .map(Connection)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fuse_mounts_only() {
+        let mounts: Vec<_> = mountinfo::parse(
+            "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+36 22 0:45 / /mnt/sshfs rw,nosuid,nodev - fuse.sshfs host:/home rw,user_id=1000,group_id=1000
+37 22 0:46 / /mnt/disk rw - fuseblk /dev/sdb1 rw,user_id=0,group_id=0,blksize=4096
+38 22 0:47 / /mnt/plain rw - fuse hello rw
+",
+        )
+        .unwrap()
+        .into_iter()
+        .filter_map(FuseMount::from_mountinfo)
+        .collect();
+        assert_eq!(mounts.len(), 3);
+        assert_eq!(mounts[0].source, "host:/home");
+        assert_eq!(mounts[0].fstype, "fuse");
+        assert_eq!(mounts[0].subtype.as_deref(), Some("sshfs"));
+        assert_eq!(mounts[0].mountpoint, Path::new("/mnt/sshfs"));
+        assert_eq!(mounts[0].mount_options, ["rw", "nosuid", "nodev"]);
+        assert_eq!(mounts[0].user_id, Some(1000));
+        assert_eq!(mounts[0].connection.id(), 45);
+        assert_eq!(
+            mounts[0].connection.path(),
+            Path::new("/sys/fs/fuse/connections/45")
+        );
+        assert_eq!(mounts[1].fstype, "fuseblk");
+        assert_eq!(mounts[1].subtype, None);
+        assert_eq!(mounts[2].user_id, None);
+    }
+
+    #[test]
+    fn connection_of_device() {
+        let fdinfo = "pos:\t0\nflags:\t02100002\nmnt_id:\t25\nino:\t88\nfuse_connection:\t39\n";
+        assert_eq!(parse_fdinfo(fdinfo), Some(Connection(39)));
+        assert_eq!(parse_fdinfo("pos:\t0\nflags:\t02100002\n"), None);
+    }
+}
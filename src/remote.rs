@@ -0,0 +1,455 @@
+//! Serving a filesystem over a stream, such as a Unix socket or a TCP connection
+//!
+//! A `Forwarder` owns the mount. It passes the requests it reads from the FUSE device over a
+//! stream to a session that receives them with a `StreamTransport`, usually in another process
+//! or on another host, and passes the replies back to the kernel. FUSE messages start with their
+//! length, so they are sent as they are, which requires both ends to use the same byte order.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use libc::{c_int, EAGAIN, EINTR, ENODEV, ENOENT, O_NONBLOCK};
+use log::{info, warn};
+
+use crate::channel::{Channel, ChannelSender, Transport};
+use crate::ll::fuse_abi::{self as abi, fuse_opcode};
+use crate::ll::reply::peek_reply_header;
+use crate::ll::{self, Errno, RequestId, Response};
+use crate::mnt::{Mount, MountError};
+use crate::reply::ReplySender;
+use crate::session::{aligned_sub_buf, SessionUnmounter, BUFFER_SIZE};
+use crate::MountOption;
+
+/// Receives requests from a `Forwarder` over a stream, such as a `UnixStream` or `TcpStream`.
+/// Pass it to `Session::with_transport` to serve them.
+///
+/// The session loop ends once the forwarder closes the stream, e.g. because the filesystem was
+/// unmounted.
+#[derive(Debug)]
+pub struct StreamTransport<S> {
+    sender: StreamSender<S>,
+}
+
+impl<S> StreamTransport<S> {
+    /// Create a transport that receives requests from `stream`
+    pub fn new(stream: S) -> StreamTransport<S> {
+        StreamTransport {
+            sender: StreamSender {
+                stream: Arc::new(stream),
+                lock: Arc::new(Mutex::new(())),
+            },
+        }
+    }
+}
+
+impl<S> Transport for StreamTransport<S>
+where
+    S: AsFd + Send + Sync + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    type Sender = StreamSender<S>;
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let min_len = std::mem::size_of::<abi::fuse_in_header>();
+        match read_message(&*self.sender.stream, buffer, min_len) {
+            Ok(Some(len)) => Ok(len),
+            // Like reading from an unmounted FUSE device. The forwarder resets the connection if
+            // it closes the stream before it received all replies.
+            Ok(None) => Err(io::Error::from_raw_os_error(ENODEV)),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {
+                Err(io::Error::from_raw_os_error(ENODEV))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn sender(&self) -> StreamSender<S> {
+        self.sender.clone()
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.sender.stream.as_fd())
+    }
+}
+
+/// Sends replies over the stream of a `StreamTransport`
+#[derive(Debug)]
+pub struct StreamSender<S> {
+    stream: Arc<S>,
+    /// Keeps replies that are sent from several threads from being interleaved
+    lock: Arc<Mutex<()>>,
+}
+
+impl<S> Clone for StreamSender<S> {
+    fn clone(&self) -> Self {
+        StreamSender {
+            stream: self.stream.clone(),
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+impl<S> ReplySender for StreamSender<S>
+where
+    S: Send + Sync + 'static,
+    for<'a> &'a S: Write,
+{
+    fn send(&self, data: &[IoSlice<'_>]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(data.iter().map(|x| x.len()).sum());
+        for x in data {
+            message.extend_from_slice(x);
+        }
+        let _guard = self.lock.lock().unwrap();
+        (&*self.stream).write_all(&message)
+    }
+}
+
+/// Why `Forwarder::serve` returned
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Disconnect {
+    /// The stream was closed or failed. The filesystem stays mounted, and requests are served
+    /// again by the next call to `serve`.
+    Peer,
+    /// The filesystem was unmounted
+    Unmounted,
+}
+
+/// Mounts a filesystem and forwards its requests over a stream to a session that serves them
+/// with a `StreamTransport`.
+///
+/// The forwarder survives reconnects: the kernel's INIT request is sent to every new peer, whose
+/// reply is only checked, since the kernel was initialized already. Requests that were sent to a
+/// peer that disconnected before replying fail with `EIO`.
+///
+/// Access control is left to the kernel, i.e. to the `allow_other` option. `allow_root` and
+/// `auto_unmount` without `allow_other` grant access to all users.
+#[derive(Debug)]
+pub struct Forwarder {
+    ch: Channel,
+    sender: ChannelSender,
+    mount: Arc<Mutex<Option<Mount>>>,
+    mountpoint: PathBuf,
+    /// INIT request of the kernel, sent again to new peers
+    init: Option<(RequestId, Vec<u8>)>,
+    requests: Vec<u8>,
+    replies: Vec<u8>,
+}
+
+impl Forwarder {
+    /// Mount a filesystem at `mountpoint`, whose requests are forwarded by `serve`
+    pub fn new(mountpoint: &Path, options: &[MountOption]) -> Result<Forwarder, MountError> {
+        let (file, mount, _) = crate::session::mount(mountpoint, options)?;
+        Ok(Forwarder::from_parts(file, Some(mount), mountpoint))
+    }
+
+    /// Forward the requests of an already open and mounted FUSE device. Like with
+    /// `Session::from_fd`, the forwarder does not own the mount.
+    pub fn from_fd(fd: OwnedFd, mountpoint: &Path) -> Forwarder {
+        Forwarder::from_parts(Arc::new(File::from(fd)), None, mountpoint)
+    }
+
+    fn from_parts(file: Arc<File>, mount: Option<Mount>, mountpoint: &Path) -> Forwarder {
+        let ch = Channel::new(file);
+        Forwarder {
+            sender: ch.sender(),
+            ch,
+            mount: Arc::new(Mutex::new(mount)),
+            mountpoint: mountpoint.to_owned(),
+            init: None,
+            requests: vec![0; BUFFER_SIZE],
+            replies: vec![0; BUFFER_SIZE],
+        }
+    }
+
+    /// Return path of the mounted filesystem
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Returns a thread-safe object that can be used to unmount the filesystem, which makes
+    /// `serve` return
+    pub fn unmount_callable(&self) -> SessionUnmounter {
+        SessionUnmounter::new(self.mount.clone())
+    }
+
+    /// Forward requests to the peer at the other end of `stream`, and its replies to the kernel,
+    /// until the peer disconnects or the filesystem is unmounted
+    ///
+    /// The stream is put into non-blocking mode while it is served: requests are queued until the
+    /// stream is writable, so replies are received even while the peer doesn't read requests.
+    pub fn serve<S>(&mut self, stream: S) -> io::Result<Disconnect>
+    where
+        S: AsFd,
+        for<'a> &'a S: Read + Write,
+    {
+        info!("Forwarding requests of {}", self.mountpoint.display());
+        let _nonblocking = NonBlocking::set(stream.as_fd())?;
+        let requests = aligned_sub_buf(
+            &mut self.requests,
+            std::mem::align_of::<abi::fuse_in_header>(),
+        );
+        let mut outbound = Outbound::default();
+        // Length of the replies in `self.replies` that were not forwarded yet
+        let mut received = 0;
+        let mut pending = HashSet::new();
+        // The new peer has to be initialized like the kernel was
+        let mut replayed_init = None;
+        if let Some((unique, init)) = &self.init {
+            outbound.push(init);
+            replayed_init = Some(*unique);
+        }
+
+        let result = 'serve: loop {
+            // New requests are only received once the previous ones were written
+            let ready = match wait_ready(&self.ch, &stream, outbound.is_empty()) {
+                Ok(ready) => ready,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => break Err(err),
+            };
+            if ready.stream_readable {
+                match (&stream).read(&mut self.replies[received..]) {
+                    Ok(0) => break Ok(Disconnect::Peer),
+                    Ok(size) => received += size,
+                    Err(err)
+                        if err.kind() == io::ErrorKind::WouldBlock
+                            || err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        warn!("Failed to receive reply: {}", err);
+                        break Ok(Disconnect::Peer);
+                    }
+                }
+                let min_len = std::mem::size_of::<abi::fuse_out_header>();
+                let mut start = 0;
+                while received - start >= 4 {
+                    let header = self.replies[start..start + 4].try_into().unwrap();
+                    let size = match message_size(header, min_len, self.replies.len()) {
+                        Ok(size) => size,
+                        Err(err) => {
+                            warn!("Failed to receive reply: {}", err);
+                            break 'serve Ok(Disconnect::Peer);
+                        }
+                    };
+                    if received - start < size {
+                        break;
+                    }
+                    let reply = &self.replies[start..start + size];
+                    start += size;
+                    let (unique, error) = peek_reply_header(reply).unwrap();
+                    if Some(unique) == replayed_init {
+                        replayed_init = None;
+                        if error != 0 {
+                            warn!("Peer failed to initialize: error {}", -error);
+                        }
+                        continue;
+                    }
+                    pending.remove(&unique.0);
+                    // Fails if the request was interrupted in the meantime
+                    if let Err(err) = self.sender.send(&[IoSlice::new(reply)]) {
+                        warn!("Failed to forward reply to request {:?}: {}", unique, err);
+                    }
+                }
+                self.replies.copy_within(start..received, 0);
+                received -= start;
+            }
+            if ready.stream_writable {
+                if let Err(err) = outbound.write_to(&stream) {
+                    warn!("Failed to forward request: {}", err);
+                    break Ok(Disconnect::Peer);
+                }
+            }
+            if ready.device {
+                match self.ch.receive(requests) {
+                    Ok(size) => {
+                        let request = &requests[..size];
+                        if let Some((unique, opcode)) = ll::peek_header(request) {
+                            if opcode == fuse_opcode::FUSE_INIT as u32 {
+                                self.init = Some((unique, request.to_vec()));
+                            }
+                            if ll::expects_reply(opcode) {
+                                pending.insert(unique.0);
+                            }
+                        }
+                        outbound.push(request);
+                        if let Err(err) = outbound.write_to(&stream) {
+                            warn!("Failed to forward request: {}", err);
+                            break Ok(Disconnect::Peer);
+                        }
+                    }
+                    Err(err) => match err.raw_os_error() {
+                        Some(ENOENT) | Some(EINTR) | Some(EAGAIN) => {}
+                        Some(ENODEV) => break Ok(Disconnect::Unmounted),
+                        _ => break Err(err),
+                    },
+                }
+            }
+        };
+
+        if result.as_ref().ok() == Some(&Disconnect::Peer) {
+            info!(
+                "Peer disconnected, failing {} pending requests",
+                pending.len()
+            );
+            for unique in pending {
+                let _ = Response::new_error(Errno::EIO)
+                    .with_iovec(RequestId(unique), |iov| self.sender.send(iov));
+            }
+        }
+        result
+    }
+}
+
+/// Requests that were not completely written to the stream yet
+#[derive(Debug, Default)]
+struct Outbound {
+    data: Vec<u8>,
+    written: usize,
+}
+
+impl Outbound {
+    fn is_empty(&self) -> bool {
+        self.written == self.data.len()
+    }
+
+    fn push(&mut self, message: &[u8]) {
+        if self.is_empty() {
+            self.data.clear();
+            self.written = 0;
+        }
+        self.data.extend_from_slice(message);
+    }
+
+    /// Write as much as the non-blocking `stream` takes
+    fn write_to<W: Write>(&mut self, mut stream: W) -> io::Result<()> {
+        while !self.is_empty() {
+            match stream.write(&self.data[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(size) => self.written += size,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Puts a file descriptor into non-blocking mode, and restores its flags on drop
+struct NonBlocking<'a> {
+    fd: BorrowedFd<'a>,
+    flags: c_int,
+}
+
+impl<'a> NonBlocking<'a> {
+    fn set(fd: BorrowedFd<'a>) -> io::Result<NonBlocking<'a>> {
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | O_NONBLOCK) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NonBlocking { fd, flags })
+    }
+}
+
+impl Drop for NonBlocking<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::fcntl(self.fd.as_raw_fd(), libc::F_SETFL, self.flags) };
+    }
+}
+
+/// Read a single FUSE message into `buffer`. Returns `None` if the stream was closed.
+fn read_message<R: Read>(
+    mut stream: R,
+    buffer: &mut [u8],
+    min_len: usize,
+) -> io::Result<Option<usize>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let size = message_size(len, min_len, buffer.len())?;
+    buffer[..4].copy_from_slice(&len);
+    stream.read_exact(&mut buffer[4..size])?;
+    Ok(Some(size))
+}
+
+/// Length of a FUSE message, from its first 4 bytes
+fn message_size(header: [u8; 4], min_len: usize, max_len: usize) -> io::Result<usize> {
+    let size = u32::from_ne_bytes(header) as usize;
+    if size < min_len || size > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid FUSE message length {}", size),
+        ));
+    }
+    Ok(size)
+}
+
+/// What `Forwarder::serve` can do without blocking
+#[derive(Debug)]
+struct Ready {
+    device: bool,
+    stream_readable: bool,
+    stream_writable: bool,
+}
+
+/// Waits until the stream is readable, or writable while requests are queued, or until the FUSE
+/// device is readable if `receive` is set
+fn wait_ready<S: AsFd>(ch: &Channel, stream: &S, receive: bool) -> io::Result<Ready> {
+    let mut fds = [
+        libc::pollfd {
+            // Negative descriptors are ignored
+            fd: if receive { ch.as_fd().as_raw_fd() } else { -1 },
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stream.as_fd().as_raw_fd(),
+            events: if receive {
+                libc::POLLIN
+            } else {
+                libc::POLLIN | libc::POLLOUT
+            },
+            revents: 0,
+        },
+    ];
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = fds[1].revents;
+    Ok(Ready {
+        device: fds[0].revents != 0,
+        stream_readable: stream & !libc::POLLOUT != 0,
+        stream_writable: stream & !libc::POLLIN != 0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_messages() {
+        let mut data = vec![];
+        data.extend_from_slice(&20u32.to_ne_bytes());
+        data.extend_from_slice(&[1; 16]);
+        data.extend_from_slice(&8u32.to_ne_bytes());
+        let mut stream = &data[..];
+        let mut buffer = [0; 64];
+        assert_eq!(
+            read_message(&mut stream, &mut buffer, 16).unwrap(),
+            Some(20)
+        );
+        assert_eq!(buffer[19], 1);
+        // Shorter than a header
+        assert!(read_message(&mut stream, &mut buffer, 16).is_err());
+        assert_eq!(read_message(&mut stream, &mut buffer, 16).unwrap(), None);
+    }
+}
@@ -12,12 +12,13 @@ use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::path::Path;
 
-use crate::channel::ChannelSender;
-use crate::ll::Request as _;
+use crate::buffer::RequestData;
+use crate::channel::{SharedSender, Transport};
+use crate::ll::{Request as _, RequestError};
 #[cfg(feature = "abi-7-21")]
 use crate::reply::ReplyDirectoryPlus;
 use crate::reply::{Reply, ReplyDirectory, ReplySender};
-use crate::session::{Session, SessionACL};
+use crate::session::{InvalidRequestError, Session, SessionACL, SessionError};
 use crate::Filesystem;
 use crate::{ll, KernelConfig};
 
@@ -25,37 +26,72 @@ use crate::{ll, KernelConfig};
 #[derive(Debug)]
 pub struct Request<'a> {
     /// Channel sender for sending the reply
-    ch: ChannelSender,
+    ch: SharedSender,
     /// Request raw data
     data: &'a [u8],
+    /// Buffer the request was received into
+    buffer: &'a RequestData,
     /// Parsed request
     request: ll::AnyRequest<'a>,
 }
 
 impl<'a> Request<'a> {
     /// Create a new request from the given data
-    pub(crate) fn new(ch: ChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
+    pub(crate) fn new(
+        ch: SharedSender,
+        buffer: &'a RequestData,
+    ) -> Result<Request<'a>, InvalidRequestError> {
+        let data = &buffer[..];
         let request = match ll::AnyRequest::try_from(data) {
             Ok(request) => request,
             Err(err) => {
-                error!("{}", err);
-                return None;
+                let header = ll::peek_header(data);
+                // If at least the header could be read, the kernel may still be waiting for a reply
+                match header {
+                    Some((unique, opcode)) if ll::expects_reply(opcode) => {
+                        send_error(&ch, unique, Errno::EIO)
+                    }
+                    _ => {}
+                }
+                return Err(InvalidRequestError::new(header, err));
             }
         };
 
-        Some(Self { ch, data, request })
+        Ok(Self {
+            ch,
+            data,
+            buffer,
+            request,
+        })
     }
 
     /// Dispatch request to the given filesystem.
     /// This calls the appropriate filesystem operation method for the
-    /// request and sends back the returned reply to the kernel
-    pub(crate) fn dispatch<FS: Filesystem>(&self, se: &mut Session<FS>) {
+    /// request and sends back the returned reply to the kernel. Requests
+    /// whose arguments can't be parsed are answered with an error and
+    /// returned to the caller.
+    pub(crate) fn dispatch<FS: Filesystem, T: Transport>(
+        &self,
+        se: &mut Session<FS, T>,
+    ) -> Result<(), InvalidRequestError> {
         debug!("{}", self.request);
         let unique = self.request.unique();
 
-        let res = match self.dispatch_req(se) {
+        let op = match self.request.operation() {
+            Ok(op) => op,
+            Err(err) => {
+                let errno = match err {
+                    RequestError::UnknownOperation(_) => Errno::ENOSYS,
+                    _ => Errno::EIO,
+                };
+                send_error(&self.ch, unique, errno);
+                return Err(InvalidRequestError::new(ll::peek_header(self.data), err));
+            }
+        };
+
+        let res = match self.dispatch_req(op, se) {
             Ok(Some(resp)) => resp,
-            Ok(None) => return,
+            Ok(None) => return Ok(()),
             Err(errno) => self.request.reply_err(errno),
         }
         .with_iovec(unique, |iov| self.ch.send(iov));
@@ -63,13 +99,14 @@ impl<'a> Request<'a> {
         if let Err(err) = res {
             warn!("Request {:?}: Failed to send reply: {}", unique, err)
         }
+        Ok(())
     }
 
-    fn dispatch_req<FS: Filesystem>(
+    fn dispatch_req<FS: Filesystem, T: Transport>(
         &self,
-        se: &mut Session<FS>,
+        op: ll::Operation<'a>,
+        se: &mut Session<FS, T>,
     ) -> Result<Option<Response>, Errno> {
-        let op = self.request.operation().map_err(|_| Errno::ENOSYS)?;
         // Implement allow_root & access check for auto_unmount
         if (se.allowed == SessionACL::RootAndOwner
             && self.request.uid() != se.session_owner
@@ -144,6 +181,10 @@ impl<'a> Request<'a> {
                 let v = x.version();
                 if v < ll::Version(7, 6) {
                     error!("Unsupported FUSE ABI version {}", v);
+                    se.init_error = Some(SessionError::UnsupportedProtocol {
+                        major: v.major(),
+                        minor: v.minor(),
+                    });
                     return Err(Errno::EPROTO);
                 }
                 // Remember ABI version supported by kernel
@@ -152,9 +193,10 @@ impl<'a> Request<'a> {
 
                 let mut config = KernelConfig::new(x.capabilities(), x.max_readahead());
                 // Call filesystem init method and give it a chance to return an error
-                se.filesystem
-                    .init(self, &mut config)
-                    .map_err(Errno::from_i32)?;
+                if let Err(errno) = se.filesystem.init(self, &mut config) {
+                    se.init_error = Some(SessionError::InitFailed(errno));
+                    return Err(Errno::from_i32(errno));
+                }
 
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
@@ -167,6 +209,8 @@ impl<'a> Request<'a> {
                     config.max_readahead,
                     config.max_write
                 );
+                se.connection =
+                    Some(config.connection_info(v.major(), v.minor(), x.capabilities()));
                 se.initialized = true;
                 return Ok(Some(x.reply(&config)));
             }
@@ -588,6 +632,29 @@ impl<'a> Request<'a> {
                     self.reply(),
                 );
             }
+            #[cfg(feature = "abi-7-31")]
+            ll::Operation::SetupMapping(x) => {
+                se.filesystem.setupmapping(
+                    self,
+                    self.request.nodeid().into(),
+                    x.file_handle().into(),
+                    x.file_offset(),
+                    x.len(),
+                    x.flags(),
+                    x.window_offset(),
+                    self.reply(),
+                );
+            }
+            #[cfg(feature = "abi-7-31")]
+            ll::Operation::RemoveMapping(x) => {
+                let mappings: Vec<_> = x.mappings().collect();
+                se.filesystem.removemapping(
+                    self,
+                    self.request.nodeid().into(),
+                    &mappings,
+                    self.reply(),
+                );
+            }
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName(x) => {
                 se.filesystem.setvolname(self, x.name(), self.reply());
@@ -648,4 +715,88 @@ impl<'a> Request<'a> {
     pub fn pid(&self) -> u32 {
         self.request.pid()
     }
+
+    /// Returns a reference to `part` of this request, such as the `data` argument of
+    /// `Filesystem::write` or a name, that can be moved to another thread without copying it.
+    /// Returns `None` if `part` is not part of the request.
+    pub fn share(&self, part: &[u8]) -> Option<RequestData> {
+        self.buffer.share(part)
+    }
+
+    /// Returns an owned version of this request, which keeps its buffer and can be moved to
+    /// another thread along with the reply
+    pub fn to_owned_request(&self) -> OwnedRequest {
+        OwnedRequest {
+            data: self.buffer.clone(),
+            unique: self.unique(),
+            nodeid: self.request.nodeid().into(),
+            uid: self.uid(),
+            gid: self.gid(),
+            pid: self.pid(),
+        }
+    }
+}
+
+/// A request that owns the buffer it was received into, see `Request::to_owned_request`
+///
+/// The session loop receives the next requests into other buffers while it is kept, so several
+/// requests can be processed at the same time.
+#[derive(Clone, Debug)]
+pub struct OwnedRequest {
+    data: RequestData,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+}
+
+impl OwnedRequest {
+    /// Returns the unique identifier of this request
+    pub fn unique(&self) -> u64 {
+        self.unique
+    }
+
+    /// Returns the node id of the inode this request is targeted to
+    pub fn nodeid(&self) -> u64 {
+        self.nodeid
+    }
+
+    /// Returns the uid of this request
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Returns the gid of this request
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Returns the pid of this request
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Raw data of the request, as received from the kernel
+    pub fn data(&self) -> &RequestData {
+        &self.data
+    }
+
+    /// Returns a reference to `part` of this request, see `Request::share`
+    pub fn share(&self, part: &[u8]) -> Option<RequestData> {
+        self.data.share(part)
+    }
+
+    /// Parses the operation and its arguments from the data of this request again
+    pub fn operation(&self) -> Result<ll::Operation<'_>, RequestError> {
+        ll::AnyRequest::try_from(&self.data[..])?.operation()
+    }
+}
+
+/// Reply to a request with an error, without going through a parsed request
+fn send_error(ch: &SharedSender, unique: ll::RequestId, errno: Errno) {
+    let res = Response::new_error(errno).with_iovec(unique, |iov| ch.send(iov));
+    if let Err(err) = res {
+        warn!("Request {:?}: Failed to send reply: {}", unique, err)
+    }
 }
@@ -0,0 +1,56 @@
+//! Notifying a service manager about the state of the filesystem, like `sd_notify(3)`
+//!
+//! The service manager passes the address of a unix datagram socket in `NOTIFY_SOCKET`. A
+//! leading `@` denotes an address in the abstract namespace.
+
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+/// Environment variable holding the address of the notification socket
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Send `state`, e.g. `"READY=1"`, to the service manager. Returns false without doing anything
+/// if the process wasn't started by a service manager that expects notifications.
+pub(crate) fn notify(state: &str) -> io::Result<bool> {
+    let address = match env::var_os(NOTIFY_SOCKET) {
+        Some(address) if !address.is_empty() => address,
+        _ => return Ok(false),
+    };
+    let socket = UnixDatagram::unbound()?;
+    match address.to_str().and_then(|x| x.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            let address = SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), &address)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notify_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        env::set_var(NOTIFY_SOCKET, &path);
+        let sent = notify("READY=1");
+        env::remove_var(NOTIFY_SOCKET);
+        assert!(sent.unwrap());
+        let mut buf = [0u8; 16];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        assert!(!notify("READY=1").unwrap());
+    }
+}
@@ -5,30 +5,301 @@
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
 //! for filesystem operations under its mount point.
 
-use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::{info, warn};
-use std::fmt;
+use libc::{c_int, EINTR, ENODEV, ENOENT};
+use log::{error, info, warn};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
-use std::{io, ops::DerefMut};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle, Scope, ScopedJoinHandle};
+use std::time::Duration;
+use std::{error, fmt};
 
-use crate::ll::fuse_abi as abi;
+use crate::buffer::BufferPool;
+use crate::handoff::{self, HandoffState, MountState};
+#[cfg(target_os = "linux")]
+use crate::io_uring::IoUringTransport;
+use crate::ll::{self, fuse_abi as abi, RequestError};
+#[cfg(target_os = "linux")]
+use crate::mounts;
 use crate::request::Request;
+use crate::sd_notify;
+use crate::signals::SignalHandlers;
 use crate::Filesystem;
 use crate::MountOption;
-use crate::{channel::Channel, mnt::Mount};
+use crate::{
+    channel::{Channel, SharedSender, Transport},
+    mnt::mount_options::check_block_device,
+    mnt::{fd_mountpoint, recover_stale_mount, Mount, MountError, ReleasedMount},
+};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on macOS
 /// and 128k on other systems.
 pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
-/// Size of the buffer for reading a request from the kernel. Since the kernel may send
-/// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+/// Space for the headers of a write request in addition to its data
+const BUFFER_HEADER_SIZE: usize = 4096;
 
-#[derive(Debug, Eq, PartialEq)]
+/// Size of the buffer for reading a request from the kernel, if max_write is not known. Since the
+/// kernel may send up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some
+/// extra space.
+pub(crate) const BUFFER_SIZE: usize = MAX_WRITE_SIZE + BUFFER_HEADER_SIZE;
+
+/// Buffers hold at least this much data besides the headers, so that extended attribute values
+/// up to the kernel's limit of 64 KiB fit into them even if max_write is smaller
+const MIN_BUFFER_DATA_SIZE: usize = 64 * 1024;
+
+/// `FUSE_MIN_READ_BUFFER`, the smallest buffer the kernel reads requests into
+const MIN_READ_BUFFER_SIZE: usize = 8192;
+
+/// Size of the headers of a write request (`fuse_in_header` and `fuse_write_in`)
+const WRITE_HEADER_SIZE: usize = 80;
+
+/// Size of the buffers needed for requests of a connection with the given max_write
+fn buffer_size(max_write: u32) -> usize {
+    std::cmp::max(max_write as usize, MIN_BUFFER_DATA_SIZE) + BUFFER_HEADER_SIZE
+}
+
+/// Smallest buffer the kernel reads requests into for a connection with the given max_write.
+/// Reading into a smaller one fails with `EINVAL`.
+fn min_buffer_size(max_write: u32) -> usize {
+    std::cmp::max(MIN_READ_BUFFER_SIZE, WRITE_HEADER_SIZE + max_write as usize)
+}
+
+/// Error that may occur while mounting a filesystem or running its session loop
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionError {
+    /// The filesystem could not be mounted
+    Mount(MountError),
+    /// The kernel offered a FUSE protocol version that is not supported
+    UnsupportedProtocol {
+        /// Major version offered by the kernel
+        major: u32,
+        /// Minor version offered by the kernel
+        minor: u32,
+    },
+    /// `Filesystem::init` failed with the contained error code
+    InitFailed(c_int),
+    /// The kernel sent a request that could not be parsed
+    InvalidRequest(InvalidRequestError),
+    /// Communication with the kernel driver failed
+    Io(io::Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::Mount(err) => write!(f, "{}", err),
+            SessionError::UnsupportedProtocol { major, minor } => {
+                write!(f, "Unsupported FUSE ABI version {}.{}", major, minor)
+            }
+            SessionError::InitFailed(errno) => write!(
+                f,
+                "Filesystem initialization failed: {}",
+                io::Error::from_raw_os_error(*errno)
+            ),
+            SessionError::InvalidRequest(err) => write!(f, "{}", err),
+            SessionError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SessionError::Mount(err) => Some(err),
+            SessionError::InvalidRequest(err) => Some(err),
+            SessionError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<MountError> for SessionError {
+    fn from(err: MountError) -> Self {
+        SessionError::Mount(err)
+    }
+}
+
+impl From<InvalidRequestError> for SessionError {
+    fn from(err: InvalidRequestError) -> Self {
+        SessionError::InvalidRequest(err)
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        SessionError::Io(err)
+    }
+}
+
+impl From<SessionError> for io::Error {
+    fn from(err: SessionError) -> Self {
+        match err {
+            SessionError::Mount(err) => err.into(),
+            SessionError::Io(err) => err,
+            SessionError::InvalidRequest(_) => io::Error::new(io::ErrorKind::InvalidData, err),
+            _ => io::Error::new(io::ErrorKind::Other, err),
+        }
+    }
+}
+
+/// Error returned from the session loop when the kernel sent a request that could not be parsed
+#[derive(Debug)]
+pub struct InvalidRequestError {
+    unique: Option<u64>,
+    opcode: Option<u32>,
+    error: RequestError,
+}
+
+impl InvalidRequestError {
+    pub(crate) fn new(header: Option<(ll::RequestId, u32)>, error: RequestError) -> Self {
+        Self {
+            unique: header.map(|(unique, _)| unique.into()),
+            opcode: header.map(|(_, opcode)| opcode),
+            error,
+        }
+    }
+
+    /// Unique id of the request, if its header could be read
+    pub fn unique(&self) -> Option<u64> {
+        self.unique
+    }
+
+    /// Opcode of the request, if its header could be read
+    pub fn opcode(&self) -> Option<u32> {
+        self.opcode
+    }
+
+    /// The reason why the request could not be parsed
+    pub fn error(&self) -> &RequestError {
+        &self.error
+    }
+}
+
+impl fmt::Display for InvalidRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.unique, self.opcode) {
+            (Some(unique), Some(opcode)) => write!(
+                f,
+                "Invalid FUSE request {} (opcode {}): {}",
+                unique, opcode, self.error
+            ),
+            _ => write!(f, "Invalid FUSE request: {}", self.error),
+        }
+    }
+}
+
+impl error::Error for InvalidRequestError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// What the session loop does when the kernel sends a request that could not be parsed
+///
+/// Whenever the request header is readable, the request is answered with `EIO` (or `ENOSYS`
+/// for unknown opcodes) before the policy is applied, so the calling process is never left
+/// waiting for a reply. Requests that the kernel expects no reply to, like FORGET, are not
+/// answered. Unknown opcodes never stop the session, since newer kernels may send
+/// operations this version of fuser doesn't know about.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum InvalidRequestPolicy {
+    /// Stop the session loop and return the error from `Session::run`
+    #[default]
+    Abort,
+    /// Log the error and keep processing requests
+    Continue,
+}
+
+/// What `Session::process_next` did
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Processed {
+    /// A request was received and dispatched to the filesystem
+    Request,
+    /// Receiving was interrupted by a signal, or the kernel withdrew the request because it was
+    /// interrupted. Other requests may be available.
+    Interrupted,
+    /// The filesystem was unmounted, no more requests will arrive
+    Unmounted,
+}
+
+/// Parameters of the connection to the kernel, as negotiated during INIT
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// FUSE protocol major version of the kernel
+    pub proto_major: u32,
+    /// FUSE protocol minor version of the kernel
+    pub proto_minor: u32,
+    /// Capability flags (`FUSE_*` in `consts`) that are enabled for this connection
+    pub flags: u32,
+    /// Maximum readahead size
+    pub max_readahead: u32,
+    /// Maximum size of a single write request
+    pub max_write: u32,
+    /// Maximum number of pending background requests (0 without `abi-7-13`)
+    pub max_background: u16,
+    /// Number of background requests at which the kernel considers the queue congested
+    /// (0 without `abi-7-13`)
+    pub congestion_threshold: u16,
+}
+
+/// Whether a session is ready to serve requests
+#[derive(Clone, Copy, Debug, Default)]
+enum ReadyState {
+    /// INIT was not processed yet
+    #[default]
+    Pending,
+    /// INIT was processed
+    Ready(ConnectionInfo),
+    /// The session loop ended without processing INIT
+    Ended,
+}
+
+/// Readiness of a session, shared with the threads waiting for it
+#[derive(Debug, Default)]
+struct Readiness {
+    state: Mutex<ReadyState>,
+    changed: Condvar,
+}
+
+impl Readiness {
+    fn set(&self, state: ReadyState) {
+        let mut current = self.state.lock().unwrap();
+        // Once ready, a session stays ready
+        if !matches!(*current, ReadyState::Ready(_)) {
+            *current = state;
+            self.changed.notify_all();
+        }
+    }
+
+    fn wait(&self, timeout: Duration) -> io::Result<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |x| matches!(x, ReadyState::Pending))
+            .unwrap();
+        match *state {
+            ReadyState::Ready(connection) => Ok(connection),
+            ReadyState::Pending => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for the filesystem to be initialized",
+            )),
+            ReadyState::Ended => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Session ended before the filesystem was initialized",
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub(crate) enum SessionACL {
     All,
     RootAndOwner,
@@ -36,12 +307,17 @@ pub(crate) enum SessionACL {
 }
 
 /// The session data structure
+///
+/// A session usually communicates with the kernel through the FUSE device (`Channel`), but it
+/// may serve requests received over any `Transport`, see `Session::with_transport`.
 #[derive(Debug)]
-pub struct Session<FS: Filesystem> {
+pub struct Session<FS: Filesystem, T: Transport = Channel> {
     /// Filesystem operation implementations
     pub(crate) filesystem: FS,
     /// Communication channel to the kernel driver
-    ch: Channel,
+    ch: T,
+    /// Sends replies over `ch`
+    sender: SharedSender,
     /// Handle to the mount.  Dropping this unmounts.
     mount: Arc<Mutex<Option<Mount>>>,
     /// Mount point
@@ -57,54 +333,307 @@ pub struct Session<FS: Filesystem> {
     pub(crate) proto_minor: u32,
     /// True if the filesystem is initialized (init operation done)
     pub(crate) initialized: bool,
+    /// Connection parameters negotiated during INIT
+    pub(crate) connection: Option<ConnectionInfo>,
     /// True if the filesystem was destroyed (destroy operation done)
     pub(crate) destroyed: bool,
+    /// Set if the INIT request failed, so the session loop can report why
+    pub(crate) init_error: Option<SessionError>,
+    /// What to do with requests that could not be parsed
+    invalid_request_policy: InvalidRequestPolicy,
+    /// Number of requests that could not be parsed
+    invalid_requests: u64,
+    /// Becomes readable when the session loop should stop
+    stop: Option<UnixStream>,
+    /// Signals received while signal handlers are installed, one byte per signal
+    signals: Option<UnixStream>,
+    /// Signal that stopped the session loop
+    exit_signal: Option<c_int>,
+    /// Buffers that requests are received into
+    buffers: BufferPool,
+    /// Size of the buffers set with `set_buffer_size`, instead of the one derived from max_write
+    buffer_size: Option<usize>,
+    /// Whether the session loop was told that the filesystem is usable
+    was_ready: bool,
+    /// Whether the FUSE device is in non-blocking mode, so the session loop has to wait for it
+    nonblocking: bool,
+    /// Tells a `BackgroundSession` when INIT was processed
+    readiness: Arc<Readiness>,
+    /// Whether the service manager is notified once INIT was processed
+    notify_ready: bool,
+}
+
+/// Mount a FUSE filesystem, and determine the access control the session has to enforce
+pub(crate) fn mount(
+    mountpoint: &Path,
+    options: &[MountOption],
+) -> Result<(Arc<File>, Mount, SessionACL), MountError> {
+    info!("Mounting {}", mountpoint.display());
+    if options.contains(&MountOption::RecoverStale) {
+        recover_stale_mount(mountpoint)?;
+    }
+    check_block_device(options)?;
+    // If AutoUnmount is requested, but not AllowRoot or AllowOther we enforce the ACL
+    // ourself and implicitly set AllowOther because fusermount needs allow_root or allow_other
+    // to handle the auto_unmount option
+    let (file, mount) = if options.contains(&MountOption::AutoUnmount)
+        && !(options.contains(&MountOption::AllowRoot)
+            || options.contains(&MountOption::AllowOther))
+    {
+        warn!("Given auto_unmount without allow_root or allow_other; adding allow_other, with userspace permission handling");
+        let mut modified_options = options.to_vec();
+        modified_options.push(MountOption::AllowOther);
+        Mount::new(mountpoint, &modified_options)?
+    } else {
+        Mount::new(mountpoint, options)?
+    };
+
+    let allowed = if options.contains(&MountOption::AllowRoot) {
+        SessionACL::RootAndOwner
+    } else if options.contains(&MountOption::AllowOther) {
+        SessionACL::All
+    } else {
+        SessionACL::Owner
+    };
+    Ok((file, mount, allowed))
+}
+
+/// Duplicates the FUSE device that the caller passed with libfuse's `/dev/fd/N` mountpoint
+/// convention, and determines where it is mounted, if possible
+fn passed_device(mountpoint: &Path) -> io::Result<Option<(OwnedFd, PathBuf)>> {
+    let fd = match fd_mountpoint(mountpoint) {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    // The caller owns the descriptor and keeps it open while we duplicate it
+    let device = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    #[cfg(target_os = "linux")]
+    let mounted_at = match mounts::Connection::of_device(device.as_fd())? {
+        Some(connection) => mounts::list()?
+            .into_iter()
+            .find(|x| x.connection == connection)
+            .map(|x| x.mountpoint),
+        None => None,
+    };
+    #[cfg(not(target_os = "linux"))]
+    let mounted_at = None;
+    let mountpoint = mounted_at.unwrap_or_else(|| mountpoint.to_owned());
+    info!("Using already mounted FUSE device {}", mountpoint.display());
+    Ok(Some((device, mountpoint)))
 }
 
 impl<FS: Filesystem> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
+    ///
+    /// Following libfuse's convention, a mountpoint of `/dev/fd/N` means that the caller already
+    /// mounted the filesystem and passes the open FUSE device N, which is used like with
+    /// `from_fd`. The device is duplicated, so the caller keeps owning N.
     pub fn new(
         filesystem: FS,
         mountpoint: &Path,
         options: &[MountOption],
-    ) -> io::Result<Session<FS>> {
-        info!("Mounting {}", mountpoint.display());
-        // If AutoUnmount is requested, but not AllowRoot or AllowOther we enforce the ACL
-        // ourself and implicitly set AllowOther because fusermount needs allow_root or allow_other
-        // to handle the auto_unmount option
-        let (file, mount) = if options.contains(&MountOption::AutoUnmount)
-            && !(options.contains(&MountOption::AllowRoot)
-                || options.contains(&MountOption::AllowOther))
-        {
-            warn!("Given auto_unmount without allow_root or allow_other; adding allow_other, with userspace permission handling");
-            let mut modified_options = options.to_vec();
-            modified_options.push(MountOption::AllowOther);
-            Mount::new(mountpoint, &modified_options)?
-        } else {
-            Mount::new(mountpoint, options)?
+    ) -> Result<Session<FS>, MountError> {
+        if let Some((fd, mountpoint)) = passed_device(mountpoint)? {
+            return Ok(Session::from_fd(filesystem, fd, &mountpoint));
+        }
+        let (file, mount, allowed) = mount(mountpoint, options)?;
+        Ok(Session::from_parts(
+            filesystem,
+            file,
+            Some(mount),
+            mountpoint,
+            allowed,
+        ))
+    }
+
+    /// Create a new session from an already open and mounted FUSE device, e.g. one that was
+    /// mounted by a privileged process and handed over to this one.
+    ///
+    /// The session does not own the mount: dropping it or calling `unmount` won't unmount the
+    /// filesystem, that is up to whoever mounted it. Access control is left to the kernel, i.e.
+    /// to the `allow_other` option the filesystem was mounted with.
+    pub fn from_fd(filesystem: FS, fd: OwnedFd, mountpoint: &Path) -> Session<FS> {
+        let file = Arc::new(File::from(fd));
+        Session::from_parts(filesystem, file, None, mountpoint, SessionACL::All)
+    }
+
+    /// Resume a session that another process handed over with `hand_off` on `socket`.
+    ///
+    /// If the other process already processed INIT, the kernel won't send it again. In this case
+    /// `Filesystem::init` is not called and `filesystem` must be ready to serve requests.
+    ///
+    /// If the other process owned the mount, the resumed session owns it now, and unmounts it when
+    /// it is dropped or `unmount` is called.
+    pub fn resume(filesystem: FS, socket: &UnixStream) -> io::Result<Session<FS>> {
+        let (file, auto_unmount, state) = handoff::receive(socket)?;
+        info!("Resuming session at {}", state.mountpoint.display());
+        let file = Arc::new(file);
+        let mount = match state.mount {
+            Some(mount) => {
+                let released = ReleasedMount {
+                    mountpoint: mount.mountpoint,
+                    auto_unmount: mount.auto_unmount.zip(auto_unmount),
+                };
+                Some(Mount::take_over(released, file.clone())?)
+            }
+            None => None,
+        };
+        let mut session =
+            Session::from_parts(filesystem, file, mount, &state.mountpoint, state.allowed);
+        session.session_owner = state.session_owner;
+        if let Some(connection) = state.connection {
+            session.proto_major = connection.proto_major;
+            session.proto_minor = connection.proto_minor;
+            session.connection = Some(connection);
+            session.initialized = true;
+        }
+        Ok(session)
+    }
+
+    /// Hand this session over to another process, which picks it up with `resume`.
+    ///
+    /// The session loop must have been stopped with a `SessionStopper` (or never started). The
+    /// filesystem stays mounted and `Filesystem::destroy` is not called. Replies to requests that
+    /// are still being processed by this process may be sent until it exits.
+    ///
+    /// If this session owns the mount, it is handed over as well, including the auto-unmount of
+    /// `MountOption::AutoUnmount`, which then triggers once the receiving process exits. With the
+    /// libfuse backends, the auto-unmount can't be handed over and triggers once this process
+    /// exits.
+    pub fn hand_off(mut self, socket: &UnixStream) -> io::Result<()> {
+        // Released rather than dropped, so the filesystem stays mounted
+        let released = self.mount.lock().unwrap().take().map(Mount::release);
+        let mount = released.as_ref().map(|x| MountState {
+            mountpoint: x.mountpoint.clone(),
+            auto_unmount: x.auto_unmount.as_ref().map(|(kind, _)| *kind),
+        });
+        let state = HandoffState {
+            mountpoint: self.mountpoint.clone(),
+            allowed: self.allowed,
+            session_owner: self.session_owner,
+            connection: self.connection,
+            mount,
         };
+        let auto_unmount = released
+            .as_ref()
+            .and_then(|x| x.auto_unmount.as_ref())
+            .map(|(_, socket)| socket.as_raw_fd());
+        let fd = self.ch.as_fd().as_raw_fd();
+        if let Err(err) = handoff::send(socket, fd, auto_unmount, &state) {
+            // Keep owning the mount
+            if let Some(released) = released {
+                let mount = Mount::take_over(released, self.ch.file().clone())?;
+                *self.mount.lock().unwrap() = Some(mount);
+            }
+            return Err(err);
+        }
+        info!("Handed off session at {}", self.mountpoint.display());
+        self.destroyed = true;
+        Ok(())
+    }
 
-        let ch = Channel::new(file);
-        let allowed = if options.contains(&MountOption::AllowRoot) {
-            SessionACL::RootAndOwner
-        } else if options.contains(&MountOption::AllowOther) {
-            SessionACL::All
+    /// Put the FUSE device into non-blocking mode, so `process_next` fails with
+    /// `io::ErrorKind::WouldBlock` instead of waiting for a request. Sessions sharing the device,
+    /// e.g. through `from_fd`, are affected as well.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.ch.as_fd().as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
         } else {
-            SessionACL::Owner
+            flags & !libc::O_NONBLOCK
         };
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    pub(crate) fn from_parts(
+        filesystem: FS,
+        file: Arc<File>,
+        mount: Option<Mount>,
+        mountpoint: &Path,
+        allowed: SessionACL,
+    ) -> Session<FS> {
+        Session::from_transport(filesystem, Channel::new(file), mount, mountpoint, allowed)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<FS: Filesystem> Session<FS, IoUringTransport> {
+    /// Create a new session like `new`, which receives requests and sends replies over io_uring
+    /// if the kernel supports FUSE-over-io_uring (Linux 6.14 with the `enable_uring` parameter
+    /// of the fuse module set), and over the FUSE device otherwise
+    pub fn new_io_uring(
+        filesystem: FS,
+        mountpoint: &Path,
+        options: &[MountOption],
+    ) -> Result<Session<FS, IoUringTransport>, MountError> {
+        if let Some((fd, mountpoint)) = passed_device(mountpoint)? {
+            let transport = IoUringTransport::from_fd(fd)?;
+            return Ok(Session::with_transport(filesystem, transport, &mountpoint));
+        }
+        let (file, mount, allowed) = mount(mountpoint, options)?;
+        let transport = IoUringTransport::new(file)?;
+        Ok(Session::from_transport(
+            filesystem,
+            transport,
+            Some(mount),
+            mountpoint,
+            allowed,
+        ))
+    }
+}
+
+impl<FS: Filesystem, T: Transport> Session<FS, T> {
+    /// Create a new session that serves the requests received over `transport`, e.g. a socket
+    /// connected to a process that forwards the requests of a FUSE device, or an in-memory queue
+    /// for testing. `mountpoint` is only used to describe the session.
+    ///
+    /// Like with `from_fd`, the session does not own a mount, and access control is left to
+    /// whoever sends the requests.
+    pub fn with_transport(filesystem: FS, transport: T, mountpoint: &Path) -> Session<FS, T> {
+        Session::from_transport(filesystem, transport, None, mountpoint, SessionACL::All)
+    }
 
-        Ok(Session {
+    fn from_transport(
+        filesystem: FS,
+        transport: T,
+        mount: Option<Mount>,
+        mountpoint: &Path,
+        allowed: SessionACL,
+    ) -> Session<FS, T> {
+        Session {
             filesystem,
-            ch,
-            mount: Arc::new(Mutex::new(Some(mount))),
+            sender: SharedSender::new(transport.sender()),
+            ch: transport,
+            mount: Arc::new(Mutex::new(mount)),
             mountpoint: mountpoint.to_owned(),
             allowed,
             session_owner: unsafe { libc::geteuid() },
             proto_major: 0,
             proto_minor: 0,
             initialized: false,
+            connection: None,
             destroyed: false,
-        })
+            init_error: None,
+            invalid_request_policy: InvalidRequestPolicy::default(),
+            invalid_requests: 0,
+            stop: None,
+            signals: None,
+            exit_signal: None,
+            buffers: BufferPool::new(BUFFER_SIZE, std::mem::align_of::<abi::fuse_in_header>()),
+            buffer_size: None,
+            was_ready: false,
+            nonblocking: false,
+            readiness: Arc::new(Readiness::default()),
+            notify_ready: false,
+        }
     }
 
     /// Return path of the mounted filesystem
@@ -112,45 +641,259 @@ impl<FS: Filesystem> Session<FS> {
         &self.mountpoint
     }
 
+    /// Connection of the mounted filesystem, which can be inspected and controlled through
+    /// /sys/fs/fuse/connections. It is the connection of the FUSE device the session receives
+    /// requests from. If the kernel doesn't report it, or the transport has no FUSE device, it is
+    /// looked up by `mountpoint()` instead, and fails with `NotFound` if no filesystem is mounted
+    /// there in the mount namespace of this process.
+    #[cfg(target_os = "linux")]
+    pub fn connection(&self) -> io::Result<mounts::Connection> {
+        if let Some(fd) = self.ch.fd() {
+            if let Some(connection) = mounts::Connection::of_device(fd)? {
+                return Ok(connection);
+            }
+        }
+        match mounts::find(&self.mountpoint)? {
+            Some(mount) => Ok(mount.connection),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No FUSE filesystem mounted at {}",
+                    self.mountpoint.display()
+                ),
+            )),
+        }
+    }
+
+    /// Set what the session loop does when the kernel sends a request that could not be parsed
+    pub fn set_invalid_request_policy(&mut self, policy: InvalidRequestPolicy) {
+        self.invalid_request_policy = policy;
+    }
+
+    /// Number of requests received so far that could not be parsed
+    pub fn invalid_requests(&self) -> u64 {
+        self.invalid_requests
+    }
+
+    /// Connection parameters negotiated with the kernel, or `None` if INIT wasn't processed yet
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.connection
+    }
+
+    /// Send `READY=1` to the service manager (e.g. systemd with `Type=notify`) once INIT was
+    /// processed, if it passed a socket in `NOTIFY_SOCKET`
+    pub fn set_notify_ready(&mut self, enabled: bool) {
+        self.notify_ready = enabled;
+    }
+
+    /// Set the size of the buffers requests are received into. Requests that don't fit fail.
+    ///
+    /// By default, buffers fit write requests of the max_write negotiated during INIT. Sessions
+    /// that don't process INIT, e.g. those created with `from_fd`, use buffers that fit
+    /// `MAX_WRITE_SIZE`, unless a smaller size is set here.
+    ///
+    /// The kernel refuses to read requests into buffers smaller than 8 KiB, or than a write
+    /// request of max_write bytes, so the size is raised to that if needed. Without INIT, the
+    /// max_write of the connection isn't known, and the size has to fit it.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        let size = match self.connection {
+            Some(connection) => std::cmp::max(size, min_buffer_size(connection.max_write)),
+            None => std::cmp::max(size, MIN_READ_BUFFER_SIZE),
+        };
+        self.buffer_size = Some(size);
+        self.buffers.set_size(size);
+    }
+
+    /// Size of the buffers requests are received into
+    pub fn buffer_size(&self) -> usize {
+        self.buffers.size()
+    }
+
+    /// Set how many unused buffers are kept for receiving requests (4 by default). More buffers
+    /// are allocated while requests are kept by the filesystem, see `Request::to_owned_request`.
+    pub fn set_max_free_buffers(&mut self, count: usize) {
+        self.buffers.set_max_free(count);
+    }
+
+    /// Called once the filesystem is usable
+    fn ready(&mut self) {
+        if let Some(connection) = self.connection {
+            self.readiness.set(ReadyState::Ready(connection));
+            let size = match self.buffer_size {
+                Some(size) => std::cmp::max(size, min_buffer_size(connection.max_write)),
+                None => buffer_size(connection.max_write),
+            };
+            self.buffers.set_size(size);
+        }
+        if self.notify_ready {
+            match sd_notify::notify("READY=1") {
+                Ok(_) => {}
+                Err(err) => warn!("Failed to notify the service manager: {}", err),
+            }
+        }
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
-    /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
-    /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
-    pub fn run(&mut self) -> io::Result<()> {
-        // Buffer for receiving requests from the kernel. Only one is allocated and
-        // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer = vec![0; BUFFER_SIZE];
-        let buf = aligned_sub_buf(
-            buffer.deref_mut(),
-            std::mem::align_of::<abi::fuse_in_header>(),
-        );
+    /// calls into the filesystem. This read-dispatch-loop is non-concurrent and reuses its
+    /// buffer to conserve memory, but the filesystem methods may run concurrent by spawning
+    /// threads, see `Request::to_owned_request`.
+    pub fn run(&mut self) -> Result<(), SessionError> {
+        self.run_loop(false)
+    }
+
+    /// Run the session loop like `run`, with handlers for SIGINT, SIGTERM and SIGHUP installed,
+    /// and SIGPIPE ignored.
+    ///
+    /// On the first of these signals, the filesystem is unmounted, and requests are processed
+    /// until the kernel closed the connection. If the session doesn't own the mount (see
+    /// `from_fd`), the unmount fails, or a second signal arrives before the connection was
+    /// closed, the loop stops right away instead. Then `Filesystem::destroy` is called, and
+    /// the signal that stopped the loop is returned, or `None` if the filesystem was unmounted
+    /// by other means. The previous signal handlers are restored before returning.
+    ///
+    /// Only one session at a time can run with signal handlers. Like with `stop_callable`,
+    /// signals are only noticed between requests if the transport has no file descriptor to
+    /// poll.
+    pub fn run_with_signal_handlers(&mut self) -> Result<Option<c_int>, SessionError> {
+        let (handlers, signals) = SignalHandlers::install()?;
+        self.signals = Some(signals);
+        self.exit_signal = None;
+        let result = self.run();
+        self.signals = None;
+        drop(handlers);
+        result?;
+        if !self.destroyed {
+            self.filesystem.destroy();
+            self.destroyed = true;
+        }
+        Ok(self.exit_signal.take())
+    }
+
+    /// Process requests until the kernel's INIT request was processed, i.e. until the filesystem
+    /// is usable. Fails if the filesystem is unmounted before.
+    pub(crate) fn run_until_initialized(&mut self) -> Result<(), SessionError> {
+        self.run_loop(true)?;
+        if !self.initialized {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Filesystem was unmounted before it was initialized",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn run_loop(&mut self, until_initialized: bool) -> Result<(), SessionError> {
         loop {
-            // Read the next request from the given channel to kernel driver
-            // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(buf) {
-                Ok(size) => match Request::new(self.ch.sender(), &buf[..size]) {
-                    // Dispatch request
-                    Some(req) => req.dispatch(self),
-                    // Quit loop on illegal request
-                    None => break,
-                },
-                Err(err) => match err.raw_os_error() {
-                    // Operation interrupted. Accordingly to FUSE, this is safe to retry
-                    Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
-                    Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
-                    // Unhandled error
-                    _ => return Err(err),
-                },
+            self.check_ready();
+            if until_initialized && self.initialized {
+                break;
+            }
+            if self.stop.is_some() || self.signals.is_some() || self.nonblocking {
+                match wait_readable(self.ch.fd(), self.stop.as_ref(), self.signals.as_ref()) {
+                    Ok(Wakeup::Request) => {}
+                    Ok(Wakeup::Stop) => break,
+                    Ok(Wakeup::Signal(signal)) => {
+                        if self.exit_signal.is_some() {
+                            // The unmount is stuck, e.g. on a request that never finishes
+                            info!("Received signal {} again, stopping", signal);
+                            break;
+                        }
+                        self.exit_signal = Some(signal);
+                        let mount = match self.mount.lock().unwrap().take() {
+                            Some(mount) => mount,
+                            None => {
+                                info!("Received signal {}, stopping", signal);
+                                break;
+                            }
+                        };
+                        info!("Received signal {}, unmounting", signal);
+                        if let Err(err) = mount.unmount() {
+                            error!("Unmount failed: {}, stopping", err);
+                            break;
+                        }
+                        // Keep processing requests until the kernel closed the connection
+                        continue;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            match self.process_next() {
+                Ok(Processed::Unmounted) => break,
+                Ok(_) => {}
+                // Explicitly try again, or wait for the next request in non-blocking mode
+                Err(SessionError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
             }
         }
         Ok(())
     }
 
+    /// Receive and process a single request, for driving the session from an external event
+    /// loop instead of `run`. The loop should wait until the session's file descriptor is
+    /// readable (see `set_nonblocking`), and call this until it fails with
+    /// `io::ErrorKind::WouldBlock`.
+    ///
+    /// Errors are those of `run`. Once the filesystem was unmounted, `Processed::Unmounted` is
+    /// returned and the session should be dropped, which calls `Filesystem::destroy` if the
+    /// kernel didn't send DESTROY.
+    pub fn process_next(&mut self) -> Result<Processed, SessionError> {
+        self.check_ready();
+        // Read the next request from the given channel to kernel driver
+        // The kernel driver makes sure that we get exactly one request per read. The buffer
+        // is returned to the pool after dispatching, unless the filesystem kept the request.
+        let mut buffer = self.buffers.take();
+        match self.ch.receive(buffer.as_mut_slice()) {
+            Ok(size) => {
+                let data = buffer.into_request(size);
+                let res = Request::new(self.sender.clone(), &data)
+                    // Dispatch request
+                    .and_then(|req| req.dispatch(self));
+                if let Err(err) = res {
+                    self.handle_invalid_request(err)?;
+                }
+                // The kernel aborts the connection after a failed INIT
+                if let Some(err) = self.init_error.take() {
+                    return Err(err);
+                }
+                self.check_ready();
+                Ok(Processed::Request)
+            }
+            Err(err) => match err.raw_os_error() {
+                // Operation interrupted. Accordingly to FUSE, this is safe to retry
+                Some(ENOENT) => Ok(Processed::Interrupted),
+                // Interrupted system call, retry
+                Some(EINTR) => Ok(Processed::Interrupted),
+                // Filesystem was unmounted
+                Some(ENODEV) => Ok(Processed::Unmounted),
+                // No request available (EAGAIN), or unhandled error
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    /// Calls `ready` once the filesystem was initialized
+    fn check_ready(&mut self) {
+        if self.initialized && !self.was_ready {
+            self.was_ready = true;
+            self.ready();
+        }
+    }
+
+    /// Count a request that could not be parsed and decide whether the session loop goes on
+    fn handle_invalid_request(&mut self, err: InvalidRequestError) -> Result<(), SessionError> {
+        self.invalid_requests += 1;
+        let unknown_operation = matches!(err.error(), RequestError::UnknownOperation(_));
+        if unknown_operation || self.invalid_request_policy == InvalidRequestPolicy::Continue {
+            warn!("{}", err);
+            Ok(())
+        } else {
+            error!("{}", err);
+            Err(err.into())
+        }
+    }
+
     /// Unmount the filesystem
     pub fn unmount(&mut self) {
         drop(std::mem::take(&mut *self.mount.lock().unwrap()));
@@ -158,9 +901,77 @@ impl<FS: Filesystem> Session<FS> {
 
     /// Returns a thread-safe object that can be used to unmount the Filesystem
     pub fn unmount_callable(&mut self) -> SessionUnmounter {
-        SessionUnmounter {
-            mount: self.mount.clone(),
-        }
+        SessionUnmounter::new(self.mount.clone())
+    }
+
+    /// Returns a thread-safe object that can be used to stop the session loop without
+    /// unmounting the filesystem, e.g. to hand the session over to another process. Once
+    /// stopped, `run` returns `Ok(())` and may be called again.
+    ///
+    /// If the transport has no file descriptor to poll (see `Transport::fd`), the loop only
+    /// stops after the next request was received and processed.
+    pub fn stop_callable(&mut self) -> io::Result<SessionStopper> {
+        let (sender, receiver) = UnixStream::pair()?;
+        self.stop = Some(receiver);
+        Ok(SessionStopper { sender })
+    }
+}
+
+/// Why `wait_readable` returned
+enum Wakeup {
+    /// The channel has a request to read
+    Request,
+    /// The session loop is asked to stop
+    Stop,
+    /// A signal was received
+    Signal(c_int),
+}
+
+/// Waits until the channel has a request to read, the session loop is asked to stop, or a
+/// signal was received. Channels that can't be polled are assumed to have a request, so for them
+/// stop requests and signals are only checked before the next (blocking) receive.
+fn wait_readable(
+    ch: Option<BorrowedFd<'_>>,
+    stop: Option<&UnixStream>,
+    signals: Option<&UnixStream>,
+) -> io::Result<Wakeup> {
+    let pollfd = |fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let mut fds = [
+        pollfd(ch.map_or(-1, |x| x.as_raw_fd())),
+        pollfd(stop.map_or(-1, AsRawFd::as_raw_fd)),
+        pollfd(signals.map_or(-1, AsRawFd::as_raw_fd)),
+    ];
+    let timeout = if ch.is_some() { -1 } else { 0 };
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = [0u8];
+    if let (Some(signals), true) = (signals, fds[2].revents != 0) {
+        (&*signals).read_exact(&mut buf)?;
+        return Ok(Wakeup::Signal(buf[0] as c_int));
+    }
+    if let (Some(stop), true) = (stop, fds[1].revents != 0) {
+        // Consume the stop request, so the session loop can be run again
+        let _ = (&*stop).read(&mut buf);
+        return Ok(Wakeup::Stop);
+    }
+    Ok(Wakeup::Request)
+}
+
+#[derive(Debug)]
+/// A thread-safe object that can be used to stop a session loop without unmounting
+pub struct SessionStopper {
+    sender: UnixStream,
+}
+
+impl SessionStopper {
+    /// Make the session loop return once it finished processing the current request
+    pub fn stop(&self) -> io::Result<()> {
+        (&self.sender).write_all(&[0])
     }
 }
 
@@ -171,14 +982,20 @@ pub struct SessionUnmounter {
 }
 
 impl SessionUnmounter {
+    pub(crate) fn new(mount: Arc<Mutex<Option<Mount>>>) -> SessionUnmounter {
+        SessionUnmounter { mount }
+    }
+
     /// Unmount the filesystem
     pub fn unmount(&mut self) -> io::Result<()> {
-        drop(std::mem::take(&mut *self.mount.lock().unwrap()));
-        Ok(())
+        match self.mount.lock().unwrap().take() {
+            Some(mount) => mount.unmount(),
+            None => Ok(()),
+        }
     }
 }
 
-fn aligned_sub_buf(buf: &mut [u8], alignment: usize) -> &mut [u8] {
+pub(crate) fn aligned_sub_buf(buf: &mut [u8], alignment: usize) -> &mut [u8] {
     let off = alignment - (buf.as_ptr() as usize) % alignment;
     if off == alignment {
         buf
@@ -187,14 +1004,40 @@ fn aligned_sub_buf(buf: &mut [u8], alignment: usize) -> &mut [u8] {
     }
 }
 
-impl<FS: 'static + Filesystem + Send> Session<FS> {
+impl<FS: Filesystem> AsRawFd for Session<FS> {
+    /// Returns the FUSE device of this session, which becomes readable when a request is
+    /// available, e.g. to register it with an event loop that calls `process_next`
+    fn as_raw_fd(&self) -> RawFd {
+        self.ch.as_fd().as_raw_fd()
+    }
+}
+
+impl<FS: Filesystem> AsFd for Session<FS> {
+    /// Returns the FUSE device of this session, e.g. to hand it over to another process
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ch.as_fd()
+    }
+}
+
+impl<FS: 'static + Filesystem + Send, T: 'static + Transport + Send> Session<FS, T> {
     /// Run the session loop in a background thread
-    pub fn spawn(self) -> io::Result<BackgroundSession> {
+    pub fn spawn(self) -> Result<BackgroundSession, MountError> {
         BackgroundSession::new(self)
     }
 }
 
-impl<FS: Filesystem> Drop for Session<FS> {
+impl<'scope, FS: 'scope + Filesystem + Send, T: 'scope + Transport + Send> Session<FS, T> {
+    /// Run the session loop in a thread of the given scope, so that the filesystem may borrow
+    /// from outside of it
+    pub fn spawn_scoped<'env>(
+        self,
+        scope: &'scope Scope<'scope, 'env>,
+    ) -> Result<ScopedBackgroundSession<'scope>, MountError> {
+        ScopedBackgroundSession::new(scope, self)
+    }
+}
+
+impl<FS: Filesystem, T: Transport> Drop for Session<FS, T> {
     fn drop(&mut self) {
         if !self.destroyed {
             self.filesystem.destroy();
@@ -209,44 +1052,146 @@ pub struct BackgroundSession {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
     /// Thread guard of the background session
-    pub guard: JoinHandle<io::Result<()>>,
+    pub guard: JoinHandle<Result<(), SessionError>>,
     /// Ensures the filesystem is unmounted when the session ends
-    _mount: Mount,
+    _mount: Option<Mount>,
+    /// Set once the session processed INIT, or ended
+    readiness: Arc<Readiness>,
 }
 
 impl BackgroundSession {
     /// Create a new background session for the given session by running its
     /// session loop in a background thread. If the returned handle is dropped,
     /// the filesystem is unmounted and the given session ends.
-    pub fn new<FS: Filesystem + Send + 'static>(
-        mut se: Session<FS>,
-    ) -> io::Result<BackgroundSession> {
+    pub fn new<FS: Filesystem + Send + 'static, T: Transport + Send + 'static>(
+        mut se: Session<FS, T>,
+    ) -> Result<BackgroundSession, MountError> {
         let mountpoint = se.mountpoint().to_path_buf();
-        // Take the fuse_session, so that we can unmount it
+        // Take the fuse_session, so that we can unmount it. Sessions created from an already
+        // mounted FUSE device don't have one.
         let mount = std::mem::take(&mut *se.mount.lock().unwrap());
-        let mount = mount.ok_or_else(|| io::Error::from_raw_os_error(libc::ENODEV))?;
+        let readiness = se.readiness.clone();
         let guard = thread::spawn(move || {
             let mut se = se;
-            se.run()
+            let result = se.run();
+            se.readiness.set(ReadyState::Ended);
+            result
         });
         Ok(BackgroundSession {
             mountpoint,
             guard,
             _mount: mount,
+            readiness,
         })
     }
+
+    /// Wait until the kernel's INIT request was processed, i.e. until the filesystem is usable,
+    /// and return the negotiated connection parameters. Fails with `TimedOut` if that takes
+    /// longer than `timeout`, or with `NotConnected` if the session ended before.
+    pub fn wait_ready(&self, timeout: Duration) -> io::Result<ConnectionInfo> {
+        self.readiness.wait(timeout)
+    }
     /// Unmount the filesystem and join the background thread.
     pub fn join(self) {
         let Self {
             mountpoint: _,
             guard,
             _mount,
+            readiness: _,
         } = self;
         drop(_mount);
         guard.join().unwrap().unwrap();
     }
 }
 
+/// A background session running on a scoped thread, see `std::thread::scope`
+///
+/// Dropping the handle unmounts the filesystem if the session owns the mount, and stops the
+/// session loop. The handle can't escape the scope, so the filesystem is unmounted and its
+/// thread joined before the scope returns. If the handle is leaked instead, the scope waits
+/// until the filesystem is unmounted externally.
+pub struct ScopedBackgroundSession<'scope> {
+    /// Path of the mounted filesystem
+    pub mountpoint: PathBuf,
+    /// Thread guard of the background session
+    pub guard: ScopedJoinHandle<'scope, Result<(), SessionError>>,
+    /// Ensures the filesystem is unmounted when the session ends
+    _mount: Option<Mount>,
+    /// Ends the session loop also if the session doesn't own the mount. Dropped after the
+    /// mount, so that the loop can process the requests of unmounting.
+    _stop: StopOnDrop,
+    /// Set once the session processed INIT, or ended
+    readiness: Arc<Readiness>,
+}
+
+impl<'scope> ScopedBackgroundSession<'scope> {
+    /// Create a new background session for the given session by running its session loop in a
+    /// thread of `scope`. If the returned handle is dropped, the filesystem is unmounted and the
+    /// given session ends. This replaces a `SessionStopper` of the session.
+    pub fn new<'env, FS: Filesystem + Send + 'scope, T: Transport + Send + 'scope>(
+        scope: &'scope Scope<'scope, 'env>,
+        mut se: Session<FS, T>,
+    ) -> Result<ScopedBackgroundSession<'scope>, MountError> {
+        let mountpoint = se.mountpoint().to_path_buf();
+        let stop = StopOnDrop(se.stop_callable()?);
+        let mount = std::mem::take(&mut *se.mount.lock().unwrap());
+        let readiness = se.readiness.clone();
+        let guard = scope.spawn(move || {
+            let mut se = se;
+            let result = se.run();
+            se.readiness.set(ReadyState::Ended);
+            result
+        });
+        Ok(ScopedBackgroundSession {
+            mountpoint,
+            guard,
+            _mount: mount,
+            _stop: stop,
+            readiness,
+        })
+    }
+
+    /// Wait until the kernel's INIT request was processed, see `BackgroundSession::wait_ready`
+    pub fn wait_ready(&self, timeout: Duration) -> io::Result<ConnectionInfo> {
+        self.readiness.wait(timeout)
+    }
+
+    /// Unmount the filesystem, stop the session loop and join the background thread.
+    pub fn join(self) {
+        let Self {
+            mountpoint: _,
+            guard,
+            _mount,
+            _stop,
+            readiness: _,
+        } = self;
+        drop(_mount);
+        drop(_stop);
+        guard.join().unwrap().unwrap();
+    }
+}
+
+/// Stops a session loop when dropped
+#[derive(Debug)]
+struct StopOnDrop(SessionStopper);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        // Unlike writing, shutting down can't raise SIGPIPE if the loop ended already
+        let _ = self.0.sender.shutdown(std::net::Shutdown::Write);
+    }
+}
+
+impl fmt::Debug for ScopedBackgroundSession<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "ScopedBackgroundSession {{ mountpoint: {:?}, guard: ScopedJoinHandle<()> }}",
+            self.mountpoint
+        )
+    }
+}
+
 // replace with #[derive(Debug)] if Debug ever gets implemented for
 // thread_scoped::JoinGuard
 impl<'a> fmt::Debug for BackgroundSession {
@@ -258,3 +1203,150 @@ impl<'a> fmt::Debug for BackgroundSession {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ll::reply::peek_reply_header;
+    use crate::ll::test::request;
+    use crate::reply::ReplySender;
+    use std::collections::VecDeque;
+    use std::io::IoSlice;
+
+    struct NoopFS;
+
+    impl Filesystem for NoopFS {}
+
+    /// Collects the replies sent to the requests of a `Queue`
+    #[derive(Clone, Default)]
+    struct Replies(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl ReplySender for Replies {
+        fn send(&self, data: &[IoSlice<'_>]) -> io::Result<()> {
+            let reply = data.iter().flat_map(|x| x.iter().copied()).collect();
+            self.0.lock().unwrap().push(reply);
+            Ok(())
+        }
+    }
+
+    /// In memory transport that receives the queued requests, then fails like an unmounted
+    /// filesystem
+    struct Queue {
+        requests: Mutex<VecDeque<Vec<u8>>>,
+        replies: Replies,
+    }
+
+    impl Queue {
+        fn new(requests: Vec<Vec<u8>>) -> (Queue, Replies) {
+            let replies = Replies::default();
+            let queue = Queue {
+                requests: Mutex::new(requests.into()),
+                replies: replies.clone(),
+            };
+            (queue, replies)
+        }
+    }
+
+    impl Transport for Queue {
+        type Sender = Replies;
+
+        fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            match self.requests.lock().unwrap().pop_front() {
+                Some(request) => {
+                    buffer[..request.len()].copy_from_slice(&request);
+                    Ok(request.len())
+                }
+                None => Err(io::Error::from_raw_os_error(ENODEV)),
+            }
+        }
+
+        fn sender(&self) -> Replies {
+            self.replies.clone()
+        }
+    }
+
+    #[test]
+    fn in_memory_transport() {
+        let (queue, replies) = Queue::new(vec![
+            // INIT with protocol version 7.31, no readahead and no flags
+            request(26, 1, &[7, 31, 0, 0]),
+            // STATFS
+            request(17, 2, &[]),
+        ]);
+        let mut session = Session::with_transport(NoopFS, queue, Path::new("/queue"));
+        // Without a file descriptor to poll, stopping is checked before each receive
+        let stopper = session.stop_callable().unwrap();
+        stopper.stop().unwrap();
+        session.run().unwrap();
+        assert!(replies.0.lock().unwrap().is_empty());
+        session.run().unwrap();
+        assert_eq!(session.connection_info().unwrap().proto_major, 7);
+
+        let replies = replies.0.lock().unwrap();
+        assert_eq!(replies.len(), 2);
+        for (reply, unique) in replies.iter().zip(1u64..) {
+            // Length, error and unique id of the request
+            assert_eq!(&reply[..4], &(reply.len() as u32).to_ne_bytes());
+            assert_eq!(&reply[4..8], &0i32.to_ne_bytes());
+            assert_eq!(&reply[8..16], &unique.to_ne_bytes());
+        }
+    }
+
+    /// Claims 8 more bytes than `request` has, so it can't be parsed
+    fn truncated(mut request: Vec<u8>) -> Vec<u8> {
+        let len = request.len() as u32 + 8;
+        request[..4].copy_from_slice(&len.to_ne_bytes());
+        request
+    }
+
+    /// A session that receives a truncated STATFS, and a truncated FORGET, which has no reply
+    fn invalid_request_session() -> (Session<NoopFS, Queue>, Replies) {
+        let (queue, replies) = Queue::new(vec![
+            request(26, 1, &[7, 31, 0, 0]),
+            truncated(request(17, 2, &[])),
+            truncated(request(2, 3, &[1, 0])),
+            request(17, 4, &[]),
+        ]);
+        let session = Session::with_transport(NoopFS, queue, Path::new("/queue"));
+        (session, replies)
+    }
+
+    /// Unique ids and errors of the replies
+    fn reply_headers(replies: &Replies) -> Vec<(ll::RequestId, i32)> {
+        let replies = replies.0.lock().unwrap();
+        replies
+            .iter()
+            .map(|reply| peek_reply_header(reply).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn abort_on_invalid_request() {
+        let (mut session, replies) = invalid_request_session();
+        let result = session.run();
+        assert!(
+            matches!(result, Err(SessionError::InvalidRequest(err)) if err.unique() == Some(2))
+        );
+        assert_eq!(session.invalid_requests(), 1);
+        assert_eq!(
+            reply_headers(&replies),
+            [(ll::RequestId(1), 0), (ll::RequestId(2), -libc::EIO)]
+        );
+    }
+
+    #[test]
+    fn continue_after_invalid_requests() {
+        let (mut session, replies) = invalid_request_session();
+        session.set_invalid_request_policy(InvalidRequestPolicy::Continue);
+        session.run().unwrap();
+        assert_eq!(session.invalid_requests(), 2);
+        assert_eq!(
+            reply_headers(&replies),
+            [
+                (ll::RequestId(1), 0),
+                (ll::RequestId(2), -libc::EIO),
+                (ll::RequestId(4), 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Stopping a session on SIGINT, SIGTERM and SIGHUP, like libfuse's `fuse_set_signal_handlers`
+//!
+//! The signal handler writes the signal number to a socket, which the session loop polls along
+//! with the FUSE device. Only one session at a time can have the handlers installed.
+
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::{mem, ptr};
+
+use libc::c_int;
+
+/// Signals that stop the session
+const STOP_SIGNALS: [c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Socket the signal handler writes to, or -1 if no handlers are installed
+static SIGNAL_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle_signal(signal: c_int) {
+    let fd = SIGNAL_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = signal as u8;
+        // Only async-signal-safe functions may be called here. If the socket is full, a
+        // previous signal is still pending, which stops the session just as well.
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+}
+
+/// Installed signal handlers. Dropping this restores the previous handlers.
+#[derive(Debug)]
+pub(crate) struct SignalHandlers {
+    _sender: UnixStream,
+    previous: Vec<(c_int, libc::sigaction)>,
+}
+
+impl SignalHandlers {
+    /// Install the handlers. Returns the socket from which the received signals can be read,
+    /// one byte per signal.
+    pub(crate) fn install() -> io::Result<(SignalHandlers, UnixStream)> {
+        let (sender, receiver) = UnixStream::pair()?;
+        sender.set_nonblocking(true)?;
+        if SIGNAL_FD
+            .compare_exchange(-1, sender.as_raw_fd(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                "Signal handlers are already installed for another session",
+            ));
+        }
+        let mut handlers = SignalHandlers {
+            _sender: sender,
+            previous: vec![],
+        };
+        for signal in STOP_SIGNALS.iter() {
+            handlers.set(
+                *signal,
+                handle_signal as extern "C" fn(c_int) as libc::sighandler_t,
+            )?;
+        }
+        // Replies to a filesystem that was unmounted in the meantime must not kill the process
+        handlers.set(libc::SIGPIPE, libc::SIG_IGN)?;
+        Ok((handlers, receiver))
+    }
+
+    fn set(&mut self, signal: c_int, handler: libc::sighandler_t) -> io::Result<()> {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signal, &action, &mut previous) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.previous.push((signal, previous));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        for (signal, previous) in self.previous.drain(..).rev() {
+            unsafe { libc::sigaction(signal, &previous, ptr::null_mut()) };
+        }
+        // The socket is closed right after this, once the handlers can't write to it anymore
+        SIGNAL_FD.store(-1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn forward_signal() {
+        let (handlers, mut receiver) = SignalHandlers::install().unwrap();
+        assert!(SignalHandlers::install().is_err());
+        unsafe { libc::raise(libc::SIGHUP) };
+        let mut buf = [0u8];
+        receiver.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0] as c_int, libc::SIGHUP);
+        drop(handlers);
+        assert_eq!(SIGNAL_FD.load(Ordering::SeqCst), -1);
+    }
+}
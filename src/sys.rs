@@ -0,0 +1,59 @@
+//! Helpers shared by the Linux-only transports and the mount set
+//!
+//! They wait for their file descriptors with epoll, and read the native-endian fields of kernel
+//! and vhost-user messages.
+
+use std::convert::TryInto;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+
+use libc::c_int;
+
+/// Create an epoll instance
+pub(crate) fn epoll_create() -> io::Result<OwnedFd> {
+    let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    if epoll < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(epoll) })
+}
+
+/// Add, modify (`op`) or remove `fd` in an epoll instance, waiting until it is readable. `flags`
+/// are added to `EPOLLIN`, and `token` is reported with its events.
+pub(crate) fn epoll_ctl(
+    epoll: BorrowedFd<'_>,
+    op: c_int,
+    fd: RawFd,
+    flags: c_int,
+    token: u64,
+) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: (libc::EPOLLIN | flags) as u32,
+        u64: token,
+    };
+    if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), op, fd, &mut event) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// An error about malformed data received from the kernel or a peer
+pub(crate) fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Read a native-endian `u32` at `offset`
+pub(crate) fn u32_at(data: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| invalid("Short message"))?;
+    Ok(u32::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read a native-endian `u64` at `offset`
+pub(crate) fn u64_at(data: &[u8], offset: usize) -> io::Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or_else(|| invalid("Short message"))?;
+    Ok(u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
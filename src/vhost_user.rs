@@ -0,0 +1,902 @@
+//! Serving a filesystem to virtual machines over vhost-user, like virtiofsd
+//!
+//! The hypervisor, the vhost-user frontend, connects to a Unix socket and shares the memory of
+//! the guest and the virtqueues of a virtio-fs device with us. The guest's driver puts FUSE
+//! requests into the queues, `VhostUserTransport` passes them to a session, and the replies are
+//! copied into the buffers that the guest provided along with each request. Queue 0 is the high
+//! priority queue for FORGET and INTERRUPT requests, the others are request queues.
+//!
+//! Only split virtqueues without indirect descriptors are supported. The DAX window of the device
+//! is not managed here: SETUPMAPPING and REMOVEMAPPING requests are passed to the filesystem,
+//! which has to arrange the mappings with the hypervisor. By default they are answered with
+//! `ENOSYS`, so the guest reads and writes through the queues.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{fence, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{cmp, mem, ptr};
+
+use libc::{c_int, ENODEV, ENOENT};
+use log::{debug, warn};
+
+use crate::channel::Transport;
+use crate::fd_passing::recv_fds;
+use crate::ll;
+use crate::ll::reply::peek_reply_header;
+use crate::reply::ReplySender;
+use crate::sys::{self, invalid, u32_at, u64_at};
+
+// Requests of the frontend
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const RESET_OWNER: u32 = 4;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const GET_VRING_BASE: u32 = 11;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const SET_VRING_ERR: u32 = 14;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const GET_QUEUE_NUM: u32 = 17;
+const SET_VRING_ENABLE: u32 = 18;
+
+// Flags of message headers
+const FLAG_VERSION: u32 = 0x1;
+const FLAG_REPLY: u32 = 0x4;
+const FLAG_NEED_REPLY: u32 = 0x8;
+
+/// VIRTIO_F_VERSION_1 and VHOST_USER_F_PROTOCOL_FEATURES
+const FEATURES: u64 = 1 << 32 | F_PROTOCOL_FEATURES;
+const F_PROTOCOL_FEATURES: u64 = 1 << 30;
+/// VHOST_USER_PROTOCOL_F_MQ and VHOST_USER_PROTOCOL_F_REPLY_ACK
+const PROTOCOL_FEATURES: u64 = 1 << 0 | PROTOCOL_F_REPLY_ACK;
+const PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+
+/// The high priority queue and up to 15 request queues
+const MAX_QUEUES: usize = 16;
+const MAX_QUEUE_SIZE: u32 = 32768;
+const MAX_REGIONS: usize = 8;
+const HEADER_SIZE: usize = 12;
+/// Size of the largest message, SET_MEM_TABLE
+const MAX_PAYLOAD: usize = 8 + MAX_REGIONS * 32;
+/// Epoll token of the socket, those of the kick eventfds are the indices of their queues
+const SOCKET: u64 = u64::MAX;
+
+// Flags of descriptors
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const DESC_F_INDIRECT: u16 = 4;
+/// Set by the driver to suppress notifications of used buffers
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// Receives the requests of a virtio-fs device from a vhost-user frontend, such as QEMU, over a
+/// connected Unix socket. Pass it to `Session::with_transport` to serve them; the mountpoint is
+/// only used to describe the session, e.g. by the tag of the device.
+///
+/// The session loop ends once the frontend disconnects.
+#[derive(Debug)]
+pub struct VhostUserTransport {
+    socket: UnixStream,
+    /// Waits for messages on the socket and for kicks of the queues
+    epoll: OwnedFd,
+    sender: VhostUserSender,
+}
+
+impl VhostUserTransport {
+    /// Create a transport that serves the frontend at the other end of `socket`, usually accepted
+    /// from a `UnixListener` whose path is passed to the hypervisor
+    pub fn new(socket: UnixStream) -> io::Result<VhostUserTransport> {
+        let epoll = sys::epoll_create()?;
+        sys::epoll_ctl(
+            epoll.as_fd(),
+            libc::EPOLL_CTL_ADD,
+            socket.as_raw_fd(),
+            0,
+            SOCKET,
+        )?;
+        let device = Device {
+            queues: (0..MAX_QUEUES).map(|_| Queue::default()).collect(),
+            ..Device::default()
+        };
+        Ok(VhostUserTransport {
+            socket,
+            epoll,
+            sender: VhostUserSender(Arc::new(Mutex::new(device))),
+        })
+    }
+
+    /// Waits until the frontend sent a message or kicked a queue
+    fn wait(&self) -> io::Result<()> {
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_QUEUES + 1];
+        let count = unsafe {
+            libc::epoll_wait(
+                self.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as c_int,
+                -1,
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let tokens: Vec<u64> = events[..count as usize].iter().map(|x| x.u64).collect();
+        // Kicks are consumed first, since messages may replace their eventfds
+        for &token in tokens.iter().filter(|&&x| x != SOCKET) {
+            let device = self.sender.0.lock().unwrap();
+            if let Some(kick) = &device.queues[token as usize].kick {
+                let _ = (&*kick).read(&mut [0; 8]);
+            }
+        }
+        if tokens.contains(&SOCKET) {
+            self.handle_message()?;
+        }
+        Ok(())
+    }
+
+    fn handle_message(&self) -> io::Result<()> {
+        let mut header = [0; HEADER_SIZE];
+        let (fds, len) = recv_fds(&self.socket, &mut header, MAX_REGIONS)?;
+        if len == 0 {
+            debug!("vhost-user frontend disconnected");
+            // Like reading from an unmounted FUSE device
+            return Err(io::Error::from_raw_os_error(ENODEV));
+        }
+        (&self.socket).read_exact(&mut header[len..])?;
+        let request = u32_at(&header, 0)?;
+        let flags = u32_at(&header, 4)?;
+        let size = u32_at(&header, 8)? as usize;
+        if flags & 0x3 != FLAG_VERSION || size > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid vhost-user message header {:?}", header),
+            ));
+        }
+        let mut payload = [0; MAX_PAYLOAD];
+        (&self.socket).read_exact(&mut payload[..size])?;
+
+        let mut device = self.sender.0.lock().unwrap();
+        let result = device.handle(request, &payload[..size], fds, self.epoll.as_fd());
+        let ack =
+            flags & FLAG_NEED_REPLY != 0 && device.protocol_features & PROTOCOL_F_REPLY_ACK != 0;
+        drop(device);
+        let reply = match result {
+            Ok(Some(reply)) => reply,
+            Ok(None) if ack => 0u64.to_ne_bytes().to_vec(),
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                warn!("vhost-user request {} failed: {}", request, err);
+                if !ack {
+                    return Ok(());
+                }
+                1u64.to_ne_bytes().to_vec()
+            }
+        };
+        let mut message = Vec::with_capacity(HEADER_SIZE + reply.len());
+        message.extend_from_slice(&request.to_ne_bytes());
+        message.extend_from_slice(&(FLAG_VERSION | FLAG_REPLY).to_ne_bytes());
+        message.extend_from_slice(&(reply.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&reply);
+        (&self.socket).write_all(&message)
+    }
+}
+
+impl Transport for VhostUserTransport {
+    type Sender = VhostUserSender;
+
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(size) = self.sender.0.lock().unwrap().next_request(buffer) {
+                return Ok(size);
+            }
+            self.wait()?;
+        }
+    }
+
+    fn sender(&self) -> VhostUserSender {
+        self.sender.clone()
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.epoll.as_fd())
+    }
+}
+
+/// Copies replies into the buffers of the requests of a `VhostUserTransport`, and returns them to
+/// the guest
+#[derive(Clone, Debug)]
+pub struct VhostUserSender(Arc<Mutex<Device>>);
+
+impl ReplySender for VhostUserSender {
+    fn send(&self, data: &[IoSlice<'_>]) -> io::Result<()> {
+        let mut reply = Vec::with_capacity(data.iter().map(|x| x.len()).sum());
+        for x in data {
+            reply.extend_from_slice(x);
+        }
+        let (unique, _) = peek_reply_header(&reply)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.0.lock().unwrap().complete(unique.0, &reply)
+    }
+}
+
+/// State of the device, shared by the transport and its senders
+#[derive(Debug, Default)]
+struct Device {
+    features: u64,
+    protocol_features: u64,
+    memory: Memory,
+    queues: Vec<Queue>,
+    /// Requests that wait for a reply, by unique id
+    pending: HashMap<u64, Chain>,
+}
+
+impl Device {
+    /// Handles a message of the frontend. Returns the payload of the reply, if the request has
+    /// one.
+    fn handle(
+        &mut self,
+        request: u32,
+        payload: &[u8],
+        mut fds: Vec<File>,
+        epoll: BorrowedFd<'_>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        match request {
+            GET_FEATURES => return Ok(Some(FEATURES.to_ne_bytes().to_vec())),
+            SET_FEATURES => self.features = u64_at(payload, 0)?,
+            SET_OWNER | RESET_OWNER => {}
+            GET_PROTOCOL_FEATURES => return Ok(Some(PROTOCOL_FEATURES.to_ne_bytes().to_vec())),
+            SET_PROTOCOL_FEATURES => self.protocol_features = u64_at(payload, 0)?,
+            GET_QUEUE_NUM => return Ok(Some((MAX_QUEUES as u64).to_ne_bytes().to_vec())),
+            SET_MEM_TABLE => self.memory = Memory::map(payload, &fds)?,
+            SET_VRING_NUM => {
+                let size = u32_at(payload, 4)?;
+                if !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+                    return Err(invalid(format!("Invalid queue size {}", size)));
+                }
+                self.queue(payload)?.size = size as u16;
+            }
+            SET_VRING_ADDR => {
+                let queue = self.queue(payload)?;
+                queue.desc = u64_at(payload, 8)?;
+                queue.used = u64_at(payload, 16)?;
+                queue.avail = u64_at(payload, 24)?;
+            }
+            SET_VRING_BASE => {
+                let base = u32_at(payload, 4)? as u16;
+                let queue = self.queue(payload)?;
+                queue.next_avail = base;
+                queue.next_used = base;
+            }
+            GET_VRING_BASE => {
+                let queue = self.queue(payload)?;
+                // Stops the queue
+                if let Some(kick) = queue.kick.take() {
+                    sys::epoll_ctl(epoll, libc::EPOLL_CTL_DEL, kick.as_raw_fd(), 0, 0)?;
+                }
+                let mut reply = payload[..4].to_vec();
+                reply.extend_from_slice(&u32::from(queue.next_avail).to_ne_bytes());
+                return Ok(Some(reply));
+            }
+            SET_VRING_KICK | SET_VRING_CALL | SET_VRING_ERR => {
+                let value = u64_at(payload, 0)?;
+                let index = (value & 0xff) as usize;
+                // Bit 8 is set if no file descriptor was passed
+                let fd = if value & 0x100 == 0 {
+                    Some(
+                        fds.pop()
+                            .ok_or_else(|| invalid("Missing file descriptor"))?,
+                    )
+                } else {
+                    None
+                };
+                // Without protocol features, queues are enabled when they are started
+                let enable = self.features & F_PROTOCOL_FEATURES == 0;
+                let queue = self
+                    .queues
+                    .get_mut(index)
+                    .ok_or_else(|| invalid(format!("Invalid queue {}", index)))?;
+                match request {
+                    SET_VRING_KICK => {
+                        let kick = fd.ok_or_else(|| invalid("Polling queues is not supported"))?;
+                        if let Some(old) = queue.kick.take() {
+                            sys::epoll_ctl(epoll, libc::EPOLL_CTL_DEL, old.as_raw_fd(), 0, 0)?;
+                        }
+                        sys::epoll_ctl(
+                            epoll,
+                            libc::EPOLL_CTL_ADD,
+                            kick.as_raw_fd(),
+                            0,
+                            index as u64,
+                        )?;
+                        queue.kick = Some(kick);
+                        queue.enabled |= enable;
+                    }
+                    SET_VRING_CALL => queue.call = fd,
+                    // Errors of the queues are only logged
+                    _ => {}
+                }
+            }
+            SET_VRING_ENABLE => {
+                let enabled = u32_at(payload, 4)? != 0;
+                self.queue(payload)?.enabled = enabled;
+            }
+            _ => return Err(invalid("Unsupported request")),
+        }
+        Ok(None)
+    }
+
+    /// The queue whose index is at the start of `payload`
+    fn queue(&mut self, payload: &[u8]) -> io::Result<&mut Queue> {
+        let index = u32_at(payload, 0)?;
+        self.queues
+            .get_mut(index as usize)
+            .ok_or_else(|| invalid(format!("Invalid queue {}", index)))
+    }
+
+    /// Takes the next request from the queues and copies it into `buffer`
+    fn next_request(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        for index in 0..self.queues.len() {
+            loop {
+                let head = match self.queues[index].pop(&self.memory) {
+                    Ok(Some(head)) => head,
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("Disabling queue {}: {}", index, err);
+                        self.queues[index].enabled = false;
+                        break;
+                    }
+                };
+                match self.receive_request(index, head, buffer) {
+                    Ok(size) => {
+                        // The kick may have been consumed already, so the transport's fd has to
+                        // be made readable again while more requests are available
+                        self.queues[index].rekick(&self.memory);
+                        return Some(size);
+                    }
+                    Err(err) => {
+                        warn!("Dropping invalid request on queue {}: {}", index, err);
+                        let _ = self.queues[index].push(&self.memory, head, 0);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn receive_request(&mut self, queue: usize, head: u16, buffer: &mut [u8]) -> io::Result<usize> {
+        let chain = self.queues[queue].chain(&self.memory, queue, head)?;
+        let mut size = 0;
+        for &(addr, len) in &chain.readable {
+            let len = len as usize;
+            let target = buffer
+                .get_mut(size..size + len)
+                .ok_or_else(|| invalid("Request is too large"))?;
+            let source = self.memory.guest(addr, len)?;
+            unsafe { ptr::copy_nonoverlapping(source, target.as_mut_ptr(), len) };
+            size += len;
+        }
+        let (unique, _) =
+            ll::peek_header(&buffer[..size]).ok_or_else(|| invalid("Request is too short"))?;
+        if chain.writable.is_empty() {
+            // Requests without a reply, such as FORGET, are returned right away
+            self.queues[queue].push(&self.memory, head, 0)?;
+        } else {
+            self.pending.insert(unique.0, chain);
+        }
+        Ok(size)
+    }
+
+    /// Copies the reply to a request into its buffers and returns them to the guest
+    fn complete(&mut self, unique: u64, reply: &[u8]) -> io::Result<()> {
+        // Like the FUSE device does for unknown requests
+        let chain = self
+            .pending
+            .remove(&unique)
+            .ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+        let result = self.write_reply(&chain, reply);
+        let queue = &mut self.queues[chain.queue];
+        // The frontend does not expect buffers of stopped queues to be returned
+        if queue.kick.is_some() {
+            let len = if result.is_ok() {
+                reply.len() as u32
+            } else {
+                0
+            };
+            queue.push(&self.memory, chain.head, len)?;
+        }
+        result
+    }
+
+    fn write_reply(&self, chain: &Chain, reply: &[u8]) -> io::Result<()> {
+        let capacity: usize = chain.writable.iter().map(|x| x.1 as usize).sum();
+        if reply.len() > capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Reply of {} bytes does not fit into buffers of {} bytes",
+                    reply.len(),
+                    capacity
+                ),
+            ));
+        }
+        let mut rest = reply;
+        for &(addr, len) in &chain.writable {
+            let len = cmp::min(len as usize, rest.len());
+            let target = self.memory.guest(addr, len)?;
+            unsafe { ptr::copy_nonoverlapping(rest.as_ptr(), target, len) };
+            rest = &rest[len..];
+        }
+        Ok(())
+    }
+}
+
+/// A split virtqueue
+#[derive(Debug, Default)]
+struct Queue {
+    size: u16,
+    /// Addresses of the descriptor table and the rings, in the address space of the frontend
+    desc: u64,
+    avail: u64,
+    used: u64,
+    /// Index of the next descriptor chain in the available ring
+    next_avail: u16,
+    /// Index of the next element of the used ring
+    next_used: u16,
+    /// Kicked by the driver when it made buffers available. The queue is started while it is set.
+    kick: Option<File>,
+    /// Notifies the driver of used buffers
+    call: Option<File>,
+    enabled: bool,
+}
+
+impl Queue {
+    fn has_available(&self, memory: &Memory) -> io::Result<bool> {
+        let idx = memory.user::<AtomicU16>(self.avail + 2)?;
+        Ok(unsafe { (*idx).load(Ordering::Acquire) } != self.next_avail)
+    }
+
+    /// Takes the head of the next descriptor chain that the driver made available
+    fn pop(&mut self, memory: &Memory) -> io::Result<Option<u16>> {
+        if self.kick.is_none() || !self.enabled || self.size == 0 || !self.has_available(memory)? {
+            return Ok(None);
+        }
+        let slot = u64::from(self.next_avail % self.size);
+        let head = unsafe { ptr::read_volatile(memory.user::<u16>(self.avail + 4 + 2 * slot)?) };
+        self.next_avail = self.next_avail.wrapping_add(1);
+        Ok(Some(head))
+    }
+
+    fn chain(&self, memory: &Memory, queue: usize, head: u16) -> io::Result<Chain> {
+        let mut chain = Chain {
+            queue,
+            head,
+            readable: vec![],
+            writable: vec![],
+        };
+        let mut index = head;
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(invalid(format!("Invalid descriptor {}", index)));
+            }
+            let desc = memory.user::<Descriptor>(self.desc + 16 * u64::from(index))?;
+            let desc = unsafe { ptr::read_volatile(desc) };
+            if desc.flags & DESC_F_INDIRECT != 0 {
+                return Err(invalid("Indirect descriptors are not supported"));
+            }
+            if desc.flags & DESC_F_WRITE != 0 {
+                chain.writable.push((desc.addr, desc.len));
+            } else if chain.writable.is_empty() {
+                chain.readable.push((desc.addr, desc.len));
+            } else {
+                return Err(invalid("Readable descriptor after a writable one"));
+            }
+            if desc.flags & DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = desc.next;
+        }
+        Err(invalid("Descriptor chain is longer than the queue"))
+    }
+
+    /// Returns a descriptor chain to the driver, with `len` bytes written to its buffers
+    fn push(&mut self, memory: &Memory, head: u16, len: u32) -> io::Result<()> {
+        let slot = u64::from(self.next_used % self.size);
+        let element = memory.user::<[u32; 2]>(self.used + 4 + 8 * slot)?;
+        let idx = memory.user::<AtomicU16>(self.used + 2)?;
+        let flags = memory.user::<AtomicU16>(self.avail)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        unsafe {
+            ptr::write_volatile(element, [u32::from(head), len]);
+            (*idx).store(self.next_used, Ordering::Release);
+        }
+        // The driver's flags must be read after the used index was published
+        fence(Ordering::SeqCst);
+        if unsafe { (*flags).load(Ordering::Relaxed) } & AVAIL_F_NO_INTERRUPT == 0 {
+            if let Some(call) = &self.call {
+                let _ = (&*call).write(&1u64.to_ne_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn rekick(&self, memory: &Memory) {
+        if let (Some(kick), Ok(true)) = (&self.kick, self.has_available(memory)) {
+            let _ = (&*kick).write(&1u64.to_ne_bytes());
+        }
+    }
+}
+
+/// A descriptor of a buffer in the memory of the guest
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A request in a queue
+#[derive(Debug)]
+struct Chain {
+    queue: usize,
+    head: u16,
+    /// Guest physical addresses and lengths of the buffers that hold the request
+    readable: Vec<(u64, u32)>,
+    /// Guest physical addresses and lengths of the buffers for the reply
+    writable: Vec<(u64, u32)>,
+}
+
+/// The memory of the guest, as shared by the frontend with SET_MEM_TABLE
+#[derive(Debug, Default)]
+struct Memory(Vec<MemoryRegion>);
+
+#[derive(Debug)]
+struct MemoryRegion {
+    guest_addr: u64,
+    /// Address of the region in the address space of the frontend
+    user_addr: u64,
+    size: u64,
+    mapping: *mut libc::c_void,
+    mapping_len: usize,
+    /// Offset of the region in the mapping
+    offset: usize,
+}
+
+// The mapping is owned by the region
+unsafe impl Send for MemoryRegion {}
+
+impl Drop for MemoryRegion {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.mapping, self.mapping_len) };
+    }
+}
+
+impl Memory {
+    fn map(payload: &[u8], fds: &[File]) -> io::Result<Memory> {
+        let count = u32_at(payload, 0)? as usize;
+        if count > MAX_REGIONS || fds.len() != count {
+            return Err(invalid(format!(
+                "{} memory regions with {} file descriptors",
+                count,
+                fds.len()
+            )));
+        }
+        let mut regions = vec![];
+        for (i, fd) in fds.iter().enumerate() {
+            let entry = 8 + 32 * i;
+            let guest_addr = u64_at(payload, entry)?;
+            let size = u64_at(payload, entry + 8)?;
+            let user_addr = u64_at(payload, entry + 16)?;
+            let offset = u64_at(payload, entry + 24)?;
+            let mapping_len = size
+                .checked_add(offset)
+                .ok_or_else(|| invalid("Invalid memory region"))?;
+            let mapping = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    mapping_len as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd.as_raw_fd(),
+                    0,
+                )
+            };
+            if mapping == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            regions.push(MemoryRegion {
+                guest_addr,
+                user_addr,
+                size,
+                mapping,
+                mapping_len: mapping_len as usize,
+                offset: offset as usize,
+            });
+        }
+        debug!("Mapped {} regions of guest memory", regions.len());
+        Ok(Memory(regions))
+    }
+
+    /// Translates a guest physical address
+    fn guest(&self, addr: u64, len: usize) -> io::Result<*mut u8> {
+        self.translate(addr, len, |x| x.guest_addr)
+    }
+
+    /// Translates an address of the frontend, such as those of the rings
+    fn user<T>(&self, addr: u64) -> io::Result<*mut T> {
+        let ptr = self.translate(addr, mem::size_of::<T>(), |x| x.user_addr)?;
+        if ptr as usize & (mem::align_of::<T>() - 1) != 0 {
+            return Err(invalid(format!("Unaligned address {:#x}", addr)));
+        }
+        Ok(ptr as *mut T)
+    }
+
+    fn translate(
+        &self,
+        addr: u64,
+        len: usize,
+        start: impl Fn(&MemoryRegion) -> u64,
+    ) -> io::Result<*mut u8> {
+        for region in &self.0 {
+            let offset = match addr.checked_sub(start(region)) {
+                Some(offset) if offset <= region.size => offset,
+                _ => continue,
+            };
+            if len as u64 <= region.size - offset {
+                let offset = region.offset + offset as usize;
+                return Ok(unsafe { (region.mapping as *mut u8).add(offset) });
+            }
+        }
+        Err(invalid(format!("Invalid address {:#x}", addr)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fd_passing::send_fds;
+    use crate::ll::test::request;
+    use crate::{Filesystem, Session};
+    use std::convert::TryInto;
+    use std::os::unix::io::FromRawFd;
+    use std::path::Path;
+    use std::thread;
+
+    struct NoopFS;
+
+    impl Filesystem for NoopFS {}
+
+    const MEMORY_SIZE: usize = 1 << 20;
+    const GUEST_BASE: u64 = 0x4000_0000;
+    const QUEUE_SIZE: u16 = 8;
+    /// Start of the buffers of the requests, after the rings of both queues
+    const BUFFERS: u64 = 0x20000;
+
+    /// A frontend with a high priority and a request queue in one region of guest memory. The
+    /// rings of queue `i` are at `0x10000 * i`.
+    struct Frontend {
+        socket: UnixStream,
+        memory: *mut u8,
+        kicks: Vec<File>,
+        calls: Vec<File>,
+        next_avail: [u16; 2],
+        next_used: [u16; 2],
+        next_buffer: u64,
+    }
+
+    impl Frontend {
+        fn new(socket: UnixStream) -> Frontend {
+            let memfd = unsafe { libc::memfd_create(b"guest\0".as_ptr() as *const _, 0) };
+            assert!(memfd >= 0);
+            let memfd = unsafe { File::from_raw_fd(memfd) };
+            memfd.set_len(MEMORY_SIZE as u64).unwrap();
+            let memory = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    MEMORY_SIZE,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    memfd.as_raw_fd(),
+                    0,
+                )
+            };
+            assert_ne!(memory, libc::MAP_FAILED);
+            let eventfd = || unsafe { File::from_raw_fd(libc::eventfd(0, libc::EFD_CLOEXEC)) };
+            let frontend = Frontend {
+                socket,
+                memory: memory as *mut u8,
+                kicks: vec![eventfd(), eventfd()],
+                calls: vec![eventfd(), eventfd()],
+                next_avail: [0; 2],
+                next_used: [0; 2],
+                next_buffer: BUFFERS,
+            };
+
+            frontend.send(SET_OWNER, 0, &[], None);
+            let features = frontend.call(GET_FEATURES, &[]);
+            assert_eq!(features, FEATURES.to_ne_bytes());
+            frontend.send(SET_FEATURES, 0, &features, None);
+            let protocol_features = frontend.call(GET_PROTOCOL_FEATURES, &[]);
+            assert_eq!(protocol_features, PROTOCOL_FEATURES.to_ne_bytes());
+            frontend.send(SET_PROTOCOL_FEATURES, 0, &protocol_features, None);
+            // One region, and padding
+            let mut table = state(1, 0);
+            for x in &[GUEST_BASE, MEMORY_SIZE as u64, memory as u64, 0] {
+                table.extend_from_slice(&x.to_ne_bytes());
+            }
+            // Acknowledged since REPLY_ACK was negotiated
+            frontend.send(SET_MEM_TABLE, FLAG_NEED_REPLY, &table, Some(&memfd));
+            assert_eq!(frontend.reply(SET_MEM_TABLE), 0u64.to_ne_bytes());
+            for i in 0..2u32 {
+                let rings = memory as u64 + 0x10000 * u64::from(i);
+                frontend.send(SET_VRING_NUM, 0, &state(i, QUEUE_SIZE.into()), None);
+                let mut addr = state(i, 0);
+                for x in &[rings, rings + 0x2000, rings + 0x1000, 0] {
+                    addr.extend_from_slice(&x.to_ne_bytes());
+                }
+                frontend.send(SET_VRING_ADDR, 0, &addr, None);
+                frontend.send(SET_VRING_BASE, 0, &state(i, 0), None);
+                let index = &u64::from(i).to_ne_bytes();
+                let call = frontend.calls[i as usize].try_clone().unwrap();
+                frontend.send(SET_VRING_CALL, 0, index, Some(&call));
+                let kick = frontend.kicks[i as usize].try_clone().unwrap();
+                frontend.send(SET_VRING_KICK, 0, index, Some(&kick));
+                frontend.send(SET_VRING_ENABLE, 0, &state(i, 1), None);
+            }
+            frontend
+        }
+
+        fn send(&self, request: u32, flags: u32, payload: &[u8], fd: Option<&File>) {
+            let mut message = vec![];
+            message.extend_from_slice(&request.to_ne_bytes());
+            message.extend_from_slice(&(FLAG_VERSION | flags).to_ne_bytes());
+            message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+            message.extend_from_slice(payload);
+            match fd {
+                Some(fd) => send_fds(&self.socket, &[fd.as_raw_fd()], &message).unwrap(),
+                None => (&self.socket).write_all(&message).unwrap(),
+            }
+        }
+
+        fn reply(&self, request: u32) -> Vec<u8> {
+            let mut header = [0; HEADER_SIZE];
+            (&self.socket).read_exact(&mut header).unwrap();
+            assert_eq!(u32_at(&header, 0).unwrap(), request);
+            assert_eq!(u32_at(&header, 4).unwrap(), FLAG_VERSION | FLAG_REPLY);
+            let mut payload = vec![0; u32_at(&header, 8).unwrap() as usize];
+            (&self.socket).read_exact(&mut payload).unwrap();
+            payload
+        }
+
+        fn call(&self, request: u32, payload: &[u8]) -> Vec<u8> {
+            self.send(request, 0, payload, None);
+            self.reply(request)
+        }
+
+        fn write(&self, offset: u64, data: &[u8]) {
+            assert!(offset as usize + data.len() <= MEMORY_SIZE);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    data.as_ptr(),
+                    self.memory.add(offset as usize),
+                    data.len(),
+                )
+            };
+        }
+
+        fn read(&self, offset: u64, len: usize) -> Vec<u8> {
+            let mut data = vec![0; len];
+            unsafe {
+                ptr::copy_nonoverlapping(self.memory.add(offset as usize), data.as_mut_ptr(), len)
+            };
+            data
+        }
+
+        /// Puts a request into a queue, followed by a buffer for the reply if `reply_len` is not 0
+        fn submit(&mut self, queue: usize, request: &[u8], reply_len: u32) -> u64 {
+            let rings = 0x10000 * queue as u64;
+            let avail = self.next_avail[queue];
+            let head = (avail * 2) % QUEUE_SIZE;
+            let reply = self.next_buffer + request.len() as u64;
+            self.write(self.next_buffer, request);
+            let mut descriptors = vec![];
+            let next = if reply_len == 0 { 0 } else { DESC_F_NEXT };
+            for (addr, len, flags, next) in &[
+                (self.next_buffer, request.len() as u32, next, head + 1),
+                (reply, reply_len, DESC_F_WRITE, 0),
+            ] {
+                descriptors.extend_from_slice(&(GUEST_BASE + addr).to_ne_bytes());
+                descriptors.extend_from_slice(&len.to_ne_bytes());
+                descriptors.extend_from_slice(&flags.to_ne_bytes());
+                descriptors.extend_from_slice(&next.to_ne_bytes());
+            }
+            self.next_buffer = reply + u64::from(reply_len);
+            self.write(rings + 16 * u64::from(head), &descriptors);
+            let slot = u64::from(avail % QUEUE_SIZE);
+            self.write(rings + 0x1000 + 4 + 2 * slot, &head.to_ne_bytes());
+            self.next_avail[queue] = avail.wrapping_add(1);
+            fence(Ordering::SeqCst);
+            self.write(rings + 0x1000 + 2, &self.next_avail[queue].to_ne_bytes());
+            (&self.kicks[queue]).write_all(&1u64.to_ne_bytes()).unwrap();
+            reply
+        }
+
+        /// Waits for the next used element of a queue
+        fn wait_used(&mut self, queue: usize) -> (u32, u32) {
+            let rings = 0x10000 * queue as u64;
+            let used = self.next_used[queue];
+            loop {
+                let idx = self.read(rings + 0x2000 + 2, 2);
+                if u16::from_ne_bytes(idx.try_into().unwrap()) != used {
+                    break;
+                }
+                (&self.calls[queue]).read_exact(&mut [0; 8]).unwrap();
+            }
+            self.next_used[queue] = used.wrapping_add(1);
+            let slot = u64::from(used % QUEUE_SIZE);
+            let element = self.read(rings + 0x2000 + 4 + 8 * slot, 8);
+            (u32_at(&element, 0).unwrap(), u32_at(&element, 4).unwrap())
+        }
+
+        /// Sends a request on the request queue and returns the reply
+        fn request(&mut self, request: &[u8]) -> Vec<u8> {
+            let reply = self.submit(1, request, 4096);
+            let (_, len) = self.wait_used(1);
+            self.read(reply, len as usize)
+        }
+    }
+
+    fn state(index: u32, num: u32) -> Vec<u8> {
+        let mut state = index.to_ne_bytes().to_vec();
+        state.extend_from_slice(&num.to_ne_bytes());
+        state
+    }
+
+    #[test]
+    fn serve_virtio_fs() {
+        let (socket, backend) = UnixStream::pair().unwrap();
+        let transport = VhostUserTransport::new(backend).unwrap();
+        let session = thread::spawn(move || {
+            Session::with_transport(NoopFS, transport, Path::new("virtiofs")).run()
+        });
+        let mut frontend = Frontend::new(socket);
+
+        // INIT with protocol version 7.31, no readahead and no flags
+        let reply = frontend.request(&request(26, 1, &[7, 31, 0, 0]));
+        assert_eq!(u32_at(&reply, 0).unwrap() as usize, reply.len());
+        assert_eq!(peek_reply_header(&reply), Some((ll::RequestId(1), 0)));
+
+        // FORGET on the high priority queue, which has no reply
+        frontend.submit(0, &request(2, 2, &[1, 0]), 0);
+        assert_eq!(frontend.wait_used(0), (0, 0));
+
+        // STATFS
+        let reply = frontend.request(&request(17, 3, &[]));
+        assert_eq!(u32_at(&reply, 0).unwrap() as usize, reply.len());
+        assert_eq!(peek_reply_header(&reply), Some((ll::RequestId(3), 0)));
+
+        // SETUPMAPPING, which NoopFS doesn't implement
+        #[cfg(feature = "abi-7-31")]
+        {
+            let reply = frontend.request(&request(48, 4, &[0; 10]));
+            assert_eq!(
+                peek_reply_header(&reply),
+                Some((ll::RequestId(4), -libc::ENOSYS))
+            );
+        }
+
+        // Stopping the request queue returns the index of the next request
+        let next = if cfg!(feature = "abi-7-31") { 3 } else { 2 };
+        assert_eq!(frontend.call(GET_VRING_BASE, &state(1, 0)), state(1, next));
+        drop(frontend);
+        session.join().unwrap().unwrap();
+    }
+}
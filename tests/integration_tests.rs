@@ -1,9 +1,45 @@
 use fuser::{Filesystem, Session};
+use std::os::unix::io::AsFd;
 use std::rc::Rc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+/// Held by tests that signal the test process, since only one session can handle signals
+#[cfg(target_os = "linux")]
+static SIGNALS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Filesystem that answers every request with the default replies
+struct NoopFS;
+
+impl Filesystem for NoopFS {}
+
+/// Attributes of the root directory for inode 1, and of a regular file of `size` bytes otherwise
+#[cfg(target_os = "linux")]
+fn attr(ino: u64, size: u64) -> fuser::FileAttr {
+    fuser::FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: std::time::UNIX_EPOCH,
+        mtime: std::time::UNIX_EPOCH,
+        ctime: std::time::UNIX_EPOCH,
+        crtime: std::time::UNIX_EPOCH,
+        kind: if ino == 1 {
+            fuser::FileType::Directory
+        } else {
+            fuser::FileType::RegularFile
+        },
+        perm: 0o755,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
 #[test]
 #[cfg(target_os = "linux")]
 fn unmount_no_send() {
@@ -21,3 +57,900 @@ fn unmount_no_send() {
     });
     session.run().unwrap();
 }
+
+#[test]
+#[cfg(target_os = "linux")]
+fn session_from_fd() {
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let session = Session::new(NoopFS, tmpdir.path(), &[]).unwrap();
+    let fd = session.as_fd().try_clone_to_owned().unwrap();
+    let mut worker = Session::from_fd(NoopFS, fd, tmpdir.path());
+    let connection = session.connection().unwrap();
+    if fuser::mounts::Connection::of_device(worker.as_fd())
+        .unwrap()
+        .is_some()
+    {
+        // Found through the device, not the mountpoint
+        let other = Session::from_fd(
+            NoopFS,
+            worker.as_fd().try_clone_to_owned().unwrap(),
+            "worker".as_ref(),
+        );
+        assert_eq!(other.connection().unwrap(), connection);
+    }
+    let worker = thread::spawn(move || worker.run());
+    // The default getattr() replies ENOSYS, which shows that the worker served the request
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    drop(session);
+    worker.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn dev_fd_mountpoint() {
+    use std::ffi::CString;
+    use std::fs::OpenOptions;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    // Mount like a privileged launcher would, and pass the device by its number
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .unwrap();
+    let source = CString::new("passed").unwrap();
+    let target = CString::new(tmpdir.path().as_os_str().as_bytes()).unwrap();
+    let fstype = CString::new("fuse").unwrap();
+    let options = format!(
+        "fd={},rootmode=40000,user_id={},group_id={}",
+        device.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    let options = CString::new(options).unwrap();
+    let result = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            0,
+            options.as_ptr().cast(),
+        )
+    };
+    assert_eq!(result, 0);
+    let passed = format!("/dev/fd/{}", device.as_raw_fd());
+    let mut session = Session::new(NoopFS, Path::new(&passed), &[]).unwrap();
+    if fuser::mounts::Connection::of_device(device.as_fd())
+        .unwrap()
+        .is_some()
+    {
+        assert_eq!(session.mountpoint(), tmpdir.path());
+    }
+    let handle = thread::spawn(move || session.run());
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    assert_eq!(
+        unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) },
+        0
+    );
+    drop(device);
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn hand_off_session() {
+    use fuser::MountOption;
+    use std::os::unix::net::UnixStream;
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    // Auto-unmount hands over the watchdog along with the mount
+    let options = [MountOption::AutoUnmount];
+    let mut session = Session::new(NoopFS, tmpdir.path(), &options).unwrap();
+    let stopper = session.stop_callable().unwrap();
+    let handle = thread::spawn(move || {
+        session.run().unwrap();
+        session
+    });
+    // INIT and this request are served by the original session
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    stopper.stop().unwrap();
+    let session = handle.join().unwrap();
+
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    session.hand_off(&sender).unwrap();
+    let mut resumed = Session::resume(NoopFS, &receiver).unwrap();
+    assert_eq!(resumed.mountpoint(), tmpdir.path());
+    assert!(resumed.connection_info().is_some());
+    let mut unmounter = resumed.unmount_callable();
+    let handle = thread::spawn(move || resumed.run());
+    // Without the INIT state the resumed session would reply EIO
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+
+    // The resumed session owns the mount, and unmounts it
+    unmounter.unmount().unwrap();
+    handle.join().unwrap().unwrap();
+    assert_eq!(fuser::mounts::find(tmpdir.path()).unwrap(), None);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn wait_ready() {
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let session = fuser::spawn_mount2(NoopFS, tmpdir.path(), &[]).unwrap();
+    // INIT is sent as part of mounting, but processed by the background thread
+    let connection = session.wait_ready(Duration::from_secs(10)).unwrap();
+    assert_eq!(connection.proto_major, 7);
+    assert!(connection.max_write > 0);
+    session.join();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn stop_on_signal() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    struct DestroyFS(Arc<AtomicBool>);
+
+    impl Filesystem for DestroyFS {
+        fn destroy(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let destroyed = Arc::new(AtomicBool::new(false));
+    let mut session = Session::new(DestroyFS(destroyed.clone()), tmpdir.path(), &[]).unwrap();
+    let _signals = SIGNALS.lock().unwrap();
+    let handle = thread::spawn(move || session.run_with_signal_handlers());
+    // Once a request was served, the signal handlers are installed
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+    assert_eq!(handle.join().unwrap().unwrap(), Some(libc::SIGTERM));
+    assert!(destroyed.load(Ordering::SeqCst));
+    // Unmounted, so the directory is accessible again
+    assert!(std::fs::metadata(tmpdir.path()).is_ok());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn stop_on_signal_when_unmount_fails() {
+    use fuser::{ReplyAttr, Request};
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    struct RootFS;
+
+    impl Filesystem for RootFS {
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &attr(ino, 0));
+        }
+    }
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let mut session = Session::new(RootFS, tmpdir.path(), &[]).unwrap();
+    let _signals = SIGNALS.lock().unwrap();
+    let handle = thread::spawn(move || session.run_with_signal_handlers());
+    // An open directory keeps the filesystem busy, so unmounting fails
+    let busy = std::fs::File::open(tmpdir.path()).unwrap();
+    unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+    assert_eq!(handle.join().unwrap().unwrap(), Some(libc::SIGTERM));
+    assert!(fuser::mounts::find(tmpdir.path()).unwrap().is_some());
+
+    drop(busy);
+    let path = CString::new(tmpdir.path().as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) }, 0);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn recover_stale_mount() {
+    use fuser::MountOption;
+
+    // Mount in a child process that exits without unmounting, like a crashed filesystem. The
+    // child runs this test again, rather than forking the multithreaded test harness.
+    if let Some(mountpoint) = std::env::var_os("FUSER_TEST_STALE_MOUNT") {
+        let session = Session::new(NoopFS, mountpoint.as_ref(), &[]).unwrap();
+        std::mem::forget(session);
+        std::process::exit(0);
+    }
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let status = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "recover_stale_mount", "--test-threads=1"])
+        .env("FUSER_TEST_STALE_MOUNT", tmpdir.path())
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTCONN));
+
+    assert!(Session::new(NoopFS, tmpdir.path(), &[]).is_err());
+    let session = Session::new(NoopFS, tmpdir.path(), &[MountOption::RecoverStale]).unwrap();
+    drop(session);
+    assert!(std::fs::metadata(tmpdir.path()).is_ok());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn list_mounts() {
+    use fuser::MountOption;
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let options = [MountOption::FSName("listed".to_owned())];
+    let session = Session::new(NoopFS, tmpdir.path(), &options).unwrap();
+    let connection = session.connection().unwrap();
+    let mount = fuser::mounts::find(tmpdir.path()).unwrap().unwrap();
+    assert_eq!(mount.source, "listed");
+    assert_eq!(mount.fstype, "fuse");
+    assert_eq!(mount.connection, connection);
+    assert!(fuser::mounts::list().unwrap().contains(&mount));
+    // Only if the fusectl filesystem is mounted
+    if connection.path().exists() {
+        // The session doesn't run, so INIT is still waiting
+        assert_eq!(connection.waiting().unwrap(), 1);
+    }
+    drop(session);
+    assert_eq!(fuser::mounts::find(tmpdir.path()).unwrap(), None);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn block_device_mount() {
+    use fuser::{MountOptions, ReplyAttr, ReplyBmap, ReplyEntry, ReplyOpen, Request};
+    use std::ffi::OsStr;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    struct BlockFS;
+
+    impl Filesystem for BlockFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+            reply.entry(&Duration::from_secs(1), &attr(2, 4096 * 16), 0);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &attr(ino, 4096 * 16));
+        }
+
+        fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+            reply.opened(0, 0);
+        }
+
+        fn bmap(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _blocksize: u32,
+            idx: u64,
+            reply: ReplyBmap,
+        ) {
+            reply.bmap(idx + 100);
+        }
+    }
+
+    // Needs root and a free loop device
+    let image = tempfile::NamedTempFile::new().unwrap();
+    image.as_file().set_len(1 << 20).unwrap();
+    let output = match Command::new("losetup")
+        .arg("--find")
+        .arg("--show")
+        .arg(image.path())
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return,
+    };
+    let device = PathBuf::from(String::from_utf8(output.stdout).unwrap().trim());
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let options = MountOptions::block_device(&device, 4096).unwrap();
+    let session = fuser::spawn_mount2(BlockFS, tmpdir.path(), &options).unwrap();
+    let mount = fuser::mounts::find(tmpdir.path()).unwrap().unwrap();
+    assert_eq!(mount.fstype, "fuseblk");
+    assert_eq!(mount.source, device.to_str().unwrap());
+
+    let file = std::fs::File::open(tmpdir.path().join("file")).unwrap();
+    let mut block: libc::c_int = 3;
+    // FIBMAP
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), 1, &mut block) };
+    drop(file);
+    drop(session);
+    Command::new("losetup")
+        .arg("--detach")
+        .arg(&device)
+        .status()
+        .unwrap();
+    assert_eq!(result, 0);
+    assert_eq!(block, 103);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn forward_over_socket() {
+    use fuser::{Disconnect, Forwarder, StreamTransport};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::sync::mpsc;
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let mut forwarder = Forwarder::new(tmpdir.path(), &[]).unwrap();
+    let mut unmounter = forwarder.unmount_callable();
+    let (connect, incoming) = mpsc::channel::<UnixStream>();
+    let (report, disconnects) = mpsc::channel();
+    let forwarder = thread::spawn(move || {
+        for stream in incoming {
+            let disconnect = forwarder.serve(stream).unwrap();
+            report.send(disconnect).unwrap();
+            if disconnect == Disconnect::Unmounted {
+                break;
+            }
+        }
+    });
+    let serve = || {
+        let (local, remote) = UnixStream::pair().unwrap();
+        connect.send(local).unwrap();
+        let control = remote.try_clone().unwrap();
+        let server = thread::spawn(move || {
+            let transport = StreamTransport::new(remote);
+            Session::with_transport(NoopFS, transport, Path::new("remote")).run()
+        });
+        (control, server)
+    };
+
+    // The default getattr() replies ENOSYS, which shows that the remote session served it
+    let (control, server) = serve();
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    control.shutdown(Shutdown::Both).unwrap();
+    server.join().unwrap().unwrap();
+    // Requests that reach the forwarder before it noticed would fail with EIO
+    assert_eq!(disconnects.recv().unwrap(), Disconnect::Peer);
+
+    // The next session is initialized without the kernel sending INIT again
+    let (_control, server) = serve();
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    unmounter.unmount().unwrap();
+    assert_eq!(disconnects.recv().unwrap(), Disconnect::Unmounted);
+    forwarder.join().unwrap();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn forward_large_messages() {
+    use fuser::consts::FOPEN_DIRECT_IO;
+    use fuser::{
+        Forwarder, ReplyAttr, ReplyData, ReplyEntry, ReplyOpen, ReplyWrite, Request,
+        StreamTransport,
+    };
+    use std::ffi::OsStr;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+
+    const SIZE: u64 = 4 << 20;
+
+    struct ZeroFS;
+
+    impl Filesystem for ZeroFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+            reply.entry(&Duration::from_secs(1), &attr(2, SIZE), 0);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &attr(ino, SIZE));
+        }
+
+        fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+            reply.opened(0, FOPEN_DIRECT_IO);
+        }
+
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            size: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            reply.data(&vec![0; size as usize]);
+        }
+
+        fn write(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+            reply.written(data.len() as u32);
+        }
+    }
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let mut forwarder = Forwarder::new(tmpdir.path(), &[]).unwrap();
+    let mut unmounter = forwarder.unmount_callable();
+    let (local, remote) = UnixStream::pair().unwrap();
+    // Small socket buffers, so that a single message fills them
+    for stream in [&local, &remote] {
+        let size: libc::c_int = 16 * 1024;
+        let result = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                (&size as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0);
+    }
+    let forwarder = thread::spawn(move || forwarder.serve(local).unwrap());
+    let server = thread::spawn(move || {
+        let transport = StreamTransport::new(remote);
+        Session::with_transport(ZeroFS, transport, Path::new("remote")).run()
+    });
+
+    // Large writes are forwarded while large read replies come back, which fills the socket
+    // buffers in both directions
+    let path = tmpdir.path().join("file");
+    let clients: Vec<_> = (0..8)
+        .map(|i| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .unwrap();
+                let mut buffer = vec![1; 1 << 20];
+                for _ in 0..16 {
+                    if i % 2 == 0 {
+                        assert_eq!(file.read_at(&mut buffer, 0).unwrap(), buffer.len());
+                    } else {
+                        file.write_all_at(&buffer, 0).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    unmounter.unmount().unwrap();
+    assert_eq!(forwarder.join().unwrap(), fuser::Disconnect::Unmounted);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn io_uring_session() {
+    use fuser::{ReplyAttr, ReplyData, ReplyEntry, Request};
+    use std::ffi::OsStr;
+
+    struct HelloFS;
+
+    impl Filesystem for HelloFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, name: &OsStr, reply: ReplyEntry) {
+            if name == "hello" {
+                reply.entry(&Duration::from_secs(1), &attr(2, 5), 0);
+            } else {
+                reply.error(libc::ENOENT);
+            }
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &attr(ino, 5));
+        }
+
+        fn read(
+            &mut self,
+            _req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            _size: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyData,
+        ) {
+            reply.data(b"hello");
+        }
+    }
+
+    // Requests are served over io_uring if the kernel supports it, and over /dev/fuse otherwise
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let mut session = Session::new_io_uring(HelloFS, tmpdir.path(), &[]).unwrap();
+    let mut unmounter = session.unmount_callable();
+    let handle = thread::spawn(move || session.run());
+    let path = tmpdir.path().join("hello");
+    assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+    let err = std::fs::metadata(tmpdir.path().join("missing")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    unmounter.unmount().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+#[ignore = "needs FUSE-over-io_uring, enabled with the enable_uring parameter of the fuse module"]
+fn io_uring_ring_session() {
+    use fuser::{IoUringTransport, Transport};
+
+    // io_uring_session falls back to the FUSE device on most kernels, so make sure the ring is
+    // used where it's available
+    let enabled = std::fs::read_to_string("/sys/module/fuse/parameters/enable_uring")
+        .is_ok_and(|x| x.trim() == "Y");
+    let disabled = std::fs::read_to_string("/proc/sys/kernel/io_uring_disabled")
+        .is_ok_and(|x| x.trim() != "0");
+    assert!(enabled && !disabled, "FUSE-over-io_uring is not available");
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let session = Session::new(NoopFS, tmpdir.path(), &[]).unwrap();
+    let fd = session.as_fd().try_clone_to_owned().unwrap();
+    let transport = IoUringTransport::from_fd(fd).unwrap();
+    let sender = transport.sender();
+    let mut worker = Session::with_transport(NoopFS, transport, tmpdir.path());
+    let worker = thread::spawn(move || worker.run());
+    // The default getattr() replies ENOSYS, which shows that the request was served
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    assert!(sender.is_io_uring());
+    drop(session);
+    worker.join().unwrap().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn write_on_worker_thread() {
+    use fuser::{ReplyAttr, ReplyEntry, ReplyOpen, ReplyWrite, Request, RequestData};
+    use std::ffi::OsStr;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    struct WriteFS(mpsc::Sender<RequestData>);
+
+    impl Filesystem for WriteFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+            reply.entry(&Duration::from_secs(1), &attr(2, 0), 0);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &attr(ino, 0));
+        }
+
+        fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+            reply.opened(0, 0);
+        }
+
+        fn write(
+            &mut self,
+            req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+            // The data is passed on without copying it, and the next requests are received into
+            // other buffers meanwhile
+            let data = req.share(data).unwrap();
+            let sender = self.0.clone();
+            thread::spawn(move || {
+                // Sent before replying, since the kernel only sends the next write after the
+                // reply, so the data arrives in order
+                let len = data.len() as u32;
+                sender.send(data).unwrap();
+                reply.written(len);
+            });
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let session = fuser::spawn_mount2(WriteFS(sender), tmpdir.path(), &[]).unwrap();
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(tmpdir.path().join("file"))
+        .unwrap();
+    file.write_all(b"first").unwrap();
+    file.write_all(b"second").unwrap();
+    drop(file);
+    let first = receiver.recv().unwrap();
+    let second = receiver.recv().unwrap();
+    assert_eq!(&*first, b"first");
+    assert_eq!(&*second, b"second");
+    drop(session);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn owned_request_on_worker_thread() {
+    use fuser::{Operation, OwnedRequest, ReplyAttr, ReplyEntry, ReplyOpen, ReplyWrite, Request};
+    use std::ffi::OsStr;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    struct WriteFS(mpsc::Sender<(OwnedRequest, ReplyWrite)>);
+
+    impl Filesystem for WriteFS {
+        fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+            reply.entry(&Duration::from_secs(1), &attr(2, 0), 0);
+        }
+
+        fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+            reply.attr(&Duration::from_secs(1), &attr(ino, 0));
+        }
+
+        fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
+            reply.opened(0, 0);
+        }
+
+        fn write(
+            &mut self,
+            req: &Request<'_>,
+            _ino: u64,
+            _fh: u64,
+            _offset: i64,
+            _data: &[u8],
+            _write_flags: u32,
+            _flags: i32,
+            _lock_owner: Option<u64>,
+            reply: ReplyWrite,
+        ) {
+            self.0.send((req.to_owned_request(), reply)).unwrap();
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let session = fuser::spawn_mount2(WriteFS(sender), tmpdir.path(), &[]).unwrap();
+    let path = tmpdir.path().join("file");
+    let writer = thread::spawn(move || {
+        let mut file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all(b"data").unwrap();
+    });
+    let (request, reply) = receiver.recv().unwrap();
+    // The session serves other requests while the write is held
+    assert!(std::fs::metadata(tmpdir.path().join("other"))
+        .unwrap()
+        .is_file());
+    let worker = thread::spawn(move || match request.operation().unwrap() {
+        Operation::Write(write) => {
+            assert_eq!(request.nodeid(), 2);
+            assert_eq!(write.data(), b"data");
+            reply.written(write.data().len() as u32);
+        }
+        operation => panic!("Unexpected operation {:?}", operation),
+    });
+    worker.join().unwrap();
+    writer.join().unwrap();
+    drop(session);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn buffers_sized_to_max_write() {
+    use fuser::{KernelConfig, Request};
+
+    struct SmallWritesFS;
+
+    impl Filesystem for SmallWritesFS {
+        fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), i32> {
+            // Accepted like before max_write depended on the kernel, but reduced to its limit
+            config.set_max_write(16 * 1024 * 1024).unwrap();
+            // Returns the previous value, as reduced
+            let previous = config.set_max_write(64 * 1024).unwrap();
+            assert!((128 * 1024..16 * 1024 * 1024).contains(&previous));
+            assert!(config.set_max_write(u32::MAX).is_err());
+            Ok(())
+        }
+    }
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let mut session = Session::new(SmallWritesFS, tmpdir.path(), &[]).unwrap();
+    let stopper = session.stop_callable().unwrap();
+    let handle = thread::spawn(move || {
+        session.run().unwrap();
+        session
+    });
+    let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    stopper.stop().unwrap();
+    let mut session = handle.join().unwrap();
+    assert_eq!(session.connection_info().unwrap().max_write, 64 * 1024);
+    assert_eq!(session.buffer_size(), 64 * 1024 + 4096);
+    session.set_buffer_size(1 << 20);
+    assert_eq!(session.buffer_size(), 1 << 20);
+    // The kernel wouldn't read requests into a smaller buffer
+    session.set_buffer_size(4096);
+    assert_eq!(session.buffer_size(), 64 * 1024 + 80);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn external_event_loop() {
+    use fuser::{Processed, SessionError};
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let mut session = Session::new(NoopFS, tmpdir.path(), &[]).unwrap();
+    session.set_nonblocking(true).unwrap();
+    let mut unmounter = session.unmount_callable();
+    let path = tmpdir.path().to_owned();
+    let client = thread::spawn(move || {
+        let err = std::fs::metadata(&path).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+        unmounter.unmount().unwrap();
+    });
+
+    let mut requests = 0;
+    'event_loop: loop {
+        let mut pollfd = libc::pollfd {
+            fd: session.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 10_000) }, 1);
+        loop {
+            match session.process_next() {
+                Ok(Processed::Request) => requests += 1,
+                Ok(Processed::Unmounted) => break 'event_loop,
+                Ok(_) => {}
+                Err(SessionError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("{}", err),
+            }
+        }
+    }
+    client.join().unwrap();
+    // At least INIT and GETATTR
+    assert!(requests >= 2);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn mount_set() {
+    use fuser::MountSet;
+    use std::time::Instant;
+
+    let set = MountSet::new(2).unwrap();
+    let first: TempDir = tempfile::tempdir().unwrap();
+    let second: TempDir = tempfile::tempdir().unwrap();
+    let session = Session::new(NoopFS, first.path(), &[]).unwrap();
+    let first_id = set.add(session).unwrap();
+    let mut session = Session::new(NoopFS, second.path(), &[]).unwrap();
+    let mut unmounter = session.unmount_callable();
+    let second_id = set.add(session).unwrap();
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.mountpoint(second_id).as_deref(), Some(second.path()));
+
+    for path in [first.path(), second.path()] {
+        let err = std::fs::metadata(path).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    }
+
+    assert!(set.remove(first_id));
+    assert!(!set.remove(first_id));
+    assert_eq!(fuser::mounts::find(first.path()).unwrap(), None);
+    assert_eq!(set.mounts(), vec![second_id]);
+
+    // Sessions that were unmounted externally are removed
+    unmounter.unmount().unwrap();
+    let start = Instant::now();
+    while !set.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn mount_set_panicking_filesystem() {
+    use fuser::{MountSet, ReplyAttr, Request};
+    use std::time::Instant;
+
+    struct PanicFS;
+
+    impl Filesystem for PanicFS {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, _reply: ReplyAttr) {
+            panic!("getattr failed");
+        }
+    }
+
+    let set = MountSet::new(1).unwrap();
+    let first: TempDir = tempfile::tempdir().unwrap();
+    let second: TempDir = tempfile::tempdir().unwrap();
+    let session = Session::new(PanicFS, first.path(), &[]).unwrap();
+    set.add(session).unwrap();
+    // The reply is dropped while unwinding, which answers the request
+    assert!(std::fs::metadata(first.path()).is_err());
+    let start = Instant::now();
+    while !set.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(fuser::mounts::find(first.path()).unwrap(), None);
+
+    // The only worker still serves other sessions
+    let session = Session::new(NoopFS, second.path(), &[]).unwrap();
+    let id = set.add(session).unwrap();
+    let err = std::fs::metadata(second.path()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+    assert!(set.remove(id));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn scoped_background_session_from_fd() {
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    let session = Session::new(NoopFS, tmpdir.path(), &[]).unwrap();
+    let fd = session.as_fd().try_clone_to_owned().unwrap();
+    thread::scope(|scope| {
+        let worker = Session::from_fd(NoopFS, fd, tmpdir.path())
+            .spawn_scoped(scope)
+            .unwrap();
+        let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+        // Doesn't own the mount, so dropping only stops the session loop
+        drop(worker);
+    });
+    assert!(fuser::mounts::find(tmpdir.path()).unwrap().is_some());
+    drop(session);
+    assert_eq!(fuser::mounts::find(tmpdir.path()).unwrap(), None);
+}
+
+#[test]
+fn scoped_background_session() {
+    use fuser::{ReplyAttr, Request};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingFS<'a> {
+        calls: &'a AtomicUsize,
+    }
+
+    impl Filesystem for CountingFS<'_> {
+        fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            reply.error(libc::EIO);
+        }
+    }
+
+    let calls = AtomicUsize::new(0);
+    let tmpdir: TempDir = tempfile::tempdir().unwrap();
+    thread::scope(|scope| {
+        let fs = CountingFS { calls: &calls };
+        let session = fuser::spawn_mount_scoped(scope, fs, tmpdir.path(), &[]).unwrap();
+        session.wait_ready(Duration::from_secs(10)).unwrap();
+        let err = std::fs::metadata(tmpdir.path()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        // Dropped here, which unmounts the filesystem
+        drop(session);
+    });
+    assert!(calls.load(Ordering::SeqCst) >= 1);
+    #[cfg(target_os = "linux")]
+    assert_eq!(fuser::mounts::find(tmpdir.path()).unwrap(), None);
+}
@@ -0,0 +1,54 @@
+//! Mounting inside a user namespace
+//!
+//! Namespaces can only be entered by single threaded processes, while the default test harness
+//! runs each test on its own thread. So this test has no harness: it runs itself again as a child
+//! process, which enters the namespace from its main thread.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use fuser::{Filesystem, Session, UserNamespace};
+    use std::process::Command;
+
+    struct NoopFS;
+
+    impl Filesystem for NoopFS {}
+
+    /// Exit status of the child if user namespaces are not available
+    const SKIP: i32 = 77;
+
+    if let Some(mountpoint) = std::env::var_os("FUSER_TEST_USER_NAMESPACE") {
+        match fuser::enter_user_namespace(UserNamespace::NewAsRoot) {
+            Ok(()) => {}
+            // Unprivileged user namespaces are disabled, or not supported
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EPERM | libc::EINVAL)) => {
+                eprintln!("Cannot enter a user namespace: {}", err);
+                std::process::exit(SKIP);
+            }
+            Err(err) => panic!("Failed to enter a user namespace: {}", err),
+        }
+        let session = Session::new(NoopFS, mountpoint.as_ref(), &[]).unwrap();
+        assert!(fuser::mounts::find(mountpoint.as_ref()).unwrap().is_some());
+        drop(session);
+        return;
+    }
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let status = Command::new(std::env::current_exe().unwrap())
+        .env("FUSER_TEST_USER_NAMESPACE", tmpdir.path())
+        .status()
+        .unwrap();
+    match status.code() {
+        Some(0) => {}
+        Some(SKIP) => {
+            eprintln!("SKIPPED mount_in_user_namespace: user namespaces are not available");
+            return;
+        }
+        _ => panic!("Mounting in a user namespace failed: {}", status),
+    }
+    // The mount was never visible outside of the namespace
+    assert_eq!(fuser::mounts::find(tmpdir.path()).unwrap(), None);
+    println!("test mount_in_user_namespace ... ok");
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}